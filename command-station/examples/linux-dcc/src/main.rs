@@ -1,16 +1,26 @@
 use drogue_embedded_timer::embedded_countdown;
 use embedded_hal::timer::nb::CountDown;
+use embedded_io::adapters::FromStd;
 use linux_embedded_hal::{
     gpio_cdev::{Chip, LineRequestFlags},
     CdevPin, SysTimer,
 };
+use loco_command_station::consist::{self, ConsistKind};
+use loco_command_station::power::PowerState;
 use loco_command_station::*;
 use loco_core::address::Address;
 use loco_core::drive::{Direction, Speed};
-use loco_dcc::writer::PinEncoder;
-use log::trace;
+use loco_core::functions::{Function, FunctionGroupNumber};
+use loco_dcc::function::DccFunctionGroup;
+use loco_dcc::writer::{Encoder, PinEncoder};
+use loco_xpressnet::interface::{Framing, Interface, InterfaceReply, InterfaceRequest, Request};
+use loco_xpressnet::{CentralError, CentralMessage, CentralState, DeviceMessage};
+use log::{debug, trace, warn};
 use nb::block;
+use num_traits::FromPrimitive;
+use std::fs::{File, OpenOptions};
 use std::io::{stdout, Read, Write};
+use std::sync::mpsc::{channel, Receiver};
 use termion::async_stdin;
 
 embedded_countdown!(
@@ -22,9 +32,227 @@ embedded_countdown!(
     }
 );
 
+/// Read requests of PC software (e.g. JMRI or Rocrail) from a serial port
+///
+/// The port has to be in raw mode, e.g. `stty -F /dev/ttyGS0 raw`.
+fn open_interface(
+    path: &str,
+    framing: Framing,
+) -> (
+    Interface<FromStd<File>>,
+    Receiver<Result<Request, loco_xpressnet::Error>>,
+) {
+    let port = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .unwrap();
+    let output = Interface::new(FromStd::new(port.try_clone().unwrap()), framing);
+    let mut input = Interface::new(FromStd::new(port), framing);
+    let (tx, rx) = channel();
+    std::thread::spawn(move || loop {
+        let req = input.read();
+        if let Err(loco_xpressnet::Error::IOError) = req {
+            warn!("interface closed");
+            break;
+        }
+        if tx.send(req).is_err() {
+            break;
+        }
+    });
+    (output, rx)
+}
+
+/// XpressNet central state from the track power state
+fn central_state(power: PowerState) -> CentralState {
+    match power {
+        PowerState::On => CentralState::empty(),
+        PowerState::Off | PowerState::EmergencyOff => CentralState::EMERGENCY_OFF,
+        PowerState::EmergencyStop => CentralState::EMERGENCY_STOP,
+    }
+}
+
+/// XpressNet error for a rejected consist change
+fn consist_error(error: consist::Error) -> CentralError {
+    use consist::Error::*;
+    match error {
+        Full => CentralError::StackOverflow,
+        Occupied => CentralError::ConsistOccupied,
+        AlreadyInConsist => CentralError::AlreadyInConsist,
+        SpeedNotZero => CentralError::ConsistSpeedNotZero,
+        NotInConsist => CentralError::LocoNotInConsist,
+        NoConsist => CentralError::NoConsistBase,
+        InvalidAddress | UnknownLoco => CentralError::ConsistError,
+    }
+}
+
+/// Answer a request of the PC and apply it to the station
+fn handle<E: Encoder, const N: usize>(
+    station: &mut Station<E, N>,
+    li: &mut Interface<FromStd<File>>,
+    req: Result<Request, loco_xpressnet::Error>,
+) -> Result<(), loco_xpressnet::Error> {
+    use DeviceMessage::*;
+    let msg = match req {
+        Ok(Request::XpressNet(msg)) => msg,
+        Ok(Request::Interface(InterfaceRequest::GetVersion)) => {
+            return li.reply(&InterfaceReply::Version(0x30, 0x01));
+        }
+        Ok(Request::Interface(InterfaceRequest::Address(_))) => {
+            return li.reply(&InterfaceReply::Address(1));
+        }
+        Ok(Request::Interface(InterfaceRequest::Baudrate(_))) => {
+            return li.reply(&InterfaceReply::Baudrate(1));
+        }
+        Err(_) => return li.reply(&InterfaceReply::PcError),
+    };
+    match msg {
+        GetVersion => li.write(&CentralMessage::<CentralState>::Version(0x30, 0x00)),
+        GetState => li.write(&CentralMessage::State(central_state(station.power()))),
+        TrackPowerOn => {
+            station.set_power(PowerState::On);
+            li.broadcast(&CentralMessage::<CentralState>::TrackPowerOn)
+        }
+        TrackPowerOff => {
+            station.set_power(PowerState::Off);
+            li.broadcast(&CentralMessage::<CentralState>::TrackPowerOff)
+        }
+        EmergencyStop => {
+            station.set_power(PowerState::EmergencyStop);
+            li.broadcast(&CentralMessage::<CentralState>::EmergencyStop)
+        }
+        LocoEmergencyStop(addr) => {
+            station.loco_emergency_stop(addr);
+            li.reply(&InterfaceReply::Ok)
+        }
+        LocoDrive(addr, direction, speed) => {
+            if station.loco(addr).is_none() && station.add_loco(addr).is_err() {
+                return li.reply(&InterfaceReply::BufferOverflow);
+            }
+            station.loco_set_drive(addr, speed, direction);
+            li.reply(&InterfaceReply::Ok)
+        }
+        GetLocoInformation(addr) => {
+            use FunctionGroupNumber::*;
+            let unknown = Loco::new(addr);
+            let loco = station.loco(addr).unwrap_or(&unknown);
+            let group = |g| u8::from(loco.function_group(g));
+            li.write(&CentralMessage::<CentralState>::LocoInformation {
+                is_free: true,
+                direction: loco.direction(),
+                speed: loco.speed(),
+                f0: group(G1).into(),
+                f1: (group(G2) | group(G3)).into(),
+            })
+        }
+        SetFunctionGroup(addr, group, data) => {
+            if station.loco(addr).is_none() && station.add_loco(addr).is_err() {
+                return li.reply(&InterfaceReply::BufferOverflow);
+            }
+            for f in group.functions().filter_map(Function::from_u8) {
+                station.loco_set_function(addr, f, data.get(f));
+            }
+            li.reply(&InterfaceReply::Ok)
+        }
+        // the station switches the output off again by itself
+        ControlAccessory(accessory) => {
+            if accessory.activate {
+                station.set_turnout(Address::new(accessory.address + 1), accessory.thrown);
+            }
+            li.reply(&InterfaceReply::Ok)
+        }
+        ProgrammingOnMainWrite {
+            loco_address,
+            cv_address,
+            value,
+        } => pom_reply(li, station.program_on_main(loco_address, cv_address, value)),
+        ProgrammingOnMainWriteBit {
+            loco_address,
+            cv_address,
+            position,
+            value,
+        } => pom_reply(
+            li,
+            station.program_on_main_bit(loco_address, cv_address, position, value),
+        ),
+        AddDoubleHeading(a, b) => {
+            let result = station.consist_add(ConsistKind::Universal, a, a, false);
+            let result = result.and_then(|_| {
+                let result = station.consist_add(ConsistKind::Universal, a, b, false);
+                if result.is_err() {
+                    let _ = station.consist_remove(a, a);
+                }
+                result
+            });
+            consist_reply(li, result)
+        }
+        RemoveDoubleHeading(addr) => {
+            let result = match station.consist_of(addr).map(|c| c.address()) {
+                Some(consist) => station.consist_remove(consist, addr),
+                None => Err(consist::Error::NotInConsist),
+            };
+            consist_reply(li, result)
+        }
+        AddConsist {
+            inverted,
+            loco_address,
+            base_address,
+        } => {
+            let base = Address::new(base_address as u16);
+            let result = station.consist_add(ConsistKind::Advanced, base, loco_address, inverted);
+            consist_reply(li, result)
+        }
+        RemoveConsist {
+            loco_address,
+            base_address,
+        } => {
+            let result = station.consist_remove(Address::new(base_address as u16), loco_address);
+            consist_reply(li, result)
+        }
+        // there is no programming track, so service mode requests end here
+        msg => {
+            debug!("unhandled message: {:?}", msg);
+            li.write(&CentralMessage::<CentralState>::UnknownCommand)
+        }
+    }
+}
+
+fn consist_reply(
+    li: &mut Interface<FromStd<File>>,
+    result: Result<(), consist::Error>,
+) -> Result<(), loco_xpressnet::Error> {
+    match result {
+        Ok(()) => li.reply(&InterfaceReply::Ok),
+        Err(e) => li.write(&CentralMessage::<CentralState>::Error(consist_error(e))),
+    }
+}
+
+fn pom_reply(
+    li: &mut Interface<FromStd<File>>,
+    result: Result<(), loco_dcc::Error>,
+) -> Result<(), loco_xpressnet::Error> {
+    match result {
+        Ok(()) => li.reply(&InterfaceReply::Ok),
+        Err(e) => {
+            debug!("invalid POM request: {:?}", e);
+            li.write(&CentralMessage::<CentralState>::UnknownCommand)
+        }
+    }
+}
+
 fn main() {
     env_logger::init();
 
+    // optional serial port of a PC interface, `li100` selects the plain framing
+    let mut args = std::env::args().skip(1);
+    let mut interface = args.next().map(|path| {
+        let framing = match args.next().as_deref() {
+            Some("li100") => Framing::Li100,
+            _ => Framing::LiUsb,
+        };
+        open_interface(&path, framing)
+    });
+
     let mut stdout = stdout();
     //let mut stdout = stdout.lock().into_raw_mode().unwrap();
     let mut stdin = async_stdin().bytes();
@@ -51,10 +279,17 @@ fn main() {
     let addr: Address = 3.into();
     let mut speed = 0_i8;
 
-    station.add_loco(3.into());
+    station.add_loco(3.into()).unwrap();
 
     loop {
         block!(station.run()).unwrap();
+        if let Some((li, requests)) = &mut interface {
+            while let Ok(req) = requests.try_recv() {
+                if let Err(e) = handle(&mut station, li, req) {
+                    warn!("interface error: {:?}", e);
+                }
+            }
+        }
         let b = stdin.next();
         trace!("{:?}", b);
         if let Some(b) = b {
@@ -0,0 +1,322 @@
+//! Locos running together as a consist
+//!
+//! The command station sends the speed of a universal consist to every
+//! member, each one in its own direction. The members of an advanced
+//! consist get the consist address written to CV19 and are driven with
+//! that address instead. Locos can only be added to a single consist and
+//! only while they are stopped.
+
+use heapless::Vec;
+use loco_core::address::Address;
+use loco_core::drive::{Direction, Speed};
+use loco_dcc::writer::Encoder;
+
+use crate::Station;
+
+/// Maximum number of consists
+pub const MAX_CONSISTS: usize = 8;
+/// Maximum number of locos in a consist
+pub const MAX_MEMBERS: usize = 8;
+/// CV holding the advanced consist address, bit 7 inverts the direction
+pub const CV_CONSIST_ADDRESS: u16 = 19;
+
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// No space left for another consist, member or loco
+    Full,
+    /// Consist address used by a consist of the other kind or, for an
+    /// advanced consist, by a loco
+    Occupied,
+    /// Advanced consist address is not a short address
+    InvalidAddress,
+    AlreadyInConsist,
+    SpeedNotZero,
+    NotInConsist,
+    NoConsist,
+    /// Loco is not known to the station
+    UnknownLoco,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsistKind {
+    /// Speed commands are sent to every member by the command station
+    Universal,
+    /// Decoders listen to the consist address set in CV19
+    Advanced,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Member {
+    pub address: Address,
+    /// Runs in the opposite direction of the consist
+    pub inverted: bool,
+}
+
+#[derive(Debug)]
+pub struct Consist {
+    address: Address,
+    kind: ConsistKind,
+    members: Vec<Member, MAX_MEMBERS>,
+}
+
+impl Consist {
+    /// Consist address, for a universal consist usually the first loco
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    pub fn kind(&self) -> ConsistKind {
+        self.kind
+    }
+
+    pub fn members(&self) -> &[Member] {
+        &self.members
+    }
+
+    pub fn member(&self, addr: Address) -> Option<&Member> {
+        self.members.iter().find(|m| m.address == addr)
+    }
+
+    /// Direction of a member when the consist is driven through another member
+    pub fn member_direction(
+        &self,
+        driven: Address,
+        loco: Address,
+        direction: Direction,
+    ) -> Option<Direction> {
+        let inverted = self.member(driven)?.inverted != self.member(loco)?.inverted;
+        Some(match (direction, inverted) {
+            (direction, false) => direction,
+            (Direction::Forward, true) => Direction::Backward,
+            (Direction::Backward, true) => Direction::Forward,
+        })
+    }
+}
+
+fn is_stopped(speed: Speed) -> bool {
+    matches!(
+        speed,
+        Speed::Stop
+            | Speed::EmergencyStop
+            | Speed::Steps14(0)
+            | Speed::Steps28(0)
+            | Speed::Steps128(0)
+    )
+}
+
+impl<E: Encoder, const N: usize> Station<E, N> {
+    pub fn consists(&self) -> &[Consist] {
+        &self.consists
+    }
+
+    pub fn consist(&self, addr: Address) -> Option<&Consist> {
+        self.consists.iter().find(|c| c.address == addr)
+    }
+
+    /// Find the consist a loco is a member of
+    pub fn consist_of(&self, loco: Address) -> Option<&Consist> {
+        self.consists.iter().find(|c| c.member(loco).is_some())
+    }
+
+    /// Add a stopped loco to a consist, creating the consist if needed
+    ///
+    /// The consist address of an advanced consist is added to the station
+    /// like a loco, so it can be driven and is refreshed. It is removed
+    /// again when the consist is dissolved.
+    pub fn consist_add(
+        &mut self,
+        kind: ConsistKind,
+        addr: Address,
+        loco: Address,
+        inverted: bool,
+    ) -> Result<(), Error> {
+        if self.consist_of(loco).is_some() {
+            return Err(Error::AlreadyInConsist);
+        }
+        let speed = self.loco(loco).ok_or(Error::UnknownLoco)?.speed();
+        if !is_stopped(speed) {
+            return Err(Error::SpeedNotZero);
+        }
+        let index = match self.consists.iter().position(|c| c.address == addr) {
+            Some(index) if self.consists[index].kind != kind => return Err(Error::Occupied),
+            Some(index) if self.consists[index].members.is_full() => return Err(Error::Full),
+            Some(index) => index,
+            None => self.consist_new(kind, addr)?,
+        };
+        let member = Member {
+            address: loco,
+            inverted,
+        };
+        // can't fail, checked above
+        let _ = self.consists[index].members.push(member);
+        if kind == ConsistKind::Advanced {
+            let value = addr.num as u8 | (inverted as u8) << 7;
+            // CV 19 is always valid
+            let _ = self.program_on_main(loco, CV_CONSIST_ADDRESS, value);
+        }
+        Ok(())
+    }
+
+    fn consist_new(&mut self, kind: ConsistKind, addr: Address) -> Result<usize, Error> {
+        if self.consists.is_full() {
+            return Err(Error::Full);
+        }
+        if kind == ConsistKind::Advanced {
+            if !(1..=127).contains(&addr.num) {
+                return Err(Error::InvalidAddress);
+            }
+            if self.loco(addr).is_some() {
+                return Err(Error::Occupied);
+            }
+            self.add_loco(addr).map_err(|_| Error::Full)?;
+        }
+        let consist = Consist {
+            address: addr,
+            kind,
+            members: Vec::new(),
+        };
+        let _ = self.consists.push(consist);
+        Ok(self.consists.len() - 1)
+    }
+
+    /// Remove a stopped loco from a consist
+    ///
+    /// A universal consist is dissolved when only one loco is left, an
+    /// advanced consist when it is empty. The consist address in CV19 of
+    /// a removed advanced consist member is cleared.
+    pub fn consist_remove(&mut self, addr: Address, loco: Address) -> Result<(), Error> {
+        let index = self
+            .consists
+            .iter()
+            .position(|c| c.address == addr)
+            .ok_or(Error::NoConsist)?;
+        let consist = &self.consists[index];
+        let position = consist
+            .members
+            .iter()
+            .position(|m| m.address == loco)
+            .ok_or(Error::NotInConsist)?;
+        let driven = match consist.kind {
+            ConsistKind::Universal => loco,
+            ConsistKind::Advanced => addr,
+        };
+        if matches!(self.loco(driven), Some(l) if !is_stopped(l.speed())) {
+            return Err(Error::SpeedNotZero);
+        }
+        let consist = &mut self.consists[index];
+        consist.members.remove(position);
+        let left = match consist.kind {
+            ConsistKind::Universal => 1,
+            ConsistKind::Advanced => {
+                let _ = self.program_on_main(loco, CV_CONSIST_ADDRESS, 0);
+                0
+            }
+        };
+        if self.consists[index].members.len() <= left {
+            let consist = self.consists.remove(index);
+            if consist.kind == ConsistKind::Advanced {
+                if let Some(i) = self.locos.iter().position(|l| l.addr == addr) {
+                    self.locos.remove(i);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+    use loco_dcc::{cv::CvAccess, message::Message};
+
+    fn station() -> Station<NullEncoder, 8> {
+        let mut station = Station::new(NullEncoder);
+        for addr in 3..=5 {
+            station.add_loco(Address::new(addr)).unwrap();
+        }
+        station
+    }
+
+    /// Drive messages and CV access packets queued for the next packets
+    fn sent(station: &mut Station<NullEncoder, 8>) -> std::vec::Vec<Message> {
+        (0..12)
+            .filter_map(|_| station.scheduler.next(&mut station.locos))
+            .filter(|msg| matches!(msg, Message::Drive(..) | Message::ProgramOnMain(..)))
+            .collect()
+    }
+
+    // test that a universal consist drives all members in their own direction
+    #[test]
+    fn universal_consist() {
+        use ConsistKind::Universal;
+        let mut station = station();
+        let (a, b, c) = (Address::new(3), Address::new(4), Address::new(5));
+        station.consist_add(Universal, a, a, false).unwrap();
+        station.consist_add(Universal, a, b, true).unwrap();
+        assert_eq!(station.consist_of(b).unwrap().address(), a);
+
+        station.loco_set_drive(b, Speed::Steps128(20), Direction::Forward);
+        let loco = station.loco(a).unwrap();
+        assert_eq!(loco.speed(), Speed::Steps128(20));
+        assert_eq!(loco.direction(), Direction::Backward);
+        assert_eq!(station.loco(b).unwrap().direction(), Direction::Forward);
+        assert_eq!(station.loco(c).unwrap().speed(), Speed::Stop);
+
+        assert_eq!(
+            station.consist_add(Universal, c, b, false),
+            Err(Error::AlreadyInConsist)
+        );
+        assert_eq!(station.consist_remove(a, b), Err(Error::SpeedNotZero));
+        station.loco_set_drive(a, Speed::Stop, Direction::Forward);
+        assert_eq!(station.consist_remove(a, c), Err(Error::NotInConsist));
+        station.consist_remove(a, b).unwrap();
+        assert!(station.consists().is_empty());
+    }
+
+    // test that an advanced consist programs CV19 and is driven with its address
+    #[test]
+    fn advanced_consist() {
+        use ConsistKind::Advanced;
+        let mut station = station();
+        let (a, b, consist) = (Address::new(3), Address::new(4), Address::new(10));
+        station.loco_set_drive(b, Speed::Steps128(20), Direction::Forward);
+        assert_eq!(
+            station.consist_add(Advanced, consist, b, false),
+            Err(Error::SpeedNotZero)
+        );
+        assert_eq!(
+            station.consist_add(Advanced, Address::new(200), a, false),
+            Err(Error::InvalidAddress)
+        );
+        assert_eq!(
+            station.consist_add(Advanced, Address::new(5), a, false),
+            Err(Error::Occupied)
+        );
+        station.loco_set_drive(b, Speed::Stop, Direction::Forward);
+        sent(&mut station);
+
+        station.consist_add(Advanced, consist, a, false).unwrap();
+        station.consist_add(Advanced, consist, b, true).unwrap();
+        let msgs = sent(&mut station);
+        let pom = |addr, value| Message::ProgramOnMain(addr, CvAccess::WriteByte(19, value));
+        assert!(msgs.contains(&pom(a, 10)));
+        assert!(msgs.contains(&pom(b, 0x8A)));
+
+        station.loco_set_drive(consist, Speed::Steps128(40), Direction::Backward);
+        let drive = Message::Drive(consist, Direction::Backward, Speed::Steps128(40));
+        assert_eq!(sent(&mut station)[0], drive);
+        assert_eq!(station.consist_remove(consist, a), Err(Error::SpeedNotZero));
+        assert_eq!(
+            station.consist_add(ConsistKind::Universal, consist, Address::new(5), false),
+            Err(Error::Occupied)
+        );
+
+        station.loco_set_drive(consist, Speed::Stop, Direction::Backward);
+        station.consist_remove(consist, a).unwrap();
+        assert!(sent(&mut station).contains(&pom(a, 0)));
+        station.consist_remove(consist, b).unwrap();
+        assert!(station.consist(consist).is_none());
+        assert!(station.loco(consist).is_none());
+    }
+}
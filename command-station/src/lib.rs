@@ -1,23 +1,42 @@
+#![cfg_attr(not(test), no_std)]
+
 use bitvec::prelude::*;
 use heapless::Vec;
 use loco_core::address::Address;
 use loco_core::drive::{Direction, Speed};
 use loco_core::functions::*;
 use loco_dcc::{
+    cv::CvAccess,
+    function::{DccFunctionGroup, FunctionGroupByte},
     message::Message,
     writer::{Encoder, Writer},
+    Error,
 };
 use log::trace;
-use num_traits::cast::ToPrimitive;
+use num_traits::cast::{FromPrimitive, ToPrimitive};
 
+pub mod consist;
+pub mod power;
+pub mod refresh;
 pub mod togglepins;
 
+#[cfg(test)]
+mod tests_mock;
+
+use consist::{Consist, ConsistKind, MAX_CONSISTS};
+use power::PowerState;
+use refresh::{Scheduler, CV_ACCESS_REPEATS};
+
+/// Size of the queue for changed commands
+const QUEUE_SIZE: usize = 16;
+
 #[derive(Debug)]
 pub struct Loco {
     addr: Address,
     direction: Direction,
     speed: Speed,
-    functions: BitArr!(for 68, in Msb0, u8),
+    functions: BitArr!(for 69, in Msb0, u8),
+    refresh_group: FunctionGroupNumber,
 }
 
 impl Loco {
@@ -26,10 +45,15 @@ impl Loco {
             addr: addr.into(),
             direction: Direction::Forward,
             speed: Speed::Stop,
-            functions: bitarr![Msb0, u8; 0; 68],
+            functions: bitarr![Msb0, u8; 0; 69],
+            refresh_group: FunctionGroupNumber::G10,
         }
     }
 
+    pub fn address(&self) -> Address {
+        self.addr
+    }
+
     pub fn is_function_set(&self, func: Function) -> bool {
         self.functions[func.to_usize().unwrap()]
     }
@@ -53,13 +77,31 @@ impl Loco {
     pub fn speed(&self) -> Speed {
         self.speed
     }
+
+    pub fn function_group(&self, group: FunctionGroupNumber) -> FunctionGroupByte {
+        let mut data = FunctionGroupByte::from(0x00);
+        for n in group.functions() {
+            data.set(Function::from_u8(n).unwrap(), self.functions[n as usize]);
+        }
+        data
+    }
+
+    pub fn drive_message(&self) -> Message {
+        Message::Drive(self.addr, self.direction, self.speed)
+    }
+
+    pub fn function_group_message(&self, group: FunctionGroupNumber) -> Message {
+        Message::FunctionGroup(self.addr, group, self.function_group(group))
+    }
 }
 
 pub struct Station<E: Encoder, const N: usize> {
     locos: Vec<Loco, N>,
     writer: Writer<E>,
     msg: Option<Message>,
-    index: usize,
+    scheduler: Scheduler<QUEUE_SIZE>,
+    power: PowerState,
+    consists: Vec<Consist, MAX_CONSISTS>,
 }
 
 impl<E: Encoder, const N: usize> Station<E, N> {
@@ -68,32 +110,132 @@ impl<E: Encoder, const N: usize> Station<E, N> {
             locos: Vec::new(),
             writer: Writer::new(encoder),
             msg: None,
-            index: 0,
+            scheduler: Scheduler::new(),
+            power: PowerState::On,
+            consists: Vec::new(),
         }
     }
 
-    pub fn add_loco(&mut self, addr: Address) {
-        let loco = Loco::new(addr);
-        self.locos.push(loco).unwrap(); // FIXME: unwrap
+    pub fn power(&self) -> PowerState {
+        self.power
+    }
+
+    /// Set the track power state
+    ///
+    /// An emergency stop sends broadcast stop packets right away and keeps
+    /// all locos stopped. Switching on afterwards resumes with all locos
+    /// at speed 0.
+    pub fn set_power(&mut self, state: PowerState) {
+        match (self.power, state) {
+            (_, PowerState::EmergencyStop) => {
+                self.scheduler.emergency_stop();
+                for loco in &mut self.locos {
+                    loco.set_speed(Speed::EmergencyStop);
+                }
+            }
+            (PowerState::EmergencyStop, PowerState::On) => {
+                for loco in &mut self.locos {
+                    loco.set_speed(Speed::Stop);
+                }
+            }
+            _ => {}
+        }
+        self.power = state;
+    }
+
+    /// Add a loco to the refresh cycle
+    ///
+    /// Returns the loco if the station is already full.
+    pub fn add_loco(&mut self, addr: Address) -> Result<(), Loco> {
+        self.locos.push(Loco::new(addr))
+    }
+
+    pub fn loco(&self, addr: Address) -> Option<&Loco> {
+        self.locos.iter().find(|loco| loco.addr == addr)
     }
 
     pub fn loco_set_function(&mut self, addr: Address, func: Function, val: bool) {
         for loco in &mut self.locos {
             if loco.addr == addr {
                 loco.set_function(func, val);
+                let group = FunctionGroupNumber::from_function(func);
+                self.scheduler.push(loco.function_group_message(group));
             }
         }
     }
 
+    /// Set speed and direction of a loco and the other locos of its
+    /// universal consist
     pub fn loco_set_drive(&mut self, addr: Address, speed: Speed, direction: Direction) {
+        let consist = universal_consist(&self.consists, addr);
         for loco in &mut self.locos {
-            if loco.addr == addr {
-                loco.set_speed(speed);
-                loco.set_direction(direction);
+            let direction = match consist {
+                Some(consist) => match consist.member_direction(addr, loco.addr, direction) {
+                    Some(direction) => direction,
+                    None => continue,
+                },
+                None if loco.addr == addr => direction,
+                None => continue,
+            };
+            loco.set_speed(speed);
+            loco.set_direction(direction);
+            self.scheduler.push(loco.drive_message());
+        }
+    }
+
+    /// Stop a loco and the other locos of its universal consist immediately
+    pub fn loco_emergency_stop(&mut self, addr: Address) {
+        let consist = universal_consist(&self.consists, addr);
+        for loco in &mut self.locos {
+            let stop = match consist {
+                Some(consist) => consist.member(loco.addr).is_some(),
+                None => loco.addr == addr,
+            };
+            if stop {
+                loco.set_speed(Speed::EmergencyStop);
+                self.scheduler.push(loco.drive_message());
             }
         }
     }
 
+    /// Switch a turnout (basic accessory) to thrown or closed
+    ///
+    /// The output is switched off again with a deactivating packet after
+    /// the activating packets are sent.
+    pub fn set_turnout(&mut self, addr: Address, thrown: bool) {
+        self.scheduler
+            .push(Message::BasicAccessory(addr, thrown, true));
+    }
+
+    /// Set the aspect of a signal (extended accessory)
+    pub fn set_signal_aspect(&mut self, addr: Address, aspect: u8) {
+        self.scheduler
+            .push(Message::ExtendedAccessory(addr, aspect));
+    }
+
+    /// Write a CV of a loco on the main track
+    pub fn program_on_main(&mut self, addr: Address, cv: u16, value: u8) -> Result<(), Error> {
+        self.program_on_main_access(addr, CvAccess::WriteByte(cv, value))
+    }
+
+    /// Write a single bit of a CV of a loco on the main track
+    pub fn program_on_main_bit(
+        &mut self,
+        addr: Address,
+        cv: u16,
+        position: u8,
+        value: bool,
+    ) -> Result<(), Error> {
+        self.program_on_main_access(addr, CvAccess::WriteBit(cv, position, value))
+    }
+
+    fn program_on_main_access(&mut self, addr: Address, access: CvAccess) -> Result<(), Error> {
+        access.check()?;
+        self.scheduler
+            .push_with_repeats(Message::ProgramOnMain(addr, access), CV_ACCESS_REPEATS);
+        Ok(())
+    }
+
     pub fn run(&mut self) -> nb::Result<(), core::convert::Infallible> {
         if let Some(msg) = &self.msg {
             let ret = self.writer.write(msg);
@@ -104,14 +246,48 @@ impl<E: Encoder, const N: usize> Station<E, N> {
                 Err(nb::Error::WouldBlock) // FIXME handle error from dcc
             }
         } else {
-            if self.index >= self.locos.len() {
-                self.index = 0;
-            }
-            let loco = self.locos.get(self.index).unwrap();
-            self.msg = Some(Message::Drive(loco.addr, loco.direction, loco.speed));
-            self.index += 1;
+            self.msg = Some(
+                self.scheduler
+                    .next(&mut self.locos)
+                    .unwrap_or(Message::Idle),
+            );
             trace!("{:?}", self.msg);
             Err(nb::Error::WouldBlock)
         }
     }
 }
+
+/// Find the universal consist a loco is a member of
+fn universal_consist(consists: &[Consist], addr: Address) -> Option<&Consist> {
+    consists
+        .iter()
+        .find(|c| c.kind() == ConsistKind::Universal && c.member(addr).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::NullEncoder;
+
+    // test that CV accesses on the main are only queued for valid CVs
+    #[test]
+    fn program_on_main_range() {
+        let mut station: Station<NullEncoder, 4> = Station::new(NullEncoder);
+        let addr = Address::new(3);
+        assert_eq!(station.program_on_main(addr, 0, 1), Err(Error::InvalidCv));
+        assert_eq!(
+            station.program_on_main(addr, 1025, 1),
+            Err(Error::InvalidCv)
+        );
+        assert_eq!(
+            station.program_on_main_bit(addr, 29, 8, true),
+            Err(Error::InvalidCv)
+        );
+        assert_eq!(station.scheduler.next(&mut station.locos), None);
+        station.program_on_main(addr, 1024, 1).unwrap();
+        assert_eq!(
+            station.scheduler.next(&mut station.locos),
+            Some(Message::ProgramOnMain(addr, CvAccess::WriteByte(1024, 1)))
+        );
+    }
+}
@@ -0,0 +1,348 @@
+//! Track power with an enable pin and short circuit detection
+//!
+//! The power state of the station switches the enable pin of the track
+//! output. While the track is powered, the current is checked and the
+//! track is switched off if it stays above the limit for the short
+//! duration. It can only be switched on again after the cooldown.
+
+use core::marker::PhantomData;
+use embedded_hal::adc::nb::{Channel, OneShot};
+use embedded_hal::digital::blocking::{InputPin, OutputPin};
+use embedded_hal::timer::nb::CountDown;
+use embedded_time::duration::Microseconds;
+use loco_dcc::writer::Encoder;
+use log::warn;
+
+use crate::Station;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerState {
+    Off,
+    On,
+    /// Switched off because of a short circuit
+    EmergencyOff,
+    /// Track powered, but all locos stopped
+    EmergencyStop,
+}
+
+impl PowerState {
+    /// Check if the track is powered
+    pub fn is_on(&self) -> bool {
+        matches!(self, PowerState::On | PowerState::EmergencyStop)
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    PinError,
+    SenseError,
+    TimerError,
+}
+
+/// Overcurrent detection of the track output
+pub trait CurrentSense {
+    type Error: core::fmt::Debug;
+
+    fn is_overcurrent(&mut self) -> nb::Result<bool, Self::Error>;
+}
+
+/// Comparator with an output that is high on overcurrent
+pub struct Comparator<P> {
+    pin: P,
+}
+
+impl<P: InputPin> Comparator<P> {
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+}
+
+impl<P: InputPin> CurrentSense for Comparator<P> {
+    type Error = P::Error;
+
+    fn is_overcurrent(&mut self) -> nb::Result<bool, Self::Error> {
+        Ok(self.pin.is_high()?)
+    }
+}
+
+/// ADC channel measuring the track current against a limit
+pub struct AdcSense<ADC, A, PIN> {
+    adc: A,
+    pin: PIN,
+    limit: u16,
+    _adc: PhantomData<ADC>,
+}
+
+impl<ADC, A, PIN> AdcSense<ADC, A, PIN>
+where
+    A: OneShot<ADC, u16, PIN>,
+    PIN: Channel<ADC>,
+{
+    pub fn new(adc: A, pin: PIN, limit: u16) -> Self {
+        Self {
+            adc,
+            pin,
+            limit,
+            _adc: PhantomData,
+        }
+    }
+}
+
+impl<ADC, A, PIN> CurrentSense for AdcSense<ADC, A, PIN>
+where
+    A: OneShot<ADC, u16, PIN>,
+    PIN: Channel<ADC>,
+{
+    type Error = A::Error;
+
+    fn is_overcurrent(&mut self) -> nb::Result<bool, Self::Error> {
+        Ok(self.adc.read(&mut self.pin)? > self.limit)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Phase {
+    Normal,
+    /// Current above the limit, waiting for the short duration
+    Overcurrent,
+    /// Switched off after a short circuit, waiting for the cooldown
+    Cooldown,
+}
+
+/// Track output following the power state of a station
+pub struct Power<P, C, TIM> {
+    enable: P,
+    sense: C,
+    timer: TIM,
+    enabled: bool,
+    phase: Phase,
+    short_duration: Microseconds<u32>,
+    cooldown: Microseconds<u32>,
+}
+
+impl<P, C, TIM> Power<P, C, TIM>
+where
+    P: OutputPin,
+    C: CurrentSense,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+{
+    pub fn new(
+        enable: P,
+        sense: C,
+        timer: TIM,
+        short_duration: Microseconds<u32>,
+        cooldown: Microseconds<u32>,
+    ) -> Self {
+        Self {
+            enable,
+            sense,
+            timer,
+            enabled: false,
+            phase: Phase::Normal,
+            short_duration,
+            cooldown,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Check if switching on is blocked after a short circuit
+    pub fn is_cooling_down(&self) -> bool {
+        self.phase == Phase::Cooldown
+    }
+
+    /// Switch the track output and check for short circuits
+    ///
+    /// Returns the new power state if the station was switched off,
+    /// either by a short circuit or by switching on during the cooldown.
+    pub fn run<E: Encoder, const N: usize>(
+        &mut self,
+        station: &mut Station<E, N>,
+    ) -> nb::Result<PowerState, Error> {
+        if self.phase == Phase::Cooldown {
+            match self.timer.wait() {
+                Ok(()) => self.phase = Phase::Normal,
+                Err(nb::Error::WouldBlock) if station.power().is_on() => {
+                    station.set_power(PowerState::EmergencyOff);
+                    return Ok(PowerState::EmergencyOff);
+                }
+                Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+                Err(nb::Error::Other(_)) => return Err(nb::Error::Other(Error::TimerError)),
+            }
+        }
+        let on = station.power().is_on();
+        if on != self.enabled {
+            self.set_enabled(on)?;
+            self.phase = Phase::Normal;
+        }
+        if !self.enabled {
+            return Err(nb::Error::WouldBlock);
+        }
+        let overcurrent = self
+            .sense
+            .is_overcurrent()
+            .map_err(|e| e.map(|_| Error::SenseError))?;
+        match (overcurrent, &self.phase) {
+            (false, _) => self.phase = Phase::Normal,
+            (true, Phase::Normal) => {
+                self.start_timer(self.short_duration)?;
+                self.phase = Phase::Overcurrent;
+            }
+            (true, _) => {
+                self.timer
+                    .wait()
+                    .map_err(|e| e.map(|_| Error::TimerError))?;
+                warn!("short circuit, switching off track power");
+                self.set_enabled(false)?;
+                self.start_timer(self.cooldown)?;
+                self.phase = Phase::Cooldown;
+                station.set_power(PowerState::EmergencyOff);
+                return Ok(PowerState::EmergencyOff);
+            }
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn set_enabled(&mut self, on: bool) -> Result<(), Error> {
+        if on {
+            self.enable.set_high()
+        } else {
+            self.enable.set_low()
+        }
+        .map_err(|_| Error::PinError)?;
+        self.enabled = on;
+        Ok(())
+    }
+
+    fn start_timer(&mut self, time: Microseconds<u32>) -> Result<(), Error> {
+        self.timer.start(time).map_err(|_| Error::TimerError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+    use loco_core::{
+        address::Address,
+        drive::{Direction, Speed},
+    };
+
+    type TestPower = Power<MockPin, Comparator<MockPin>, MockTimer>;
+
+    fn setup() -> (Station<NullEncoder, 4>, TestPower, MockPin, MockPin) {
+        let enable = MockPin::default();
+        let sense = MockPin::default();
+        let power = Power::new(
+            enable.clone(),
+            Comparator::new(sense.clone()),
+            MockTimer::new(),
+            1000.microseconds(),
+            10_000.microseconds(),
+        );
+        (Station::new(NullEncoder), power, enable, sense)
+    }
+
+    fn blocks(power: &mut TestPower, station: &mut Station<NullEncoder, 4>, n: usize) {
+        for _ in 0..n {
+            assert!(matches!(power.run(station), Err(nb::Error::WouldBlock)));
+        }
+    }
+
+    // test that the enable pin follows the power state of the station
+    #[test]
+    fn switch_power() {
+        let (mut station, mut power, enable, _) = setup();
+        blocks(&mut power, &mut station, 1);
+        assert!(enable.level.get());
+        station.set_power(PowerState::Off);
+        blocks(&mut power, &mut station, 1);
+        assert!(!enable.level.get());
+        station.set_power(PowerState::EmergencyStop);
+        blocks(&mut power, &mut station, 1);
+        assert!(enable.level.get());
+    }
+
+    // test that the track is switched off after the short duration
+    #[test]
+    fn short_circuit() {
+        let (mut station, mut power, enable, sense) = setup();
+        blocks(&mut power, &mut station, 1);
+        sense.level.set(true);
+        blocks(&mut power, &mut station, 10);
+        assert!(enable.level.get());
+        assert!(matches!(
+            power.run(&mut station),
+            Ok(PowerState::EmergencyOff)
+        ));
+        assert!(!enable.level.get());
+        assert!(power.is_cooling_down());
+        assert_eq!(station.power(), PowerState::EmergencyOff);
+    }
+
+    // test that a short current spike does not switch off the track
+    #[test]
+    fn current_spike() {
+        let (mut station, mut power, enable, sense) = setup();
+        blocks(&mut power, &mut station, 1);
+        sense.level.set(true);
+        blocks(&mut power, &mut station, 6);
+        sense.level.set(false);
+        blocks(&mut power, &mut station, 1);
+        sense.level.set(true);
+        blocks(&mut power, &mut station, 10);
+        assert!(enable.level.get());
+        assert_eq!(station.power(), PowerState::On);
+    }
+
+    // test that switching on is only possible after the cooldown
+    #[test]
+    fn cooldown() {
+        let (mut station, mut power, enable, sense) = setup();
+        blocks(&mut power, &mut station, 1);
+        sense.level.set(true);
+        blocks(&mut power, &mut station, 10);
+        assert!(power.run(&mut station).is_ok());
+        sense.level.set(false);
+
+        station.set_power(PowerState::On);
+        assert!(matches!(
+            power.run(&mut station),
+            Ok(PowerState::EmergencyOff)
+        ));
+        assert!(!enable.level.get());
+        assert_eq!(station.power(), PowerState::EmergencyOff);
+
+        blocks(&mut power, &mut station, 99);
+        assert!(!power.is_cooling_down());
+        station.set_power(PowerState::On);
+        blocks(&mut power, &mut station, 1);
+        assert!(enable.level.get());
+        assert_eq!(station.power(), PowerState::On);
+    }
+
+    // test that an emergency stop stops all locos but keeps the track powered
+    #[test]
+    fn emergency_stop() {
+        let (mut station, _, _, _) = setup();
+        station.add_loco(Address::new(3)).unwrap();
+        station.add_loco(Address::new(4)).unwrap();
+        station.loco_set_drive(Address::new(3), Speed::Steps128(20), Direction::Forward);
+        station.set_power(PowerState::EmergencyStop);
+        assert!(station.power().is_on());
+        let loco = station.loco(Address::new(3)).unwrap();
+        assert_eq!(loco.speed(), Speed::EmergencyStop);
+
+        // resume with all locos stopped
+        station.set_power(PowerState::On);
+        let loco = station.loco(Address::new(3)).unwrap();
+        assert_eq!(loco.speed(), Speed::Stop);
+        station.loco_set_drive(Address::new(4), Speed::Steps128(20), Direction::Forward);
+        station.loco_emergency_stop(Address::new(4));
+        let loco = station.loco(Address::new(4)).unwrap();
+        assert_eq!(loco.speed(), Speed::EmergencyStop);
+    }
+}
@@ -0,0 +1,293 @@
+//! Packet scheduling for the command station
+//!
+//! Changed state is queued and repeated a few times right away, all
+//! other packets are sent as round-robin background refresh.
+
+use crate::Loco;
+use heapless::Deque;
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+    functions::FunctionGroupNumber,
+};
+use loco_dcc::message::Message;
+use log::debug;
+
+/// Number of times a changed command is sent before it is only refreshed
+pub const REPEATS: u8 = 3;
+/// Number of times a CV access on the main is sent
+pub const CV_ACCESS_REPEATS: u8 = 4;
+/// Number of broadcast stop packets sent on an emergency stop
+pub const EMERGENCY_STOP_REPEATS: u8 = 5;
+
+pub struct Scheduler<const Q: usize> {
+    queue: Deque<(Message, u8), Q>,
+    index: usize,
+    drive_sent: bool,
+    /// Broadcast stop packets left to send
+    stops: u8,
+}
+
+impl<const Q: usize> Default for Scheduler<Q> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const Q: usize> Scheduler<Q> {
+    pub fn new() -> Self {
+        Self {
+            queue: Deque::new(),
+            index: 0,
+            drive_sent: false,
+            stops: 0,
+        }
+    }
+
+    /// Queue a changed command with priority over the background refresh
+    ///
+    /// A queued command that is not sent completely yet will be
+    /// replaced if it addresses the same loco and state or the
+    /// same accessory.
+    pub fn push(&mut self, msg: Message) {
+        self.push_with_repeats(msg, REPEATS);
+    }
+
+    pub fn push_with_repeats(&mut self, msg: Message, repeats: u8) {
+        for entry in self.queue.iter_mut() {
+            if Self::replaces(&entry.0, &msg) {
+                *entry = (msg, repeats);
+                return;
+            }
+        }
+        if let Err((msg, _)) = self.queue.push_back((msg, repeats)) {
+            debug!("queue full, dropping {:?}", msg);
+        }
+    }
+
+    /// Send broadcast stop packets before any other packet
+    ///
+    /// Queued speed commands are dropped, so they can't restart a loco
+    /// after the emergency stop.
+    pub fn emergency_stop(&mut self) {
+        let queue = core::mem::replace(&mut self.queue, Deque::new());
+        for entry in queue {
+            if !matches!(entry.0, Message::Drive(..)) {
+                let _ = self.queue.push_back(entry);
+            }
+        }
+        self.stops = EMERGENCY_STOP_REPEATS;
+    }
+
+    fn replaces(queued: &Message, msg: &Message) -> bool {
+        use Message::*;
+        match (queued, msg) {
+            (Drive(a, ..), Drive(b, ..)) => a == b,
+            (FunctionGroup(a, ga, _), FunctionGroup(b, gb, _)) => a == b && ga == gb,
+            (BasicAccessory(a, ..), BasicAccessory(b, ..)) => a == b,
+            (ExtendedAccessory(a, _), ExtendedAccessory(b, _)) => a == b,
+            _ => false,
+        }
+    }
+
+    /// Get the next message that should be sent
+    ///
+    /// Queued messages are interleaved with each other until all
+    /// repeats are sent, except for CV access packets which are
+    /// repeated right away. Activating accessory packets are followed by
+    /// deactivating ones. Afterwards all locos are refreshed, sending a
+    /// speed packet followed by one function group for each loco.
+    /// Broadcast stop packets of an emergency stop preempt all of them.
+    pub fn next(&mut self, locos: &mut [Loco]) -> Option<Message> {
+        if self.stops > 0 {
+            self.stops -= 1;
+            return Some(Message::Drive(
+                Address::new(0),
+                Direction::Forward,
+                Speed::EmergencyStop,
+            ));
+        }
+        if let Some((msg, left)) = self.queue.pop_front() {
+            if left > 1 {
+                // decoders only act on CV access packets if they are
+                // received twice without other packets in between
+                let _ = if let Message::ProgramOnMain(..) = msg {
+                    self.queue.push_front((msg.clone(), left - 1))
+                } else {
+                    self.queue.push_back((msg.clone(), left - 1))
+                };
+            } else if let Message::BasicAccessory(addr, thrown, true) = msg {
+                // switch off the output once it was activated
+                self.push(Message::BasicAccessory(addr, thrown, false));
+            }
+            return Some(msg);
+        }
+        if locos.is_empty() {
+            return None;
+        }
+        if self.index >= locos.len() {
+            self.index = 0;
+            self.drive_sent = false;
+        }
+        let loco = &mut locos[self.index];
+        if !self.drive_sent {
+            self.drive_sent = true;
+            Some(loco.drive_message())
+        } else {
+            self.drive_sent = false;
+            self.index += 1;
+            let group = loco.next_refresh_group();
+            Some(loco.function_group_message(group))
+        }
+    }
+}
+
+impl Loco {
+    /// Get the next function group for the background refresh
+    ///
+    /// F0 to F28 are always refreshed, higher groups only if
+    /// at least one of their functions is set.
+    fn next_refresh_group(&mut self) -> FunctionGroupNumber {
+        use num_traits::{FromPrimitive, ToPrimitive};
+        let mut group = self.refresh_group;
+        loop {
+            let next = group.to_u8().unwrap() % 10 + 1;
+            group = FunctionGroupNumber::from_u8(next).unwrap();
+            if next <= 5 || u8::from(self.function_group(group)) != 0 {
+                break;
+            }
+        }
+        self.refresh_group = group;
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loco_core::{
+        address::Address,
+        drive::{Direction, Speed},
+        functions::Function,
+    };
+    use FunctionGroupNumber::*;
+
+    fn groups(msgs: &[Message]) -> Vec<FunctionGroupNumber> {
+        msgs.iter()
+            .filter_map(|m| match m {
+                Message::FunctionGroup(_, g, _) => Some(*g),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // test that background refresh sends speed and function groups in turn
+    #[test]
+    fn background_refresh() {
+        let mut scheduler: Scheduler<4> = Scheduler::new();
+        let mut locos = [Loco::new(3), Loco::new(4)];
+        locos[1].set_function(Function::F30, true);
+        let msgs: Vec<Message> = (0..24)
+            .map(|_| scheduler.next(&mut locos).unwrap())
+            .collect();
+        for (i, msg) in msgs.iter().enumerate() {
+            let addr = Address::new(3 + (i as u16 / 2) % 2);
+            match msg {
+                Message::Drive(a, ..) if i % 2 == 0 => assert_eq!(*a, addr),
+                Message::FunctionGroup(a, ..) if i % 2 == 1 => assert_eq!(*a, addr),
+                _ => panic!("unexpected message {:?}", msg),
+            }
+        }
+        let msgs: Vec<Message> = msgs.chunks(4).map(|c| c[1].clone()).collect();
+        assert_eq!(groups(&msgs), vec![G1, G2, G3, G4, G5, G1]);
+        let msgs: Vec<Message> = (0..24)
+            .map(|_| scheduler.next(&mut locos).unwrap())
+            .collect::<Vec<Message>>()
+            .chunks(4)
+            .map(|c| c[3].clone())
+            .collect();
+        assert_eq!(groups(&msgs), vec![G1, G2, G3, G4, G5, G6]);
+    }
+
+    // test that queued messages are repeated before the background refresh
+    #[test]
+    fn queued_messages() {
+        let mut scheduler: Scheduler<4> = Scheduler::new();
+        let mut locos = [Loco::new(3)];
+        let drive = |speed| Message::Drive(Address::new(3), Direction::Forward, speed);
+        let light = Message::FunctionGroup(Address::new(3), G1, 0x10.into());
+        scheduler.push(drive(Speed::Steps128(10)));
+        scheduler.push(light.clone());
+        // replaces the first drive message, but keeps its position
+        scheduler.push(drive(Speed::Steps128(20)));
+        for _ in 0..REPEATS {
+            assert_eq!(scheduler.next(&mut locos), Some(drive(Speed::Steps128(20))));
+            assert_eq!(scheduler.next(&mut locos), Some(light.clone()));
+        }
+        assert_eq!(scheduler.next(&mut locos), Some(drive(Speed::Stop)));
+    }
+
+    // test that a turnout output is deactivated after it was activated
+    #[test]
+    fn accessory_deactivation() {
+        let mut scheduler: Scheduler<4> = Scheduler::new();
+        let addr = Address::new(10);
+        scheduler.push(Message::BasicAccessory(addr, true, true));
+        let msgs: Vec<Message> = (0..2 * REPEATS)
+            .map(|_| scheduler.next(&mut []).unwrap())
+            .collect();
+        let (on, off) = msgs.split_at(REPEATS as usize);
+        assert!(on
+            .iter()
+            .all(|m| *m == Message::BasicAccessory(addr, true, true)));
+        assert!(off
+            .iter()
+            .all(|m| *m == Message::BasicAccessory(addr, true, false)));
+        assert_eq!(scheduler.next(&mut []), None);
+    }
+
+    // test that an emergency stop preempts queued packets and drops speed commands
+    #[test]
+    fn emergency_stop() {
+        let mut scheduler: Scheduler<4> = Scheduler::new();
+        let mut locos = [Loco::new(3)];
+        let drive = Message::Drive(Address::new(3), Direction::Forward, Speed::Steps128(10));
+        let light = Message::FunctionGroup(Address::new(3), G1, 0x10.into());
+        let stop = Message::Drive(Address::new(0), Direction::Forward, Speed::EmergencyStop);
+        scheduler.push(drive);
+        scheduler.push(light.clone());
+        assert!(scheduler.next(&mut locos).is_some());
+        scheduler.emergency_stop();
+        for _ in 0..EMERGENCY_STOP_REPEATS {
+            assert_eq!(scheduler.next(&mut locos), Some(stop.clone()));
+        }
+        for _ in 0..REPEATS {
+            assert_eq!(scheduler.next(&mut locos), Some(light.clone()));
+        }
+        assert_eq!(
+            scheduler.next(&mut locos),
+            Some(Message::Drive(
+                Address::new(3),
+                Direction::Forward,
+                Speed::Stop
+            ))
+        );
+    }
+
+    // test that CV access packets are sent without other packets in between
+    #[test]
+    fn program_on_main() {
+        use loco_dcc::cv::CvAccess;
+        let mut scheduler: Scheduler<4> = Scheduler::new();
+        let mut locos = [Loco::new(3)];
+        let drive = Message::Drive(Address::new(3), Direction::Forward, Speed::Steps128(10));
+        let pom = Message::ProgramOnMain(Address::new(3), CvAccess::WriteByte(3, 20));
+        scheduler.push(drive.clone());
+        scheduler.push_with_repeats(pom.clone(), CV_ACCESS_REPEATS);
+        assert_eq!(scheduler.next(&mut locos), Some(drive.clone()));
+        for _ in 0..CV_ACCESS_REPEATS {
+            assert_eq!(scheduler.next(&mut locos), Some(pom.clone()));
+        }
+        assert_eq!(scheduler.next(&mut locos), Some(drive));
+    }
+}
@@ -0,0 +1,48 @@
+use core::convert::Infallible;
+pub use embedded_hal::digital::blocking::{InputPin, OutputPin};
+pub use embedded_time::duration::*;
+pub use loco_dcc::writer::{Bit, Encoder};
+pub use loco_mock::MockTimer;
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Encoder that drops all bits
+pub struct NullEncoder;
+
+impl Encoder for NullEncoder {
+    fn write(&mut self, _bit: &Bit) -> nb::Result<(), loco_dcc::Error> {
+        Ok(())
+    }
+}
+
+/// Pin with a level shared with the test
+#[derive(Clone, Default)]
+pub struct MockPin {
+    pub level: Rc<Cell<bool>>,
+}
+
+impl OutputPin for MockPin {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        self.level.set(false);
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        self.level.set(true);
+        Ok(())
+    }
+}
+
+impl InputPin for MockPin {
+    type Error = Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(self.level.get())
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(!self.level.get())
+    }
+}
@@ -84,4 +84,5 @@ pub enum FunctionGroupNumber {
     G7,
     G8,
     G9,
+    G10,
 }
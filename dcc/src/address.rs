@@ -10,7 +10,7 @@ pub trait DccAddress {
 impl DccAddress for Address {
     fn from_bytes(bytes: &[u8]) -> Address {
         let num = if bytes[0] & 0xC0 == 0xC0 && bytes[0] & 0x3F != 0x3F {
-            u16::from_le_bytes([bytes[0] & 0x3F, bytes[1]])
+            u16::from_be_bytes([bytes[0] & 0x3F, bytes[1]])
         } else {
             bytes[0] as u16
         };
@@ -19,7 +19,7 @@ impl DccAddress for Address {
 
     fn to_buf(&self, buf: &mut [u8]) -> usize {
         if self.num > 127 {
-            mov!(buf[0..=1] <- &self.num.to_le_bytes());
+            mov!(buf[0..=1] <- &self.num.to_be_bytes());
             buf[0] |= 0xC0;
             2
         } else {
@@ -36,3 +36,30 @@ impl DccAddress for Address {
         }
     }
 }
+
+/// Accessory decoder addressing
+///
+/// The address number is the output address as used by the user
+/// (starting at 1). It is sent as an 11 bit address consisting of the
+/// 9 bit decoder address and the 2 bit output pair.
+pub trait DccAccessoryAddress {
+    fn from_accessory_bytes(bytes: &[u8]) -> Option<Address>;
+    fn to_accessory_buf(&self, buf: &mut [u8]) -> usize;
+}
+
+impl DccAccessoryAddress for Address {
+    fn from_accessory_bytes(bytes: &[u8]) -> Option<Address> {
+        let raw = (((!bytes[1] >> 4) & 0x07) as u16) << 8
+            | ((bytes[0] & 0x3F) as u16) << 2
+            | ((bytes[1] >> 1) & 0x03) as u16;
+        // decoder address 0 has no output addresses
+        raw.checked_sub(4).map(|num| Address { num: num + 1 })
+    }
+
+    fn to_accessory_buf(&self, buf: &mut [u8]) -> usize {
+        let raw = self.num + 3;
+        buf[0] = 0x80 | ((raw >> 2) & 0x3F) as u8;
+        buf[1] = ((!(raw >> 8) & 0x07) << 4) as u8 | ((raw & 0x03) << 1) as u8;
+        2
+    }
+}
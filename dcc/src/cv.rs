@@ -0,0 +1,149 @@
+//! Configuration variable (CV) access instructions
+
+use crate::Error;
+
+/// Highest CV number that can be accessed
+pub const MAX_CV: u16 = 1024;
+
+/// Check that a CV number is in the range 1 to 1024
+pub fn check_cv(cv: u16) -> Result<(), Error> {
+    if (1..=MAX_CV).contains(&cv) {
+        Ok(())
+    } else {
+        Err(Error::InvalidCv)
+    }
+}
+
+/// CV access instruction as used by service mode direct packets and
+/// the long form of operations mode (programming on the main) packets
+///
+/// CV numbers start at 1, bit positions at 0.
+#[derive(Debug, Clone, PartialEq)]
+pub enum CvAccess {
+    VerifyByte(u16, u8),
+    WriteByte(u16, u8),
+    VerifyBit(u16, u8, bool),
+    WriteBit(u16, u8, bool),
+}
+
+#[allow(clippy::unusual_byte_groupings)]
+impl CvAccess {
+    /// Parse an instruction from three bytes
+    ///
+    /// Only the lower four bits of the first byte are used,
+    /// the upper bits depend on the packet type.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        use CvAccess::*;
+        let cv = u16::from_be_bytes([bytes[0] & 0x03, bytes[1]]) + 1;
+        match (bytes[0] & 0b0000_1100) >> 2 {
+            0b01 => Some(VerifyByte(cv, bytes[2])),
+            0b11 => Some(WriteByte(cv, bytes[2])),
+            0b10 => match bytes[2] & 0b111_10000 {
+                0b111_10000 => Some(WriteBit(
+                    cv,
+                    bytes[2] & 0x07,
+                    bytes[2] & 0b000_01000 == 0b000_01000,
+                )),
+                0b111_00000 => Some(VerifyBit(
+                    cv,
+                    bytes[2] & 0x07,
+                    bytes[2] & 0b000_01000 == 0b000_01000,
+                )),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    /// Write the instruction to three bytes
+    ///
+    /// The upper four bits of the first byte are left empty. The CV has
+    /// to be checked with `check` before, other CVs are sent as CV 1024.
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use CvAccess::*;
+        let (cmd, cv, data) = match self {
+            VerifyByte(cv, value) => (0b01, cv, *value),
+            WriteByte(cv, value) => (0b11, cv, *value),
+            VerifyBit(cv, pos, value) => (0b10, cv, 0b111_00000 | (*value as u8) << 3 | pos & 0x07),
+            WriteBit(cv, pos, value) => (0b10, cv, 0b111_10000 | (*value as u8) << 3 | pos & 0x07),
+        };
+        let [h, l] = cv.wrapping_sub(1).to_be_bytes();
+        buf[0] = cmd << 2 | (h & 0x03);
+        buf[1] = l;
+        buf[2] = data;
+        3
+    }
+
+    pub fn cv(&self) -> u16 {
+        use CvAccess::*;
+        match self {
+            VerifyByte(cv, _) | WriteByte(cv, _) | VerifyBit(cv, ..) | WriteBit(cv, ..) => *cv,
+        }
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, CvAccess::WriteByte(..) | CvAccess::WriteBit(..))
+    }
+
+    /// Check the CV number and the bit position
+    pub fn check(&self) -> Result<(), Error> {
+        use CvAccess::*;
+        match self {
+            VerifyBit(_, pos, _) | WriteBit(_, pos, _) if *pos > 7 => Err(Error::InvalidCv),
+            _ => check_cv(self.cv()),
+        }
+    }
+}
+
+/// Service mode register access as used by physical register and paged mode
+///
+/// Registers are numbered 1 to 8, register 6 is the page register.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RegisterAccess {
+    Verify(u8, u8),
+    Write(u8, u8),
+}
+
+impl RegisterAccess {
+    pub const PAGE_REGISTER: u8 = 6;
+
+    /// Parse an instruction from two bytes
+    ///
+    /// Only the lower four bits of the first byte are used.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let register = (bytes[0] & 0x07) + 1;
+        if bytes[0] & 0x08 == 0x08 {
+            RegisterAccess::Write(register, bytes[1])
+        } else {
+            RegisterAccess::Verify(register, bytes[1])
+        }
+    }
+
+    /// Write the instruction to two bytes
+    ///
+    /// The upper four bits of the first byte are left empty. The register
+    /// has to be checked with `check` before.
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        let (write, register, value) = match self {
+            RegisterAccess::Verify(r, v) => (0x00, r, v),
+            RegisterAccess::Write(r, v) => (0x08, r, v),
+        };
+        buf[0] = write | (register.wrapping_sub(1) & 0x07);
+        buf[1] = *value;
+        2
+    }
+
+    pub fn is_write(&self) -> bool {
+        matches!(self, RegisterAccess::Write(..))
+    }
+
+    /// Check that the register is in the range 1 to 8
+    pub fn check(&self) -> Result<(), Error> {
+        match self {
+            RegisterAccess::Verify(r, _) | RegisterAccess::Write(r, _) if (1..=8).contains(r) => {
+                Ok(())
+            }
+            _ => Err(Error::InvalidCv),
+        }
+    }
+}
@@ -0,0 +1,271 @@
+//! Multifunction (loco) decoder on top of the reader
+//!
+//! The decoder filters packets by its primary, extended or consist
+//! address, keeps track of speed, direction and functions and stops
+//! the loco if no packet was addressed to it for the time set in CV11.
+
+use embedded_hal::timer::nb::CountDown;
+use embedded_time::duration::*;
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+    functions::{Function, FunctionGroupNumber},
+};
+use log::debug;
+
+use crate::function::{DccFunctionGroup, FunctionGroupByte};
+use crate::message::Message;
+use crate::reader::{Decoder, Reader};
+use crate::speed::DccSpeed;
+use crate::store::CvStore;
+use crate::Error;
+
+/// Decoder configuration as read from the CVs
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Active address (CV1 or CV17/18, selected by CV29 bit 5)
+    pub address: Address,
+    /// Consist address (CV19), `None` if not in a consist
+    pub consist: Option<Address>,
+    /// Reverse direction for consist commands (CV19 bit 7)
+    pub consist_reversed: bool,
+    /// Functions F0 to F12 controlled by the consist address (CV21/22)
+    pub consist_functions: u16,
+    /// Reverse direction (CV29 bit 0)
+    pub reversed: bool,
+    /// Use 14 speed steps, FL is controlled by speed packets (CV29 bit 1)
+    pub steps14: bool,
+    /// Packet timeout in seconds (CV11), 0 disables the timeout
+    pub timeout: u8,
+}
+
+impl Config {
+    /// Read the configuration using the given CV getter
+    ///
+    /// CV numbers start at 1.
+    pub fn from_cvs<F: FnMut(u16) -> u8>(mut cv: F) -> Self {
+        let cv29 = cv(29);
+        let address = if cv29 & 0x20 == 0x20 {
+            u16::from_be_bytes([cv(17) & 0x3F, cv(18)])
+        } else {
+            (cv(1) & 0x7F) as u16
+        };
+        let cv19 = cv(19);
+        let cv22 = cv(22) as u16;
+        let f0 = (cv22 & 0x03 != 0) as u16;
+        Self {
+            address: Address::new(address),
+            consist: match cv19 & 0x7F {
+                0 => None,
+                n => Some(Address::new(n as u16)),
+            },
+            consist_reversed: cv19 & 0x80 == 0x80,
+            consist_functions: f0 | (cv(21) as u16) << 1 | (cv22 & 0x3C) << 7,
+            reversed: cv29 & 0x01 == 0x01,
+            steps14: cv29 & 0x02 == 0x00,
+            timeout: cv(11),
+        }
+    }
+}
+
+impl Config {
+    /// Read the configuration from a CV store
+    pub fn from_store<S: CvStore>(store: &mut S) -> Result<Self, Error> {
+        let mut res = Ok(());
+        let config = Self::from_cvs(|cv| {
+            store.read(cv).unwrap_or_else(|e| {
+                res = Err(e);
+                0
+            })
+        });
+        res.map(|_| config)
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self::from_cvs(|cv| match cv {
+            1 => 3,
+            29 => 0x06,
+            _ => 0,
+        })
+    }
+}
+
+/// A multifunction decoder reading packets from a `Reader`
+pub struct LocoDecoder<D, TIM> {
+    reader: Reader<D>,
+    timer: TIM,
+    config: Config,
+    speed: Speed,
+    direction: Direction,
+    functions: [FunctionGroupByte; 10],
+    timeout_running: bool,
+}
+
+impl<D, TIM> LocoDecoder<D, TIM>
+where
+    D: Decoder,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+{
+    pub fn new(reader: Reader<D>, timer: TIM, config: Config) -> Self {
+        Self {
+            reader,
+            timer,
+            config,
+            speed: Speed::Stop,
+            direction: Direction::Forward,
+            functions: [0.into(); 10],
+            timeout_running: false,
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Replace the configuration, e.g. after CVs were written
+    pub fn set_config(&mut self, config: Config) {
+        self.config = config;
+    }
+
+    pub fn speed(&self) -> Speed {
+        self.speed
+    }
+
+    /// Get the direction with CV29 and CV19 reversal applied
+    pub fn direction(&self) -> Direction {
+        self.direction
+    }
+
+    pub fn function(&self, f: Function) -> bool {
+        let group = FunctionGroupNumber::from_function(f);
+        self.group(group).get(f)
+    }
+
+    pub fn is_service_mode(&self) -> bool {
+        self.reader.is_service_mode()
+    }
+
+    /// Read the next packet addressed to this decoder
+    ///
+    /// Broadcast and service mode packets are returned as well,
+    /// all other packets are skipped.
+    pub fn read(&mut self) -> nb::Result<Message, Error> {
+        if self.timeout_running && self.timer.wait().is_ok() {
+            debug!("packet timeout, stopping");
+            self.timeout_running = false;
+            self.speed = Speed::Stop;
+        }
+        let msg = self.reader.read()?;
+        if self.handle(&msg)? {
+            Ok(msg)
+        } else {
+            Err(nb::Error::WouldBlock)
+        }
+    }
+
+    /// Update the decoder state with a packet
+    ///
+    /// Returns if the packet was addressed to this decoder.
+    pub fn handle(&mut self, msg: &Message) -> Result<bool, Error> {
+        use Message::*;
+        let address = self.config.address;
+        let consist = self.config.consist;
+        match msg {
+            Reset => {
+                self.speed = Speed::Stop;
+                self.functions = [0.into(); 10];
+                self.timeout_running = false;
+                return Ok(true);
+            }
+            Drive(addr, _, speed) if addr.num == 0 => {
+                if let Speed::Stop | Speed::EmergencyStop = speed {
+                    self.speed = *speed;
+                }
+                return Ok(true);
+            }
+            DirectMode(_) | RegisterMode(_) => return Ok(true),
+            Drive(addr, dir, speed) if Some(*addr) == consist => {
+                self.drive(*dir, *speed, self.config.consist_reversed);
+            }
+            Drive(addr, dir, speed) if *addr == address => {
+                // the consist address takes over speed and direction
+                if consist.is_none() {
+                    self.drive(*dir, *speed, false);
+                }
+            }
+            FunctionGroup(addr, group, data) if *addr == address => {
+                let mut data = u8::from(*data);
+                if self.config.steps14 && *group == FunctionGroupNumber::G1 {
+                    // FL is controlled by speed packets
+                    data = (data & 0x0F) | (u8::from(*self.group(*group)) & 0x10);
+                }
+                self.functions[Self::index(*group)] = data.into();
+            }
+            FunctionGroup(addr, group, data) if Some(*addr) == consist => {
+                use num_traits::FromPrimitive;
+                let enabled = self.config.consist_functions;
+                for n in group.functions().filter(|n| *n <= 12) {
+                    if enabled & 1 << n != 0 {
+                        let f = Function::from_u8(n).unwrap();
+                        self.functions[Self::index(*group)].set(f, data.get(f));
+                    }
+                }
+            }
+            Unknown(addr) | ProgramOnMain(addr, _) if *addr == address => {}
+            _ => return Ok(false),
+        }
+        self.restart_timeout()?;
+        Ok(true)
+    }
+
+    fn drive(&mut self, dir: Direction, speed: Speed, consist_reversed: bool) {
+        self.direction = if self.config.reversed ^ consist_reversed {
+            match dir {
+                Direction::Forward => Direction::Backward,
+                Direction::Backward => Direction::Forward,
+            }
+        } else {
+            dir
+        };
+        self.speed = match speed {
+            // baseline packets are parsed as 28 steps, in 14 step mode
+            // the fifth speed bit controls FL instead
+            Speed::Steps28(s) if self.config.steps14 => {
+                let s = s / 8;
+                self.functions[0].set(Function::F0, s & 0x01 == 0x01);
+                Speed::from_byte_14_steps(s >> 1)
+            }
+            // stop packets without the fifth speed bit switch off FL
+            Speed::Stop | Speed::EmergencyStop if self.config.steps14 => {
+                self.functions[0].set(Function::F0, false);
+                speed
+            }
+            speed => speed,
+        };
+    }
+
+    fn restart_timeout(&mut self) -> Result<(), Error> {
+        if self.config.timeout == 0 {
+            return Ok(());
+        }
+        let timeout = self.config.timeout as u32 * 1_000_000;
+        self.timer
+            .start(timeout.microseconds())
+            .map_err(|_| Error::TimerError)?;
+        self.timeout_running = true;
+        Ok(())
+    }
+
+    fn group(&self, group: FunctionGroupNumber) -> &FunctionGroupByte {
+        &self.functions[Self::index(group)]
+    }
+
+    #[inline]
+    fn index(group: FunctionGroupNumber) -> usize {
+        use num_traits::ToPrimitive;
+        group.to_u8().unwrap() as usize - 1
+    }
+}
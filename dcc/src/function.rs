@@ -1,4 +1,5 @@
-use loco_core::functions::Function;
+use core::ops::RangeInclusive;
+use loco_core::functions::{Function, FunctionGroupNumber};
 
 #[derive(Clone, Debug, PartialEq, Copy)]
 pub struct FunctionGroupByte {
@@ -52,3 +53,37 @@ impl From<FunctionGroupByte> for u8 {
         data.data
     }
 }
+
+pub trait DccFunctionGroup {
+    fn from_function(f: Function) -> FunctionGroupNumber;
+    fn functions(&self) -> RangeInclusive<u8>;
+}
+
+impl DccFunctionGroup for FunctionGroupNumber {
+    #[inline]
+    fn from_function(f: Function) -> FunctionGroupNumber {
+        use num_traits::{FromPrimitive, ToPrimitive};
+        use FunctionGroupNumber::*;
+        match f.to_u8().unwrap() {
+            0..=4 => G1,
+            5..=8 => G2,
+            9..=12 => G3,
+            n => FunctionGroupNumber::from_u8((n - 13) / 8 + 4).unwrap(),
+        }
+    }
+
+    #[inline]
+    fn functions(&self) -> RangeInclusive<u8> {
+        use num_traits::ToPrimitive;
+        use FunctionGroupNumber::*;
+        match self {
+            G1 => 0..=4,
+            G2 => 5..=8,
+            G3 => 9..=12,
+            g => {
+                let first = (g.to_u8().unwrap() - 4) * 8 + 13;
+                first..=first + 7
+            }
+        }
+    }
+}
@@ -1,13 +1,28 @@
+#![cfg_attr(not(test), no_std)]
+
 pub mod address;
+pub mod cv;
+pub mod decoder;
 pub mod direction;
 pub mod function;
 pub mod message;
+pub mod railcom;
 pub mod reader;
+pub mod service;
 pub mod speed;
+pub mod store;
 pub mod writer;
 
 #[derive(Debug, PartialEq)]
 pub enum Error {
     IOError,
     TimerError,
+    NoAck,
+    StorageError,
+    InvalidCv,
+    ChecksumMismatch,
+    PacketTooLong,
+    Truncated,
+    InvalidTiming,
+    InvalidCode,
 }
@@ -1,7 +1,14 @@
-use crate::{address::DccAddress, direction::DccDirection, speed::DccSpeed};
+use crate::{
+    address::{DccAccessoryAddress, DccAddress},
+    cv::{CvAccess, RegisterAccess},
+    direction::DccDirection,
+    function::FunctionGroupByte,
+    speed::DccSpeed,
+};
 use loco_core::{
     address::Address,
     drive::{Direction, Speed},
+    functions::FunctionGroupNumber,
 };
 use log::trace;
 
@@ -9,15 +16,39 @@ use log::trace;
 pub enum Message {
     Unknown(Address),
     Drive(Address, Direction, Speed),
+    FunctionGroup(Address, FunctionGroupNumber, FunctionGroupByte),
+    /// Basic accessory (output address, output, activate)
+    BasicAccessory(Address, bool, bool),
+    /// Extended accessory (output address, aspect)
+    ExtendedAccessory(Address, u8),
+    /// Digital decoder reset (broadcast)
+    Reset,
+    Idle,
+    /// Service mode direct CV access
+    DirectMode(CvAccess),
+    /// Service mode physical register or paged access
+    RegisterMode(RegisterAccess),
+    /// Operations mode CV access (programming on the main)
+    ProgramOnMain(Address, CvAccess),
 }
 
 #[allow(clippy::unusual_byte_groupings)]
 impl Message {
     pub fn from_bytes(bytes: &[u8]) -> Self {
         use Message::*;
+        match bytes {
+            [0x00, 0x00, ..] => return Reset,
+            [0xFF, 0x00, ..] => return Idle,
+            [0b10_000000..=0b10_111111, ..] => return Self::accessory_from_bytes(bytes),
+            _ => {}
+        }
         let addr = Address::from_bytes(bytes);
         trace!("{:?} {:#04X?}", addr, bytes);
         let bytes = &bytes[addr.len()..];
+        // at least one instruction byte and the error detection byte
+        if bytes.len() < 2 {
+            return Unknown(addr);
+        }
         let cmd = (bytes[0] & 0b111_00000) >> 5;
         match cmd {
             0b010 | 0b011 => Drive(
@@ -33,12 +64,82 @@ impl Message {
                 ),
                 _ => Unknown(addr),
             },
+            0b100 => FunctionGroup(
+                addr,
+                FunctionGroupNumber::G1,
+                (bytes[0] & 0b000_11111).into(),
+            ),
+            0b101 => {
+                if bytes[0] & 0b000_10000 == 0b000_10000 {
+                    FunctionGroup(addr, FunctionGroupNumber::G2, (bytes[0] & 0x0F).into())
+                } else {
+                    FunctionGroup(addr, FunctionGroupNumber::G3, (bytes[0] << 4).into())
+                }
+            }
+            0b110 => {
+                use FunctionGroupNumber::*;
+                let group = match bytes[0] & 0b000_11111 {
+                    0b11110 => G4,
+                    0b11111 => G5,
+                    0b11000 => G6,
+                    0b11001 => G7,
+                    0b11010 => G8,
+                    0b11011 => G9,
+                    0b11100 => G10,
+                    _ => return Unknown(addr),
+                };
+                FunctionGroup(addr, group, bytes[1].into())
+            }
+            0b111 if bytes[0] & 0b000_10000 == 0 && bytes.len() >= 4 => {
+                match CvAccess::from_bytes(bytes) {
+                    Some(access) => ProgramOnMain(addr, access),
+                    None => Unknown(addr),
+                }
+            }
             _ => Unknown(addr),
         }
     }
 
+    /// Parse a packet received on the programming track
+    ///
+    /// Service mode packets can't be distinguished from operations
+    /// mode packets for addresses 112 to 127, so the caller has to
+    /// know if a decoder is in service mode. The given bytes must
+    /// include the error detection byte.
+    pub fn from_service_mode_bytes(bytes: &[u8]) -> Self {
+        use Message::*;
+        match bytes {
+            [0b0111_0000..=0b0111_1111, _, _, _] => match CvAccess::from_bytes(bytes) {
+                Some(access) => DirectMode(access),
+                None => Unknown(Address { num: 0 }),
+            },
+            [0b0111_0000..=0b0111_1111, _, _] => RegisterMode(RegisterAccess::from_bytes(bytes)),
+            _ => Self::from_bytes(bytes),
+        }
+    }
+
+    fn accessory_from_bytes(bytes: &[u8]) -> Self {
+        use Message::*;
+        let addr = match Address::from_accessory_bytes(bytes) {
+            Some(addr) => addr,
+            None => return Unknown(Address { num: 0 }),
+        };
+        trace!("accessory {:?} {:#04X?}", addr, bytes);
+        if bytes[1] & 0b1000_0000 == 0b1000_0000 {
+            BasicAccessory(
+                addr,
+                bytes[1] & 0b0000_0001 == 0b0000_0001,
+                bytes[1] & 0b0000_1000 == 0b0000_1000,
+            )
+        } else if bytes[1] & 0b0000_1001 == 0b0000_0001 && bytes.len() >= 4 {
+            ExtendedAccessory(addr, bytes[2])
+        } else {
+            Unknown(addr)
+        }
+    }
+
     pub fn to_buf(&self, buf: &mut [u8]) -> usize {
-        use loco_core::add_xor;
+        use loco_core::{add_xor, mov, xor};
         use Message::*;
         match self {
             Drive(addr, dir, speed) => {
@@ -52,6 +153,67 @@ impl Message {
                     add_xor(buf, n + 2)
                 }
             }
+            FunctionGroup(addr, group, data) => {
+                use FunctionGroupNumber::*;
+                let n = addr.to_buf(buf);
+                let data = u8::from(*data);
+                match group {
+                    G1 => {
+                        buf[n] = 0b100_00000 | (data & 0b000_11111);
+                        add_xor(buf, n + 2)
+                    }
+                    G2 => {
+                        buf[n] = 0b1011_0000 | (data & 0x0F);
+                        add_xor(buf, n + 2)
+                    }
+                    G3 => {
+                        buf[n] = 0b1010_0000 | (data >> 4);
+                        add_xor(buf, n + 2)
+                    }
+                    _ => {
+                        buf[n] = match group {
+                            G4 => 0b110_11110,
+                            G5 => 0b110_11111,
+                            G6 => 0b110_11000,
+                            G7 => 0b110_11001,
+                            G8 => 0b110_11010,
+                            G9 => 0b110_11011,
+                            _ => 0b110_11100,
+                        };
+                        buf[n + 1] = data;
+                        add_xor(buf, n + 3)
+                    }
+                }
+            }
+            BasicAccessory(addr, output, activate) => {
+                addr.to_accessory_buf(buf);
+                buf[1] |= 0b1000_0000 | (*activate as u8) << 3 | *output as u8;
+                add_xor(buf, 3)
+            }
+            ExtendedAccessory(addr, aspect) => {
+                addr.to_accessory_buf(buf);
+                buf[1] |= 0b0000_0001;
+                buf[2] = *aspect;
+                add_xor(buf, 4)
+            }
+            Reset => mov!(buf[0..3] <- &xor!([0x00, 0x00])),
+            Idle => mov!(buf[0..3] <- &xor!([0xFF, 0x00])),
+            DirectMode(access) => {
+                access.to_buf(buf);
+                buf[0] |= 0b0111_0000;
+                add_xor(buf, 4)
+            }
+            RegisterMode(access) => {
+                access.to_buf(buf);
+                buf[0] |= 0b0111_0000;
+                add_xor(buf, 3)
+            }
+            ProgramOnMain(addr, access) => {
+                let n = addr.to_buf(buf);
+                access.to_buf(&mut buf[n..]);
+                buf[n] |= 0b1110_0000;
+                add_xor(buf, n + 4)
+            }
             _ => unimplemented!(),
         }
     }
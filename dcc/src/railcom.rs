@@ -0,0 +1,171 @@
+//! RailCom datagram reception
+//!
+//! Decoders answer in the cutout after a packet with 4-of-8 encoded
+//! bytes at 250 kBaud, each byte carrying six data bits. Channel 1
+//! holds the address broadcast, channel 2 the answer to the packet
+//! addressed to the decoder. The bytes of both channels are fed into
+//! a `Receiver`, which is reset at the start of each channel.
+
+use loco_core::address::Address;
+
+use crate::Error;
+
+/// 4-of-8 codes of the six bit data values
+const CODES: [u8; 64] = [
+    0xAC, 0xAA, 0xA9, 0xA5, 0xA3, 0xA6, 0x9C, 0x9A, 0x99, 0x95, 0x93, 0x96, 0x8E, 0x8D, 0x8B, 0xB1,
+    0xB2, 0xB4, 0xB8, 0x74, 0x72, 0x6C, 0x6A, 0x69, 0x65, 0x63, 0x66, 0x5C, 0x5A, 0x59, 0x55, 0x53,
+    0x56, 0x4E, 0x4D, 0x4B, 0x47, 0x71, 0xE8, 0xE4, 0xE2, 0xD1, 0xC9, 0xC5, 0xD8, 0xD4, 0xD2, 0xCA,
+    0xC6, 0xCC, 0x78, 0x17, 0x1B, 0x1D, 0x1E, 0x2E, 0x36, 0x3A, 0x27, 0x2B, 0x2D, 0x35, 0x39, 0x33,
+];
+const ACK: [u8; 2] = [0x0F, 0xF0];
+const NACK: u8 = 0x3C;
+const BUSY: u8 = 0xE1;
+
+/// A received 4-of-8 encoded byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Symbol {
+    Data(u8),
+    Ack,
+    Nack,
+    Busy,
+}
+
+impl Symbol {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        use Symbol::*;
+        match byte {
+            b if ACK.contains(&b) => Some(Ack),
+            NACK => Some(Nack),
+            BUSY => Some(Busy),
+            b => CODES.iter().position(|c| *c == b).map(|v| Data(v as u8)),
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        use Symbol::*;
+        match self {
+            Data(v) => CODES[(v & 0x3F) as usize],
+            Ack => ACK[0],
+            Nack => NACK,
+            Busy => BUSY,
+        }
+    }
+}
+
+/// RailCom datagrams
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Datagram {
+    Ack,
+    Nack,
+    Busy,
+    /// Address broadcast, upper address byte (ID 1)
+    AddressHigh(u8),
+    /// Address broadcast, lower address byte (ID 2)
+    AddressLow(u8),
+    /// CV value read or written on the main (ID 0)
+    Pom(u8),
+    /// Dynamic data (subindex, value) (ID 7)
+    Dynamic(u8, u8),
+}
+
+impl Datagram {
+    /// Number of six bit symbols of a datagram with the given ID
+    fn symbols(id: u8) -> Option<u8> {
+        match id {
+            0..=2 => Some(2),
+            7 => Some(3),
+            _ => None,
+        }
+    }
+
+    fn from_bits(id: u8, bits: u32) -> Self {
+        use Datagram::*;
+        match id {
+            0 => Pom(bits as u8),
+            1 => AddressHigh(bits as u8),
+            2 => AddressLow(bits as u8),
+            _ => Dynamic((bits & 0x3F) as u8, (bits >> 6) as u8),
+        }
+    }
+
+    /// Get the loco address of an address broadcast
+    ///
+    /// Short addresses are sent with an upper byte of zero,
+    /// long addresses with the two upper bits set.
+    pub fn address(high: u8, low: u8) -> Address {
+        if high & 0x80 == 0x80 {
+            Address::new(u16::from_be_bytes([high & 0x3F, low]))
+        } else {
+            Address::new(low as u16)
+        }
+    }
+}
+
+/// Receives datagrams from the bytes of a RailCom channel
+#[derive(Debug, Default)]
+pub struct Receiver {
+    id: Option<u8>,
+    bits: u32,
+    left: u8,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drop a partially received datagram, e.g. at the start of a channel
+    pub fn reset(&mut self) {
+        self.id = None;
+        self.bits = 0;
+        self.left = 0;
+    }
+
+    /// Handle a received byte
+    ///
+    /// Returns a datagram once all of its bytes were received. Bytes
+    /// that are no valid 4-of-8 code or unknown datagram IDs result
+    /// in `Error::InvalidCode` and drop the current datagram.
+    pub fn push(&mut self, byte: u8) -> nb::Result<Datagram, Error> {
+        let symbol = match Symbol::from_byte(byte) {
+            Some(symbol) => symbol,
+            None => {
+                self.reset();
+                return Err(nb::Error::Other(Error::InvalidCode));
+            }
+        };
+        let value = match (symbol, self.id) {
+            (Symbol::Data(value), _) => value,
+            (_, Some(_)) => {
+                self.reset();
+                return Err(nb::Error::Other(Error::InvalidCode));
+            }
+            (Symbol::Ack, None) => return Ok(Datagram::Ack),
+            (Symbol::Nack, None) => return Ok(Datagram::Nack),
+            (Symbol::Busy, None) => return Ok(Datagram::Busy),
+        };
+        let id = match self.id {
+            Some(id) => id,
+            None => {
+                let id = value >> 2;
+                self.left = match Datagram::symbols(id) {
+                    Some(n) => n,
+                    None => return Err(nb::Error::Other(Error::InvalidCode)),
+                };
+                self.id = Some(id);
+                // the lower two bits of the first symbol are data bits
+                self.bits = (value & 0x03) as u32;
+                self.left -= 1;
+                return Err(nb::Error::WouldBlock);
+            }
+        };
+        self.bits = self.bits << 6 | value as u32;
+        self.left -= 1;
+        if self.left > 0 {
+            return Err(nb::Error::WouldBlock);
+        }
+        let datagram = Datagram::from_bits(id, self.bits);
+        self.reset();
+        Ok(datagram)
+    }
+}
@@ -7,6 +7,15 @@ use crate::message::Message;
 use crate::Error;
 
 const BUF_SIZE: usize = 8;
+/// Minimum packet length including the error detection byte
+const MIN_PACKET_SIZE: usize = 3;
+/// Half bit durations accepted by decoders (µs)
+const ONE_HALF_BIT: core::ops::RangeInclusive<u32> = 52..=64;
+const ZERO_HALF_BIT: core::ops::RangeInclusive<u32> = 90..=10000;
+/// Maximum difference of the two halves of a one bit (µs)
+const ONE_HALF_BIT_DIFF: u32 = 6;
+/// Minimum number of preamble bits of service mode packets
+const SERVICE_MODE_PREAMBLE_SIZE: u8 = 20;
 
 #[derive(Debug, PartialEq)]
 enum State {
@@ -111,11 +120,7 @@ where
 {
     #[inline]
     fn handle_half_bit(&mut self, bit: Bit) -> Option<Bit> {
-        trace!(
-            "{:<20}{:<20}",
-            "edge detected".to_string(),
-            format!("{:?}/{:?}", self.last_half_bit, bit)
-        );
+        trace!("{:<20}{:?}/{:?}", "edge detected", self.last_half_bit, bit);
         if self.last_half_bit == Some(bit) {
             self.last_half_bit = None;
             Some(bit)
@@ -126,6 +131,82 @@ where
     }
 }
 
+/// A decoder fed with timestamps of signal edges
+///
+/// The timestamps are taken from `edge`, e.g. popping from a queue
+/// filled by an input capture interrupt. Timestamps are in
+/// microseconds and may wrap around. Half bits are classified using
+/// the NMRA tolerances for decoders, other timings are rejected
+/// with `Error::InvalidTiming`.
+pub struct EdgeDecoder<F> {
+    edge: F,
+    last_edge: Option<Microseconds<u32>>,
+    last_half_bit: Option<(Bit, u32)>,
+}
+
+impl<F> EdgeDecoder<F>
+where
+    F: FnMut() -> nb::Result<Microseconds<u32>, Error>,
+{
+    pub fn new(edge: F) -> Self {
+        Self {
+            edge,
+            last_edge: None,
+            last_half_bit: None,
+        }
+    }
+}
+
+impl<F> Decoder for EdgeDecoder<F>
+where
+    F: FnMut() -> nb::Result<Microseconds<u32>, Error>,
+{
+    fn decode(&mut self) -> nb::Result<Bit, Error> {
+        let edge = (self.edge)()?;
+        let last_edge = self.last_edge.replace(edge);
+        let duration = match last_edge {
+            Some(last) => edge.0.wrapping_sub(last.0),
+            None => return Err(nb::Error::WouldBlock),
+        };
+        let bit = if ONE_HALF_BIT.contains(&duration) {
+            Bit::One
+        } else if ZERO_HALF_BIT.contains(&duration) {
+            Bit::Zero
+        } else {
+            debug!("invalid half bit duration {}µs", duration);
+            self.last_half_bit = None;
+            return Err(nb::Error::Other(Error::InvalidTiming));
+        };
+        match self.last_half_bit.take() {
+            Some((Bit::One, first)) if bit == Bit::One => {
+                if first.max(duration) - first.min(duration) > ONE_HALF_BIT_DIFF {
+                    debug!("asymmetric one bit {}µs/{}µs", first, duration);
+                    return Err(nb::Error::Other(Error::InvalidTiming));
+                }
+                Ok(bit)
+            }
+            Some((last, _)) if last == bit => Ok(bit),
+            _ => {
+                self.last_half_bit = Some((bit, duration));
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+}
+
+/// Packet counters to measure the signal quality
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Counters {
+    /// Valid packets read
+    pub packets: u32,
+    /// Packets with a wrong error detection byte
+    pub checksum_errors: u32,
+    /// Packets longer than the receive buffer
+    pub too_long: u32,
+    /// Packets shorter than the minimum packet length
+    pub truncated: u32,
+}
+
 /// A reader for the DCC protocol
 pub struct Reader<D> {
     decoder: D,
@@ -134,6 +215,9 @@ pub struct Reader<D> {
     buf: [u8; BUF_SIZE],
     bits_read: u8,
     state: State,
+    preamble: u8,
+    service_mode: bool,
+    counters: Counters,
 }
 
 impl<D> Reader<D>
@@ -148,9 +232,28 @@ where
             buf: [0; BUF_SIZE],
             bits_read: 0,
             state: State::Idle,
+            preamble: 0,
+            service_mode: false,
+            counters: Counters::default(),
         }
     }
 
+    /// Get if the last packets were service mode packets
+    ///
+    /// Service mode is entered on a reset packet with a long preamble
+    /// and left on the first packet with a short preamble.
+    pub fn is_service_mode(&self) -> bool {
+        self.service_mode
+    }
+
+    pub fn counters(&self) -> &Counters {
+        &self.counters
+    }
+
+    pub fn reset_counters(&mut self) {
+        self.counters = Counters::default();
+    }
+
     fn reset(&mut self) {
         use State::*;
         self.state = Idle;
@@ -163,6 +266,7 @@ where
         use State::*;
         self.state = Byte;
         self.bits_read = 0;
+        self.preamble = self.one_bits;
         self.one_bits = 0;
         self.current_byte = 0;
         self.buf = [0; BUF_SIZE];
@@ -171,15 +275,26 @@ where
     pub fn read(&mut self) -> nb::Result<Message, Error> {
         use Bit::*;
         use State::*;
-        let bit = self.decoder.decode()?;
+        let bit = match self.decoder.decode() {
+            Ok(bit) => bit,
+            Err(nb::Error::Other(e)) => {
+                // drop the current packet on signal errors
+                self.one_bits = 0;
+                self.reset();
+                return Err(nb::Error::Other(e));
+            }
+            Err(nb::Error::WouldBlock) => return Err(nb::Error::WouldBlock),
+        };
         trace!(
-            "{:<20}{:<20}",
+            "{:<20}{:?}/{:?}/{:?}",
             "bit read",
-            format!("{:?}/{:?}/{:?}", self.state, bit, self.bits_read)
+            self.state,
+            bit,
+            self.bits_read
         );
         match bit {
             One => {
-                self.one_bits += 1;
+                self.one_bits = self.one_bits.saturating_add(1);
             }
             Zero => {
                 if self.one_bits > 9 {
@@ -205,12 +320,42 @@ where
                 }
             }
             StartBit => {
+                let len = self.current_byte as usize;
                 if bit == Zero {
+                    if len >= BUF_SIZE {
+                        debug!("packet too long");
+                        self.counters.too_long = self.counters.too_long.wrapping_add(1);
+                        self.reset();
+                        return Err(nb::Error::Other(Error::PacketTooLong));
+                    }
                     self.state = Byte;
                 } else {
-                    let len = self.current_byte as usize;
-                    let msg = Message::from_bytes(&self.buf[..len]);
+                    if len < MIN_PACKET_SIZE {
+                        debug!("packet truncated after {} bytes", len);
+                        self.counters.truncated = self.counters.truncated.wrapping_add(1);
+                        self.reset();
+                        return Err(nb::Error::Other(Error::Truncated));
+                    }
+                    if self.buf[..len].iter().fold(0, |acc, x| acc ^ x) != 0 {
+                        debug!("checksum mismatch in {:#04X?}", &self.buf[..len]);
+                        self.counters.checksum_errors =
+                            self.counters.checksum_errors.wrapping_add(1);
+                        self.reset();
+                        return Err(nb::Error::Other(Error::ChecksumMismatch));
+                    }
+                    self.counters.packets = self.counters.packets.wrapping_add(1);
+                    let long_preamble = self.preamble >= SERVICE_MODE_PREAMBLE_SIZE;
+                    let msg = if self.service_mode && long_preamble {
+                        Message::from_service_mode_bytes(&self.buf[..len])
+                    } else {
+                        Message::from_bytes(&self.buf[..len])
+                    };
+                    self.service_mode =
+                        long_preamble && (self.service_mode || msg == Message::Reset);
                     debug!("read bytes {:#04X?} as {:?}", &self.buf[..len], msg);
+                    // the preamble starts after the end bit, ones of the
+                    // last byte must not count towards it
+                    self.one_bits = 0;
                     self.reset();
                     return Ok(msg);
                 }
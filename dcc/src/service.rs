@@ -0,0 +1,301 @@
+//! Service mode programming on the programming track
+//!
+//! Decoders on the programming track acknowledge instructions by
+//! drawing an additional current of at least 60mA for about 6ms.
+//! The programmer sends the packet sequences for direct, paged and
+//! physical register mode and uses an `AckDetector` to detect
+//! those pulses.
+
+use embedded_hal::digital::blocking::InputPin;
+use log::debug;
+
+use crate::cv::{check_cv, CvAccess, RegisterAccess};
+use crate::message::Message;
+use crate::writer::{Encoder, Writer, SERVICE_MODE_PREAMBLE_SIZE};
+use crate::Error;
+
+/// Reset packets sent before the first instruction after power on
+const POWER_ON_RESETS: u8 = 20;
+/// Reset packets sent before each instruction
+const RESETS: u8 = 3;
+/// Instruction packets sent
+const INSTRUCTIONS: u8 = 5;
+/// Write or reset packets sent after the instruction packets
+const RECOVERY: u8 = 6;
+
+/// Detects the acknowledgement current pulse of a decoder
+pub trait AckDetector {
+    /// Prepare the detection of a new acknowledgement
+    ///
+    /// Called before each instruction sequence starts.
+    fn reset(&mut self) {}
+
+    /// Get if the decoder currently acknowledges
+    fn is_ack(&mut self) -> Result<bool, Error>;
+}
+
+/// Detects acknowledgements using a comparator connected to an input pin
+///
+/// The pin has to be high while the current is above the
+/// acknowledgement threshold.
+pub struct PinAckDetector<P> {
+    pin: P,
+}
+
+impl<P: InputPin> PinAckDetector<P> {
+    pub fn new(pin: P) -> Self {
+        Self { pin }
+    }
+}
+
+impl<P: InputPin> AckDetector for PinAckDetector<P> {
+    fn is_ack(&mut self) -> Result<bool, Error> {
+        self.pin.is_high().map_err(|_| Error::IOError)
+    }
+}
+
+/// Detects acknowledgements using current samples, e.g. from an ADC
+///
+/// The lowest sample since the last reset is used as base current.
+/// An acknowledgement is detected if a sample exceeds the base current
+/// by at least `threshold`.
+pub struct SampleAckDetector<F> {
+    sample: F,
+    threshold: u16,
+    base: Option<u16>,
+}
+
+impl<F> SampleAckDetector<F>
+where
+    F: FnMut() -> nb::Result<u16, Error>,
+{
+    pub fn new(sample: F, threshold: u16) -> Self {
+        Self {
+            sample,
+            threshold,
+            base: None,
+        }
+    }
+}
+
+impl<F> AckDetector for SampleAckDetector<F>
+where
+    F: FnMut() -> nb::Result<u16, Error>,
+{
+    fn reset(&mut self) {
+        self.base = None;
+    }
+
+    fn is_ack(&mut self) -> Result<bool, Error> {
+        let sample = match (self.sample)() {
+            Ok(sample) => sample,
+            Err(nb::Error::WouldBlock) => return Ok(false),
+            Err(nb::Error::Other(e)) => return Err(e),
+        };
+        let base = self.base.map_or(sample, |base| base.min(sample));
+        self.base = Some(base);
+        Ok(sample >= base.saturating_add(self.threshold))
+    }
+}
+
+/// Service mode operations
+///
+/// CV numbers start at 1, registers are numbered 1 to 8.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Operation {
+    ReadDirect(u16),
+    Direct(CvAccess),
+    ReadPaged(u16),
+    WritePaged(u16, u8),
+    ReadRegister(u8),
+    WriteRegister(u8, u8),
+}
+
+impl Operation {
+    /// Check the CV number or register of the operation
+    pub fn check(&self) -> Result<(), Error> {
+        use Operation::*;
+        match self {
+            ReadDirect(cv) | ReadPaged(cv) | WritePaged(cv, _) => check_cv(*cv),
+            Direct(access) => access.check(),
+            ReadRegister(r) | WriteRegister(r, _) => RegisterAccess::Verify(*r, 0).check(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Step {
+    Reset(u8),
+    Instruction(u8),
+    Recovery(u8),
+}
+
+/// A programmer for decoders on the programming track
+pub struct Programmer<E, A> {
+    writer: Writer<E>,
+    detector: A,
+    operation: Option<Operation>,
+    stage: u16,
+    value: u8,
+    instruction: Message,
+    msg: Message,
+    step: Step,
+    acked: bool,
+    powered: bool,
+}
+
+impl<E, A> Programmer<E, A>
+where
+    E: Encoder,
+    A: AckDetector,
+{
+    pub fn new(encoder: E, detector: A) -> Self {
+        let mut writer = Writer::new(encoder);
+        writer.set_preamble_size(SERVICE_MODE_PREAMBLE_SIZE);
+        Self {
+            writer,
+            detector,
+            operation: None,
+            stage: 0,
+            value: 0,
+            instruction: Message::Reset,
+            msg: Message::Reset,
+            step: Step::Reset(0),
+            acked: false,
+            powered: false,
+        }
+    }
+
+    /// Start a new operation, replacing any running operation
+    ///
+    /// Returns `Error::InvalidCv` for a CV or register out of range.
+    pub fn start(&mut self, operation: Operation) -> Result<(), Error> {
+        operation.check()?;
+        debug!("starting {:?}", operation);
+        self.operation = Some(operation);
+        self.stage = 0;
+        self.value = 0;
+        self.start_sequence();
+        Ok(())
+    }
+
+    pub fn is_busy(&self) -> bool {
+        self.operation.is_some()
+    }
+
+    /// Write packets and detect acknowledgements
+    ///
+    /// Returns the value read, written or verified once the
+    /// operation finished or `Error::NoAck` if the decoder did
+    /// not acknowledge. Reset packets are sent while no
+    /// operation is running.
+    pub fn run(&mut self) -> nb::Result<u8, Error> {
+        if self.operation.is_none() {
+            self.writer.write(&Message::Reset)?;
+            return Err(nb::Error::WouldBlock);
+        }
+        let ack = self.detector.is_ack()?;
+        if ack && !matches!(self.step, Step::Reset(_)) {
+            self.acked = true;
+        }
+        self.writer.write(&self.msg)?;
+        use Step::*;
+        self.step = match self.step {
+            Reset(n) if n > 1 => Reset(n - 1),
+            Reset(_) => Instruction(INSTRUCTIONS),
+            Instruction(n) if n > 1 => Instruction(n - 1),
+            Instruction(_) => Recovery(RECOVERY),
+            Recovery(n) if n > 1 => Recovery(n - 1),
+            Recovery(_) => return self.finish_sequence(),
+        };
+        self.msg = match self.step {
+            Reset(_) => Message::Reset,
+            Instruction(_) => self.instruction.clone(),
+            Recovery(_) if self.is_write() => self.instruction.clone(),
+            Recovery(_) => Message::Reset,
+        };
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn is_write(&self) -> bool {
+        match &self.instruction {
+            Message::DirectMode(access) => access.is_write(),
+            Message::RegisterMode(access) => access.is_write(),
+            _ => false,
+        }
+    }
+
+    fn start_sequence(&mut self) {
+        use Operation::*;
+        use RegisterAccess::*;
+        let stage = self.stage;
+        let page = |cv: u16| (((cv - 1) / 4 + 1) & 0xFF) as u8;
+        let register = |cv: u16| ((cv - 1) % 4 + 1) as u8;
+        self.instruction = match self.operation.as_ref().unwrap() {
+            ReadDirect(cv) if stage < 8 => {
+                Message::DirectMode(CvAccess::VerifyBit(*cv, stage as u8, true))
+            }
+            ReadDirect(cv) => Message::DirectMode(CvAccess::VerifyByte(*cv, self.value)),
+            Direct(access) => Message::DirectMode(access.clone()),
+            ReadPaged(cv) | WritePaged(cv, _) if stage == 0 => {
+                Message::RegisterMode(Write(RegisterAccess::PAGE_REGISTER, page(*cv)))
+            }
+            ReadPaged(cv) => Message::RegisterMode(Verify(register(*cv), (stage - 1) as u8)),
+            WritePaged(cv, value) => Message::RegisterMode(Write(register(*cv), *value)),
+            ReadRegister(r) => Message::RegisterMode(Verify(*r, stage as u8)),
+            WriteRegister(r, value) => Message::RegisterMode(Write(*r, *value)),
+        };
+        let resets = if self.powered {
+            RESETS
+        } else {
+            POWER_ON_RESETS
+        };
+        self.powered = true;
+        self.step = Step::Reset(resets);
+        self.msg = Message::Reset;
+        self.acked = false;
+        self.detector.reset();
+    }
+
+    fn finish_sequence(&mut self) -> nb::Result<u8, Error> {
+        use Operation::*;
+        let acked = self.acked;
+        let stage = self.stage;
+        let operation = self.operation.as_ref().unwrap();
+        debug!("{:?} stage {} acked: {}", operation, stage, acked);
+        let next = match operation {
+            ReadDirect(_) if stage < 8 => {
+                if acked {
+                    self.value |= 1 << stage;
+                }
+                true
+            }
+            ReadPaged(_) => stage == 0 || (!acked && stage < 256),
+            WritePaged(..) => stage == 0,
+            ReadRegister(_) => !acked && stage < 255,
+            _ => false,
+        };
+        if next {
+            self.stage += 1;
+            self.start_sequence();
+            return Err(nb::Error::WouldBlock);
+        }
+        let value = match operation {
+            ReadDirect(_) => self.value,
+            Direct(CvAccess::VerifyByte(_, value) | CvAccess::WriteByte(_, value)) => *value,
+            Direct(CvAccess::VerifyBit(_, _, value) | CvAccess::WriteBit(_, _, value)) => {
+                *value as u8
+            }
+            ReadPaged(_) => (stage - 1) as u8,
+            ReadRegister(_) => stage as u8,
+            WritePaged(_, value) | WriteRegister(_, value) => *value,
+        };
+        self.operation = None;
+        self.msg = Message::Reset;
+        if acked {
+            Ok(value)
+        } else {
+            Err(nb::Error::Other(Error::NoAck))
+        }
+    }
+}
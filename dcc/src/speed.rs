@@ -44,9 +44,9 @@ impl DccSpeed for Speed {
         match self {
             Stop => 0x00,
             EmergencyStop => 0x01,
-            Steps14(s) => (s / 16) & 0x0E,
-            Steps28(s) => (((s / 8) & 0x30) >> 1) | (((s / 8) & 0x01) << 4) & 0xFE,
-            Steps128(s) => (s / 2) & 0x7E,
+            Steps14(s) => (s / 16) & 0x0F,
+            Steps28(s) => ((s / 8) >> 1) & 0x0F | ((s / 8) & 0x01) << 4,
+            Steps128(s) => (s / 2) & 0x7F,
         }
     }
 }
@@ -0,0 +1,309 @@
+//! Persistent configuration variable (CV) storage for decoders
+//!
+//! Stores are initialised from a table of default CVs. Writing 8 to CV8
+//! (manufacturer ID) resets all CVs to their defaults.
+
+use embedded_storage::nor_flash::NorFlash;
+use log::debug;
+
+use crate::cv::CvAccess;
+use crate::Error;
+
+/// Manufacturer ID reserved for public domain and do-it-yourself decoders
+pub const DIY_MANUFACTURER_ID: u8 = 13;
+
+/// Default CVs (primary address 3, 28/128 speed steps)
+pub const DEFAULT_CVS: &[(u16, u8)] = &[(1, 3), (7, 1), (8, DIY_MANUFACTURER_ID), (29, 0x06)];
+
+/// CV holding the manufacturer version number (read only)
+const CV_VERSION: u16 = 7;
+/// CV holding the manufacturer ID (read only)
+const CV_MANUFACTURER: u16 = 8;
+/// Value written to CV8 to reset all CVs
+const RESET_VALUE: u8 = 8;
+
+/// A store for CVs, CV numbers start at 1
+pub trait CvStore {
+    fn read(&mut self, cv: u16) -> Result<u8, Error>;
+
+    fn write(&mut self, cv: u16, value: u8) -> Result<(), Error>;
+
+    /// Reset all CVs to their defaults
+    fn reset(&mut self) -> Result<(), Error>;
+
+    /// Apply a CV access instruction, e.g. from service mode or
+    /// programming on the main packets
+    ///
+    /// Returns if the decoder should acknowledge the instruction.
+    /// CV7 and CV8 are read only, writing 8 to CV8 resets the store.
+    fn apply(&mut self, access: &CvAccess) -> Result<bool, Error> {
+        use CvAccess::*;
+        let cv = access.cv();
+        let read_only = cv == CV_VERSION || cv == CV_MANUFACTURER;
+        let res = match access {
+            WriteByte(CV_MANUFACTURER, RESET_VALUE) => self.reset().map(|_| true),
+            _ if read_only && access.is_write() => Ok(false),
+            VerifyByte(cv, value) => self.read(*cv).map(|v| v == *value),
+            WriteByte(cv, value) => self.write(*cv, *value).map(|_| true),
+            VerifyBit(cv, pos, value) => self.read(*cv).map(|v| (v >> pos) & 0x01 == *value as u8),
+            WriteBit(cv, pos, value) => self.read(*cv).and_then(|v| {
+                let v = (v & !(1 << pos)) | (*value as u8) << pos;
+                self.write(*cv, v).map(|_| true)
+            }),
+        };
+        match res {
+            Err(Error::InvalidCv) => Ok(false),
+            res => res,
+        }
+    }
+}
+
+#[inline]
+fn default_value(defaults: &[(u16, u8)], cv: u16) -> u8 {
+    defaults
+        .iter()
+        .find(|(n, _)| *n == cv)
+        .map_or(0, |(_, value)| *value)
+}
+
+#[inline]
+fn index<const N: usize>(cv: u16) -> Result<usize, Error> {
+    if cv >= 1 && cv as usize <= N {
+        Ok(cv as usize - 1)
+    } else {
+        Err(Error::InvalidCv)
+    }
+}
+
+/// A volatile store for the first `N` CVs
+pub struct MemoryCvStore<const N: usize> {
+    cvs: [u8; N],
+    defaults: &'static [(u16, u8)],
+}
+
+impl<const N: usize> MemoryCvStore<N> {
+    pub fn new(defaults: &'static [(u16, u8)]) -> Self {
+        let mut cvs = [0; N];
+        for (i, cv) in cvs.iter_mut().enumerate() {
+            *cv = default_value(defaults, i as u16 + 1);
+        }
+        Self { cvs, defaults }
+    }
+}
+
+impl<const N: usize> Default for MemoryCvStore<N> {
+    fn default() -> Self {
+        Self::new(DEFAULT_CVS)
+    }
+}
+
+impl<const N: usize> CvStore for MemoryCvStore<N> {
+    fn read(&mut self, cv: u16) -> Result<u8, Error> {
+        Ok(self.cvs[index::<N>(cv)?])
+    }
+
+    fn write(&mut self, cv: u16, value: u8) -> Result<(), Error> {
+        self.cvs[index::<N>(cv)?] = value;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        *self = Self::new(self.defaults);
+        Ok(())
+    }
+}
+
+/// Size of a record in flash
+const RECORD_SIZE: usize = 4;
+/// Marker of a bank header record
+const HEADER: [u8; 2] = [0x4C, 0x43];
+
+/// A store for the first `N` CVs backed by NOR flash
+///
+/// The flash is split into two banks. Changed CVs are appended to the
+/// active bank as records, so each write only uses four bytes of flash.
+/// Once the active bank is full, all CVs that differ from their default
+/// are copied to the other bank and the active bank is erased.
+///
+/// The flash capacity has to be a multiple of two erase sizes and the
+/// read and write sizes of the flash must divide the record size of four
+/// bytes, otherwise `new` returns `Error::StorageError`. All CVs are
+/// cached in RAM.
+pub struct FlashCvStore<F, const N: usize> {
+    flash: F,
+    cvs: [u8; N],
+    defaults: &'static [(u16, u8)],
+    bank: u32,
+    seq: u8,
+    offset: u32,
+}
+
+impl<F, const N: usize> FlashCvStore<F, N>
+where
+    F: NorFlash,
+{
+    /// Load the CVs from flash, formatting it if it holds no valid bank
+    pub fn new(flash: F, defaults: &'static [(u16, u8)]) -> Result<Self, Error> {
+        if RECORD_SIZE % F::WRITE_SIZE != 0
+            || RECORD_SIZE % F::READ_SIZE != 0
+            || flash.capacity() % (2 * F::ERASE_SIZE) != 0
+        {
+            return Err(Error::StorageError);
+        }
+        let mut store = Self {
+            flash,
+            cvs: MemoryCvStore::<N>::new(defaults).cvs,
+            defaults,
+            bank: 0,
+            seq: 0,
+            offset: RECORD_SIZE as u32,
+        };
+        store.load()?;
+        Ok(store)
+    }
+
+    /// Release the flash
+    pub fn free(self) -> F {
+        self.flash
+    }
+
+    fn bank_size(&self) -> u32 {
+        (self.flash.capacity() / 2) as u32
+    }
+
+    fn read_record(&mut self, offset: u32) -> Result<[u8; RECORD_SIZE], Error> {
+        let mut record = [0; RECORD_SIZE];
+        self.flash
+            .read(offset, &mut record)
+            .map_err(|_| Error::StorageError)?;
+        Ok(record)
+    }
+
+    fn write_record(&mut self, offset: u32, record: [u8; RECORD_SIZE]) -> Result<(), Error> {
+        self.flash
+            .write(offset, &record)
+            .map_err(|_| Error::StorageError)
+    }
+
+    fn erase_bank(&mut self, bank: u32) -> Result<(), Error> {
+        let size = self.bank_size();
+        self.flash
+            .erase(bank * size, (bank + 1) * size)
+            .map_err(|_| Error::StorageError)
+    }
+
+    fn header(&mut self, bank: u32) -> Result<Option<u8>, Error> {
+        let offset = bank * self.bank_size();
+        match self.read_record(offset)? {
+            [h0, h1, seq, check] if [h0, h1] == HEADER && check == !seq => Ok(Some(seq)),
+            _ => Ok(None),
+        }
+    }
+
+    fn load(&mut self) -> Result<(), Error> {
+        // a bank header is written after copying, so both banks are
+        // only valid if erasing the old bank was interrupted
+        let (bank, seq) = match (self.header(0)?, self.header(1)?) {
+            (Some(a), Some(b)) if b == a.wrapping_add(1) => (1, b),
+            (Some(a), _) => (0, a),
+            (None, Some(b)) => (1, b),
+            (None, None) => {
+                debug!("no valid bank, formatting flash");
+                self.erase_bank(0)?;
+                self.erase_bank(1)?;
+                self.write_record(0, [HEADER[0], HEADER[1], 0, !0])?;
+                (0, 0)
+            }
+        };
+        self.bank = bank;
+        self.seq = seq;
+        let start = bank * self.bank_size();
+        let mut offset = RECORD_SIZE as u32;
+        while offset < self.bank_size() {
+            let record = self.read_record(start + offset)?;
+            if record == [0xFF; RECORD_SIZE] {
+                break;
+            }
+            // skip records that were not written completely
+            let [h, l, value, check] = record;
+            let cv = u16::from_be_bytes([h, l]);
+            if check == Self::check(cv, value) {
+                if let Ok(i) = index::<N>(cv) {
+                    self.cvs[i] = value;
+                }
+            }
+            offset += RECORD_SIZE as u32;
+        }
+        self.offset = offset;
+        debug!("loaded CVs from bank {} ({} bytes used)", bank, offset);
+        Ok(())
+    }
+
+    #[inline]
+    fn check(cv: u16, value: u8) -> u8 {
+        let [h, l] = cv.to_be_bytes();
+        h ^ l ^ value ^ 0xA5
+    }
+
+    fn record(cv: u16, value: u8) -> [u8; RECORD_SIZE] {
+        let [h, l] = cv.to_be_bytes();
+        [h, l, value, Self::check(cv, value)]
+    }
+
+    /// Copy all CVs differing from their defaults to the other bank
+    fn compact(&mut self) -> Result<(), Error> {
+        let old = self.bank;
+        let bank = 1 - old;
+        let start = bank * self.bank_size();
+        debug!("compacting CVs into bank {}", bank);
+        self.erase_bank(bank)?;
+        let mut offset = RECORD_SIZE as u32;
+        for i in 0..N {
+            let cv = i as u16 + 1;
+            let value = self.cvs[i];
+            if value != default_value(self.defaults, cv) {
+                if offset >= self.bank_size() {
+                    return Err(Error::StorageError);
+                }
+                self.write_record(start + offset, Self::record(cv, value))?;
+                offset += RECORD_SIZE as u32;
+            }
+        }
+        let seq = self.seq.wrapping_add(1);
+        self.write_record(start, [HEADER[0], HEADER[1], seq, !seq])?;
+        self.erase_bank(old)?;
+        self.bank = bank;
+        self.seq = seq;
+        self.offset = offset;
+        Ok(())
+    }
+}
+
+impl<F, const N: usize> CvStore for FlashCvStore<F, N>
+where
+    F: NorFlash,
+{
+    fn read(&mut self, cv: u16) -> Result<u8, Error> {
+        Ok(self.cvs[index::<N>(cv)?])
+    }
+
+    fn write(&mut self, cv: u16, value: u8) -> Result<(), Error> {
+        let i = index::<N>(cv)?;
+        if self.cvs[i] == value {
+            return Ok(());
+        }
+        self.cvs[i] = value;
+        if self.offset >= self.bank_size() {
+            return self.compact();
+        }
+        let offset = self.bank * self.bank_size() + self.offset;
+        self.write_record(offset, Self::record(cv, value))?;
+        self.offset += RECORD_SIZE as u32;
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<(), Error> {
+        self.cvs = MemoryCvStore::<N>::new(self.defaults).cvs;
+        self.compact()
+    }
+}
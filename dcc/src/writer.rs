@@ -1,4 +1,4 @@
-use embedded_hal::digital::blocking::ToggleableOutputPin;
+use embedded_hal::digital::blocking::{OutputPin, ToggleableOutputPin};
 use embedded_hal::timer::nb::CountDown;
 use embedded_time::duration::*;
 
@@ -8,13 +8,25 @@ use crate::Error;
 use log::{debug, trace};
 
 const BUF_SIZE: usize = 8;
-const PREAMBLE_SIZE: u8 = 14;
+pub const PREAMBLE_SIZE: u8 = 14;
+/// Preamble size used for packets on the programming track
+pub const SERVICE_MODE_PREAMBLE_SIZE: u8 = 24;
 // half bit lengths in microseconds
 const ONE_HALF_BIT: u32 = 58;
 const ZERO_HALF_BIT: u32 = 100;
+// RailCom cutout start and end after the packet end bit in microseconds
+const CUTOUT_START: u32 = 29;
+const CUTOUT_END: u32 = 471;
 
 pub trait Encoder {
     fn write(&mut self, bit: &Bit) -> nb::Result<(), Error>;
+
+    /// Switch off the track for the RailCom cutout after a packet
+    ///
+    /// Encoders without cutout support return right away.
+    fn cutout(&mut self) -> nb::Result<(), Error> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -28,14 +40,33 @@ impl Copy for Bit {}
 #[derive(Debug, PartialEq)]
 enum EncoderState {
     Idle,
-    WritingFirstHalf,
-    WritingSecondHalf,
+    WritingFirstHalf(Bit),
+    WritingSecondHalf(Bit),
+    CutoutStart,
+    Cutout,
+}
+
+/// Placeholder for encoders without a RailCom cutout pin
+#[derive(Debug)]
+pub struct NoCutout;
+
+impl OutputPin for NoCutout {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
-pub struct PinEncoder<DCC, TIM> {
+pub struct PinEncoder<DCC, TIM, CUT = NoCutout> {
     pin_dcc: DCC,
     timer: TIM,
+    pin_cutout: Option<CUT>,
     state: EncoderState,
 }
 
@@ -49,10 +80,31 @@ where
         Self {
             pin_dcc,
             timer,
+            pin_cutout: None,
             state: EncoderState::Idle,
         }
     }
 
+    /// Use a pin to switch off the H-bridge during the RailCom cutout
+    ///
+    /// The pin is set high while the cutout is active.
+    pub fn with_cutout<CUT: OutputPin>(self, pin_cutout: CUT) -> PinEncoder<DCC, TIM, CUT> {
+        PinEncoder {
+            pin_dcc: self.pin_dcc,
+            timer: self.timer,
+            pin_cutout: Some(pin_cutout),
+            state: self.state,
+        }
+    }
+}
+
+impl<DCC, TIM, CUT> PinEncoder<DCC, TIM, CUT>
+where
+    DCC: ToggleableOutputPin,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+    CUT: OutputPin,
+{
     fn toggle_pin(&mut self) -> Result<(), Error> {
         self.pin_dcc.toggle().map_err(|_| Error::IOError)
     }
@@ -70,35 +122,88 @@ where
     fn wait_timer(&mut self) -> nb::Result<(), Error> {
         self.timer.wait().map_err(|e| e.map(|_| Error::TimerError))
     }
+
+    fn set_cutout(&mut self, active: bool) -> Result<(), Error> {
+        if let Some(pin) = self.pin_cutout.as_mut() {
+            let res = if active {
+                pin.set_high()
+            } else {
+                pin.set_low()
+            };
+            res.map_err(|_| Error::IOError)?;
+        }
+        Ok(())
+    }
 }
 
-impl<DCC, TIM> Encoder for PinEncoder<DCC, TIM>
+impl<DCC, TIM, CUT> Encoder for PinEncoder<DCC, TIM, CUT>
 where
     DCC: ToggleableOutputPin,
     TIM: CountDown,
     TIM::Time: From<Microseconds<u32>>,
+    CUT: OutputPin,
 {
     #[inline]
     fn write(&mut self, bit: &Bit) -> nb::Result<(), Error> {
         use EncoderState::*;
+        if matches!(self.state, CutoutStart | Cutout) {
+            // finish the cutout before the next bit
+            self.cutout()?;
+        }
         if self.state != Idle {
             self.wait_timer()?;
         }
         match self.state {
             Idle => {
                 self.start_timer(bit)?;
-                self.state = WritingFirstHalf;
+                self.state = WritingFirstHalf(*bit);
             }
-            WritingFirstHalf => {
+            WritingFirstHalf(_) => {
                 self.toggle_pin()?;
                 self.start_timer(bit)?;
-                self.state = WritingSecondHalf;
+                self.state = WritingSecondHalf(*bit);
             }
-            WritingSecondHalf => {
+            WritingSecondHalf(_) => {
                 self.toggle_pin()?;
                 self.state = Idle;
                 return Ok(());
             }
+            CutoutStart | Cutout => {}
+        }
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn cutout(&mut self) -> nb::Result<(), Error> {
+        use EncoderState::*;
+        if self.pin_cutout.is_none() {
+            return Ok(());
+        }
+        match self.state {
+            Idle => {
+                self.timer
+                    .start(CUTOUT_START.microseconds())
+                    .map_err(|_| Error::TimerError)?;
+                self.state = CutoutStart;
+            }
+            CutoutStart => {
+                self.wait_timer()?;
+                self.set_cutout(true)?;
+                self.timer
+                    .start((CUTOUT_END - CUTOUT_START).microseconds())
+                    .map_err(|_| Error::TimerError)?;
+                self.state = Cutout;
+            }
+            Cutout => {
+                self.wait_timer()?;
+                self.set_cutout(false)?;
+                self.state = Idle;
+                return Ok(());
+            }
+            WritingFirstHalf(bit) | WritingSecondHalf(bit) => {
+                // finish the bit before the cutout
+                self.write(&bit)?;
+                return self.cutout();
+            }
         }
         Err(nb::Error::WouldBlock)
     }
@@ -111,6 +216,7 @@ enum State {
     Zero,
     Writing(Bit),
     End,
+    Cutout,
 }
 
 pub struct Writer<E> {
@@ -119,6 +225,8 @@ pub struct Writer<E> {
     buf: [u8; BUF_SIZE],
     bytes_to_write: usize,
     bits_written: usize,
+    preamble_size: u8,
+    railcom: bool,
 }
 
 impl<E> Writer<E>
@@ -133,9 +241,23 @@ where
             buf: [0; BUF_SIZE],
             bytes_to_write: 0,
             bits_written: 0,
+            preamble_size: PREAMBLE_SIZE,
+            railcom: false,
         }
     }
 
+    /// Set the preamble size used for the following packets
+    #[inline]
+    pub fn set_preamble_size(&mut self, size: u8) {
+        self.preamble_size = size;
+    }
+
+    /// Insert a RailCom cutout after each packet
+    #[inline]
+    pub fn set_railcom(&mut self, enabled: bool) {
+        self.railcom = enabled;
+    }
+
     #[inline]
     fn write_preamble(&mut self, left: u8) -> nb::Result<(), Error> {
         use State::*;
@@ -188,10 +310,10 @@ where
     pub fn write(&mut self, msg: &Message) -> nb::Result<(), Error> {
         use State::*;
         trace!(
-            "{:<20}{:<20}{:<20}",
-            format!("{:?}", self.state),
-            "",
-            format!("{}/{}", self.bits_written, self.bytes_to_write * 8)
+            "{:?}\t{}/{}",
+            self.state,
+            self.bits_written,
+            self.bytes_to_write * 8
         );
         match self.state {
             Idle => {
@@ -203,7 +325,7 @@ where
                 );
                 self.bits_written = 0;
                 debug!("starting preamble");
-                self.write_preamble(PREAMBLE_SIZE)
+                self.write_preamble(self.preamble_size)
             }
             Preamble(left) => {
                 self.write_preamble(left)?;
@@ -243,10 +365,21 @@ where
             }
             End => {
                 self.write_end()?;
+                if self.railcom {
+                    debug!("starting cutout");
+                    self.state = Cutout;
+                    self.encoder.cutout()?;
+                }
                 debug!("finished");
                 self.state = Idle;
                 Ok(())
             }
+            Cutout => {
+                self.encoder.cutout()?;
+                debug!("cutout done, finished");
+                self.state = Idle;
+                Ok(())
+            }
         }
     }
 }
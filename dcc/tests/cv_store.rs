@@ -0,0 +1,172 @@
+use embedded_storage::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use test_log::test;
+
+use loco_dcc::{
+    cv::CvAccess,
+    decoder::Config,
+    store::{CvStore, FlashCvStore, MemoryCvStore, DEFAULT_CVS, DIY_MANUFACTURER_ID},
+    Error,
+};
+
+const ERASE_SIZE: usize = 64;
+
+/// A NOR flash in RAM with two erase sectors
+#[derive(Debug)]
+struct RamFlash {
+    data: [u8; 2 * ERASE_SIZE],
+    erases: usize,
+}
+
+impl RamFlash {
+    fn new() -> Self {
+        Self {
+            data: [0x00; 2 * ERASE_SIZE],
+            erases: 0,
+        }
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.data[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.data.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.data[from as usize..to as usize].fill(0xFF);
+        self.erases += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        for (i, b) in bytes.iter().enumerate() {
+            // NOR flash can only clear bits
+            self.data[offset as usize + i] &= b;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn memory_defaults_and_reset() {
+    let mut store: MemoryCvStore<1024> = MemoryCvStore::default();
+    assert_eq!(store.read(1), Ok(3));
+    assert_eq!(store.read(8), Ok(DIY_MANUFACTURER_ID));
+    assert_eq!(store.read(29), Ok(0x06));
+    assert_eq!(store.read(1025), Err(Error::InvalidCv));
+    assert_eq!(store.apply(&CvAccess::WriteByte(1, 42)), Ok(true));
+    assert_eq!(store.apply(&CvAccess::VerifyByte(1, 42)), Ok(true));
+    assert_eq!(store.apply(&CvAccess::WriteBit(29, 5, true)), Ok(true));
+    assert_eq!(store.apply(&CvAccess::VerifyBit(29, 5, true)), Ok(true));
+    assert_eq!(store.apply(&CvAccess::VerifyBit(29, 0, true)), Ok(false));
+    assert_eq!(store.read(29), Ok(0x26));
+    // read only and unknown CVs are not acknowledged
+    assert_eq!(store.apply(&CvAccess::WriteByte(7, 2)), Ok(false));
+    assert_eq!(store.apply(&CvAccess::WriteByte(1025, 2)), Ok(false));
+    assert_eq!(store.read(7), Ok(1));
+    // only writing 8 to CV8 resets to the defaults
+    assert_eq!(store.apply(&CvAccess::WriteByte(8, 13)), Ok(false));
+    assert_eq!(store.read(1), Ok(42));
+    assert_eq!(store.apply(&CvAccess::WriteByte(8, 8)), Ok(true));
+    assert_eq!(store.read(1), Ok(3));
+    assert_eq!(store.read(29), Ok(0x06));
+}
+
+/// A flash writing eight bytes at once, which doesn't fit the records
+struct WideFlash(RamFlash);
+
+impl ErrorType for WideFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for WideFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.0.read(offset, bytes)
+    }
+
+    fn capacity(&self) -> usize {
+        self.0.capacity()
+    }
+}
+
+impl NorFlash for WideFlash {
+    const WRITE_SIZE: usize = 8;
+    const ERASE_SIZE: usize = ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.0.erase(from, to)
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.0.write(offset, bytes)
+    }
+}
+
+#[test]
+fn flash_geometry() {
+    let store: Result<FlashCvStore<_, 256>, _> =
+        FlashCvStore::new(WideFlash(RamFlash::new()), DEFAULT_CVS);
+    assert!(matches!(store, Err(Error::StorageError)));
+}
+
+#[test]
+fn flash_persistence() {
+    let mut store: FlashCvStore<_, 256> = FlashCvStore::new(RamFlash::new(), DEFAULT_CVS).unwrap();
+    assert_eq!(store.read(1), Ok(3));
+    store.write(1, 42).unwrap();
+    store.write(17, 0xC4).unwrap();
+    store.write(18, 0xD2).unwrap();
+    store.write(29, 0x26).unwrap();
+    let flash = store.free();
+
+    let mut store: FlashCvStore<_, 256> = FlashCvStore::new(flash, DEFAULT_CVS).unwrap();
+    assert_eq!(store.read(1), Ok(42));
+    assert_eq!(store.read(29), Ok(0x26));
+    let config = Config::from_store(&mut store).unwrap();
+    assert_eq!(config.address.num, 1234);
+
+    assert_eq!(store.apply(&CvAccess::WriteByte(8, 8)), Ok(true));
+    let flash = store.free();
+    let mut store: FlashCvStore<_, 256> = FlashCvStore::new(flash, DEFAULT_CVS).unwrap();
+    assert_eq!(store.read(1), Ok(3));
+    assert_eq!(store.read(29), Ok(0x06));
+}
+
+#[test]
+fn flash_wear_levelling() {
+    let mut store: FlashCvStore<_, 256> = FlashCvStore::new(RamFlash::new(), DEFAULT_CVS).unwrap();
+    for i in 0..100 {
+        store.write(3, i).unwrap();
+        store.write(4, i + 1).unwrap();
+    }
+    // rewriting the same value doesn't use flash
+    store.write(4, 100).unwrap();
+    let flash = store.free();
+    // 199 records are written (CV3 defaults to 0), a bank holds 15
+    // records and two are copied on each compaction, so the flash is
+    // formatted and compacted 14 times, erasing both banks each time
+    assert_eq!(flash.erases, 2 + 14 * 2);
+
+    let mut store: FlashCvStore<_, 256> = FlashCvStore::new(flash, DEFAULT_CVS).unwrap();
+    assert_eq!(store.read(1), Ok(3));
+    assert_eq!(store.read(3), Ok(99));
+    assert_eq!(store.read(4), Ok(100));
+}
@@ -0,0 +1,170 @@
+use embedded_hal_mock::timer::*;
+use embedded_hal_sync_pins::wire::*;
+use embedded_time::duration::*;
+use test_log::test;
+
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+    functions::{Function, FunctionGroupNumber},
+};
+use loco_dcc::{
+    decoder::{Config, LocoDecoder},
+    message::Message,
+    reader::{PinDecoder, Reader},
+};
+
+type DccDecoder = LocoDecoder<PinDecoder<InputOnlyPin, SimTimer>, SimTimer>;
+
+fn decoder(cvs: &[(u16, u8)]) -> (DccDecoder, SimClock) {
+    let wire_dcc = Wire::new_with_pull(WireState::High);
+    let mut clock = SimClock::new();
+    let reader = Reader::new(PinDecoder::new(
+        wire_dcc.connect_input_pin(),
+        clock.get_timer(),
+    ));
+    let config = Config::from_cvs(|cv| {
+        cvs.iter()
+            .find(|(n, _)| *n == cv)
+            .map_or(0, |(_, value)| *value)
+    });
+    (LocoDecoder::new(reader, clock.get_timer(), config), clock)
+}
+
+fn drive(num: u16, dir: Direction, speed: Speed) -> Message {
+    Message::Drive(Address::new(num), dir, speed)
+}
+
+#[test]
+fn addresses() {
+    let (mut dec, _) = decoder(&[(1, 3), (17, 0xC4), (18, 0xD2), (29, 0x06)]);
+    assert_eq!(dec.config().address, Address::new(3));
+    assert_eq!(
+        dec.handle(&drive(4, Direction::Forward, Speed::Steps128(20))),
+        Ok(false)
+    );
+    assert_eq!(dec.speed(), Speed::Stop);
+    assert_eq!(
+        dec.handle(&drive(3, Direction::Backward, Speed::Steps128(20))),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::Steps128(20));
+    assert_eq!(dec.direction(), Direction::Backward);
+
+    let (mut dec, _) = decoder(&[(1, 3), (17, 0xC4), (18, 0xD2), (29, 0x26)]);
+    assert_eq!(dec.config().address, Address::new(1234));
+    assert_eq!(
+        dec.handle(&drive(3, Direction::Forward, Speed::Steps128(20))),
+        Ok(false)
+    );
+    assert_eq!(
+        dec.handle(&drive(1234, Direction::Forward, Speed::Steps128(20))),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::Steps128(20));
+}
+
+#[test]
+fn functions() {
+    let (mut dec, _) = decoder(&[(1, 3), (29, 0x06)]);
+    let msg = Message::FunctionGroup(Address::new(3), FunctionGroupNumber::G1, 0x11.into());
+    assert_eq!(dec.handle(&msg), Ok(true));
+    let msg = Message::FunctionGroup(Address::new(3), FunctionGroupNumber::G3, 0x20.into());
+    assert_eq!(dec.handle(&msg), Ok(true));
+    let msg = Message::FunctionGroup(Address::new(3), FunctionGroupNumber::G10, 0x80.into());
+    assert_eq!(dec.handle(&msg), Ok(true));
+    for (f, value) in [
+        (Function::F0, true),
+        (Function::F1, true),
+        (Function::F2, false),
+        (Function::F10, true),
+        (Function::F68, true),
+    ] {
+        assert_eq!(dec.function(f), value, "{:?}", f);
+    }
+}
+
+#[test]
+fn speed_steps_and_direction() {
+    // 14 speed steps with reversed direction
+    let (mut dec, _) = decoder(&[(1, 3), (29, 0x01)]);
+    assert!(dec.config().steps14);
+    // speed step 5 with FL set, parsed as 28 step packet
+    assert_eq!(
+        dec.handle(&drive(3, Direction::Forward, Speed::Steps28(13 * 8))),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::Steps14(6 * 16));
+    assert_eq!(dec.direction(), Direction::Backward);
+    assert!(dec.function(Function::F0));
+    // function group one does not control FL
+    let msg = Message::FunctionGroup(Address::new(3), FunctionGroupNumber::G1, 0x01.into());
+    assert_eq!(dec.handle(&msg), Ok(true));
+    assert!(dec.function(Function::F0));
+    assert!(dec.function(Function::F1));
+    // speed byte 0x00 stops the loco and switches FL off
+    let msg = Message::from_bytes(&[0x03, 0x40, 0x43]);
+    assert_eq!(msg, drive(3, Direction::Backward, Speed::Stop));
+    assert_eq!(dec.handle(&msg), Ok(true));
+    assert_eq!(dec.speed(), Speed::Stop);
+    assert!(!dec.function(Function::F0));
+}
+
+#[test]
+fn consist() {
+    let (mut dec, _) = decoder(&[(1, 3), (19, 0x80 | 10), (21, 0x01), (22, 0x01), (29, 0x06)]);
+    assert_eq!(
+        dec.handle(&drive(10, Direction::Forward, Speed::Steps128(20))),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::Steps128(20));
+    assert_eq!(dec.direction(), Direction::Backward);
+    // speed on the loco address is ignored while in a consist
+    assert_eq!(
+        dec.handle(&drive(3, Direction::Forward, Speed::Steps128(40))),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::Steps128(20));
+    // only F0 and F1 are controlled by the consist address
+    let msg = Message::FunctionGroup(Address::new(10), FunctionGroupNumber::G1, 0x13.into());
+    assert_eq!(dec.handle(&msg), Ok(true));
+    assert!(dec.function(Function::F0));
+    assert!(dec.function(Function::F1));
+    assert!(!dec.function(Function::F2));
+}
+
+#[test]
+fn broadcast() {
+    let (mut dec, _) = decoder(&[(1, 3), (29, 0x06)]);
+    dec.handle(&drive(3, Direction::Forward, Speed::Steps128(20)))
+        .unwrap();
+    let msg = Message::FunctionGroup(Address::new(3), FunctionGroupNumber::G1, 0x10.into());
+    dec.handle(&msg).unwrap();
+    assert_eq!(
+        dec.handle(&drive(0, Direction::Forward, Speed::EmergencyStop)),
+        Ok(true)
+    );
+    assert_eq!(dec.speed(), Speed::EmergencyStop);
+    assert!(dec.function(Function::F0));
+    dec.handle(&drive(3, Direction::Forward, Speed::Steps128(20)))
+        .unwrap();
+    assert_eq!(dec.handle(&Message::Reset), Ok(true));
+    assert_eq!(dec.speed(), Speed::Stop);
+    assert!(!dec.function(Function::F0));
+}
+
+#[test]
+fn timeout() {
+    let (mut dec, mut clock) = decoder(&[(1, 3), (11, 2), (29, 0x06)]);
+    dec.handle(&drive(3, Direction::Forward, Speed::Steps128(20)))
+        .unwrap();
+    // other decoders' packets don't restart the timeout
+    dec.handle(&drive(4, Direction::Forward, Speed::Steps128(20)))
+        .unwrap();
+    clock.tick(1_900_000_000.nanoseconds());
+    assert!(dec.read().is_err());
+    assert_eq!(dec.speed(), Speed::Steps128(20));
+    clock.tick(200_000_000.nanoseconds());
+    assert!(dec.read().is_err());
+    assert_eq!(dec.speed(), Speed::Stop);
+}
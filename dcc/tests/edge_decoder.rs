@@ -0,0 +1,94 @@
+use embedded_time::duration::*;
+use test_log::test;
+
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+};
+use loco_dcc::{
+    message::Message,
+    reader::{EdgeDecoder, Reader},
+    Error,
+};
+
+/// Get half bit durations of a packet
+fn half_bits(packet: &[u8], one: [u32; 2], zero: [u32; 2]) -> Vec<u32> {
+    let mut bits = vec![true; 14];
+    for byte in packet.iter() {
+        bits.push(false);
+        bits.extend((0..8).rev().map(|i| (byte >> i) & 0x01 == 0x01));
+    }
+    bits.push(true);
+    bits.iter()
+        .flat_map(|b| if *b { one } else { zero })
+        .collect()
+}
+
+/// Read all messages and errors from edges separated by the given durations
+fn read_edges(durations: Vec<u32>, start: u32) -> Vec<Result<Message, Error>> {
+    let mut time = start;
+    let mut edges = vec![Microseconds(time)];
+    for d in durations {
+        time = time.wrapping_add(d);
+        edges.push(Microseconds(time));
+    }
+    let count = edges.len();
+    edges.reverse();
+    let mut reader = Reader::new(EdgeDecoder::new(move || {
+        edges.pop().ok_or(nb::Error::WouldBlock)
+    }));
+    let mut results = vec![];
+    for _ in 0..count {
+        match reader.read() {
+            Ok(msg) => results.push(Ok(msg)),
+            Err(nb::Error::Other(e)) => results.push(Err(e)),
+            Err(nb::Error::WouldBlock) => {}
+        }
+    }
+    results
+}
+
+fn drive() -> (Message, [u8; 4]) {
+    (
+        Message::Drive(Address::new(3), Direction::Forward, Speed::Steps128(40)),
+        [0x03, 0x3F, 0x94, 0x03 ^ 0x3F ^ 0x94],
+    )
+}
+
+#[test]
+fn tolerances() {
+    let (msg, packet) = drive();
+    for (one, zero) in [
+        ([58, 58], [100, 100]),
+        ([52, 56], [90, 10000]),
+        ([64, 64], [95, 120]),
+    ] {
+        let results = read_edges(half_bits(&packet, one, zero), 0);
+        assert_eq!(results, vec![Ok(msg.clone())], "{:?}/{:?}", one, zero);
+    }
+    // timestamps wrap around
+    let results = read_edges(half_bits(&packet, [58, 58], [100, 100]), u32::MAX - 1000);
+    assert_eq!(results, vec![Ok(msg)]);
+}
+
+#[test]
+fn invalid_timings() {
+    let (msg, packet) = drive();
+    for (one, zero) in [
+        ([50, 58], [100, 100]),
+        ([58, 58], [100, 10001]),
+        ([52, 60], [100, 100]),
+    ] {
+        let mut durations = half_bits(&packet, one, zero);
+        durations.extend(half_bits(&packet, [58, 58], [100, 100]));
+        let results = read_edges(durations, 0);
+        assert!(
+            results.contains(&Err(Error::InvalidTiming)),
+            "{:?}/{:?}",
+            one,
+            zero
+        );
+        // the following valid packet is read
+        assert_eq!(results.last(), Some(&Ok(msg.clone())));
+    }
+}
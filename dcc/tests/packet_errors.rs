@@ -0,0 +1,106 @@
+use test_log::test;
+
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+};
+use loco_dcc::{
+    message::Message,
+    reader::{Bit, Counters, Decoder, Reader},
+    Error,
+};
+
+/// Decodes bits from a list of packets
+struct BitDecoder {
+    bits: Vec<Bit>,
+}
+
+impl BitDecoder {
+    fn new(packets: &[&[u8]]) -> Self {
+        let mut bits = vec![];
+        for packet in packets {
+            bits.extend([Bit::One; 14]);
+            for byte in packet.iter() {
+                bits.push(Bit::Zero);
+                for i in (0..8).rev() {
+                    bits.push(if (byte >> i) & 0x01 == 0x01 {
+                        Bit::One
+                    } else {
+                        Bit::Zero
+                    });
+                }
+            }
+            bits.push(Bit::One);
+        }
+        bits.reverse();
+        Self { bits }
+    }
+}
+
+impl Decoder for BitDecoder {
+    fn decode(&mut self) -> nb::Result<Bit, Error> {
+        self.bits.pop().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+fn read_all(packets: &[&[u8]]) -> (Vec<Result<Message, Error>>, Counters) {
+    let mut reader = Reader::new(BitDecoder::new(packets));
+    let mut results = vec![];
+    for _ in 0..packets.len() * 100 {
+        match reader.read() {
+            Err(nb::Error::WouldBlock) => {}
+            Err(nb::Error::Other(e)) => results.push(Err(e)),
+            Ok(msg) => results.push(Ok(msg)),
+        }
+    }
+    (results, reader.counters().clone())
+}
+
+#[test]
+fn packet_errors() {
+    let (results, counters) = read_all(&[
+        &[0x03, 0x3F, 0x94, 0x03 ^ 0x3F ^ 0x94],
+        &[0x03, 0x3F, 0x94, 0x00],
+        &[0x03, 0x03],
+        &[0x03, 0x3F, 0x94, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        &[0xFF, 0x00, 0xFF],
+    ]);
+    assert_eq!(
+        results,
+        vec![
+            Ok(Message::Drive(
+                Address::new(3),
+                Direction::Forward,
+                Speed::Steps128(40)
+            )),
+            Err(Error::ChecksumMismatch),
+            Err(Error::Truncated),
+            Err(Error::PacketTooLong),
+            Ok(Message::Idle),
+        ]
+    );
+    assert_eq!(
+        counters,
+        Counters {
+            packets: 2,
+            checksum_errors: 1,
+            too_long: 1,
+            truncated: 1,
+        }
+    );
+}
+
+#[test]
+fn idle_before_reset() {
+    // test that the ones at the end of an idle packet don't make the
+    // preamble of the next packet a service mode preamble
+    let mut reader = Reader::new(BitDecoder::new(&[&[0xFF, 0x00, 0xFF], &[0x00, 0x00, 0x00]]));
+    let mut messages = vec![];
+    for _ in 0..200 {
+        if let Ok(msg) = reader.read() {
+            messages.push(msg);
+        }
+    }
+    assert_eq!(messages, [Message::Idle, Message::Reset]);
+    assert!(!reader.is_service_mode());
+}
@@ -0,0 +1,87 @@
+use test_log::test;
+
+use loco_core::address::Address;
+use loco_dcc::{
+    railcom::{Datagram, Receiver, Symbol},
+    Error,
+};
+
+/// Encode a datagram with the given ID and data bits as bytes
+fn encode(id: u8, data: u32, symbols: u8) -> Vec<u8> {
+    let bits = (id as u32) << (symbols as u32 * 6 - 4) | data;
+    (0..symbols)
+        .rev()
+        .map(|i| Symbol::Data(((bits >> (i * 6)) & 0x3F) as u8).to_byte())
+        .collect()
+}
+
+fn receive(bytes: &[u8]) -> Vec<Result<Datagram, Error>> {
+    let mut receiver = Receiver::new();
+    bytes
+        .iter()
+        .filter_map(|b| match receiver.push(*b) {
+            Ok(d) => Some(Ok(d)),
+            Err(nb::Error::Other(e)) => Some(Err(e)),
+            Err(nb::Error::WouldBlock) => None,
+        })
+        .collect()
+}
+
+#[test]
+fn codes() {
+    let mut seen = vec![];
+    for value in 0..64 {
+        let byte = Symbol::Data(value).to_byte();
+        assert_eq!(byte.count_ones(), 4);
+        assert!(!seen.contains(&byte));
+        seen.push(byte);
+        assert_eq!(Symbol::from_byte(byte), Some(Symbol::Data(value)));
+    }
+    for s in [Symbol::Ack, Symbol::Nack, Symbol::Busy] {
+        assert_eq!(Symbol::from_byte(s.to_byte()), Some(s));
+    }
+    assert_eq!(Symbol::from_byte(0xF0), Some(Symbol::Ack));
+    assert_eq!(Symbol::from_byte(0xFF), None);
+}
+
+#[test]
+fn address_broadcast() {
+    let mut bytes = encode(1, 0x80 | 0x04, 2);
+    bytes.extend(encode(2, 0xD2, 2));
+    let datagrams = receive(&bytes);
+    assert_eq!(
+        datagrams,
+        vec![
+            Ok(Datagram::AddressHigh(0x84)),
+            Ok(Datagram::AddressLow(0xD2))
+        ]
+    );
+    assert_eq!(Datagram::address(0x84, 0xD2), Address::new(1234));
+    assert_eq!(Datagram::address(0x00, 3), Address::new(3));
+}
+
+#[test]
+fn channel2() {
+    let mut bytes = encode(0, 0xA5, 2);
+    bytes.extend(encode(7, 0x55 << 6 | 0x13, 3));
+    bytes.push(Symbol::Ack.to_byte());
+    bytes.push(0xFF);
+    // ACK in the middle of a datagram
+    bytes.extend(&encode(0, 0x01, 2)[..1]);
+    bytes.push(Symbol::Ack.to_byte());
+    bytes.push(Symbol::Nack.to_byte());
+    // unknown ID
+    bytes.push(Symbol::Data(0x3C).to_byte());
+    assert_eq!(
+        receive(&bytes),
+        vec![
+            Ok(Datagram::Pom(0xA5)),
+            Ok(Datagram::Dynamic(0x13, 0x55)),
+            Ok(Datagram::Ack),
+            Err(Error::InvalidCode),
+            Err(Error::InvalidCode),
+            Ok(Datagram::Nack),
+            Err(Error::InvalidCode),
+        ]
+    );
+}
@@ -0,0 +1,238 @@
+use embedded_hal_mock::timer::*;
+use embedded_hal_sync_pins::wire::*;
+use embedded_time::duration::*;
+use std::cell::Cell;
+use std::rc::Rc;
+use test_log::test;
+
+use loco_dcc::{
+    cv::{CvAccess, RegisterAccess},
+    message::Message,
+    reader::{PinDecoder, Reader},
+    service::{AckDetector, Operation, Programmer},
+    writer::PinEncoder,
+    Error,
+};
+
+/// ACK pulse length in simulation ticks (µs)
+const ACK_LENGTH: u32 = 6000;
+
+struct SimAck(Rc<Cell<u32>>);
+
+impl AckDetector for SimAck {
+    fn is_ack(&mut self) -> Result<bool, Error> {
+        Ok(self.0.get() > 0)
+    }
+}
+
+/// A simulated decoder on the programming track
+struct SimDecoder {
+    cvs: [u8; 1024],
+    page: u8,
+    last: Option<Message>,
+    handled: bool,
+    ack: Rc<Cell<u32>>,
+}
+
+impl SimDecoder {
+    fn new(ack: Rc<Cell<u32>>) -> Self {
+        Self {
+            cvs: [0; 1024],
+            page: 1,
+            last: None,
+            handled: false,
+            ack,
+        }
+    }
+
+    fn register(&mut self, register: u8) -> &mut u8 {
+        match register {
+            1..=4 => &mut self.cvs[(self.page as usize - 1) * 4 + register as usize - 1],
+            5 => &mut self.cvs[28],
+            6 => &mut self.page,
+            r => &mut self.cvs[r as usize - 1],
+        }
+    }
+
+    fn handle(&mut self, msg: Message) {
+        // instructions are only executed after two identical packets
+        if self.last.as_ref() != Some(&msg) {
+            self.last = Some(msg);
+            self.handled = false;
+            return;
+        }
+        if self.handled {
+            return;
+        }
+        self.handled = true;
+        let ack = match msg {
+            Message::DirectMode(CvAccess::VerifyByte(cv, value)) => {
+                self.cvs[cv as usize - 1] == value
+            }
+            Message::DirectMode(CvAccess::WriteByte(cv, value)) => {
+                self.cvs[cv as usize - 1] = value;
+                true
+            }
+            Message::DirectMode(CvAccess::VerifyBit(cv, pos, value)) => {
+                (self.cvs[cv as usize - 1] >> pos) & 0x01 == value as u8
+            }
+            Message::DirectMode(CvAccess::WriteBit(cv, pos, value)) => {
+                let cv = &mut self.cvs[cv as usize - 1];
+                *cv = (*cv & !(1 << pos)) | ((value as u8) << pos);
+                true
+            }
+            Message::RegisterMode(RegisterAccess::Verify(r, value)) => *self.register(r) == value,
+            Message::RegisterMode(RegisterAccess::Write(r, value)) => {
+                *self.register(r) = value;
+                true
+            }
+            _ => false,
+        };
+        if ack {
+            self.ack.set(ACK_LENGTH);
+        }
+    }
+}
+
+/// Run the given operations against a simulated decoder and
+/// return the results and the final decoder state
+fn program(
+    ops: Vec<Operation>,
+    decoder_present: bool,
+    cvs: &[(u16, u8)],
+) -> (Vec<Result<u8, Error>>, SimDecoder) {
+    let wire_dcc = Wire::new_with_pull(WireState::High);
+    let writer_pin_dcc = wire_dcc.connect_push_pull_pin();
+    let reader_pin_dcc = wire_dcc.connect_input_pin();
+
+    let mut clock = SimClock::new();
+    let writer_timer = clock.get_timer();
+    let reader_timer = clock.get_timer();
+
+    let ack = Rc::new(Cell::new(0));
+    let encoder = PinEncoder::new(writer_pin_dcc, writer_timer);
+    let mut programmer = Programmer::new(encoder, SimAck(ack.clone()));
+    let mut reader = Reader::new(PinDecoder::new(reader_pin_dcc, reader_timer));
+    let mut decoder = SimDecoder::new(ack.clone());
+    for (cv, value) in cvs {
+        decoder.cvs[*cv as usize - 1] = *value;
+    }
+
+    let mut ops = ops.into_iter();
+    let mut results = vec![];
+    programmer
+        .start(ops.next().expect("at least one operation"))
+        .unwrap();
+
+    loop {
+        if clock.elapsed() > 5_u32.seconds() {
+            panic!("simulation timed out");
+        }
+        match programmer.run() {
+            Err(nb::Error::WouldBlock) => {}
+            res => {
+                results.push(res.map_err(|e| match e {
+                    nb::Error::Other(e) => e,
+                    nb::Error::WouldBlock => unreachable!(),
+                }));
+                if let Some(op) = ops.next() {
+                    programmer.start(op).unwrap();
+                } else {
+                    break;
+                }
+            }
+        }
+        if let Ok(msg) = reader.read() {
+            if decoder_present && reader.is_service_mode() {
+                decoder.handle(msg);
+            }
+        }
+        ack.set(ack.get().saturating_sub(1));
+        clock.tick(1000.nanoseconds());
+    }
+    (results, decoder)
+}
+
+#[test]
+fn direct_write_and_read() {
+    let (results, decoder) = program(
+        vec![
+            Operation::Direct(CvAccess::WriteByte(3, 0xA5)),
+            Operation::ReadDirect(3),
+            Operation::Direct(CvAccess::VerifyByte(3, 0xA5)),
+        ],
+        true,
+        &[],
+    );
+    assert_eq!(results, vec![Ok(0xA5), Ok(0xA5), Ok(0xA5)]);
+    assert_eq!(decoder.cvs[2], 0xA5);
+}
+
+#[test]
+fn direct_bits() {
+    let (results, decoder) = program(
+        vec![
+            Operation::Direct(CvAccess::WriteBit(29, 5, true)),
+            Operation::Direct(CvAccess::VerifyBit(29, 1, true)),
+            Operation::Direct(CvAccess::VerifyBit(29, 2, true)),
+        ],
+        true,
+        &[(29, 0b0000_0010)],
+    );
+    assert_eq!(results, vec![Ok(1), Ok(1), Err(Error::NoAck)]);
+    assert_eq!(decoder.cvs[28], 0b0010_0010);
+}
+
+#[test]
+fn paged_write_and_read() {
+    let (results, decoder) = program(
+        vec![Operation::WritePaged(6, 2), Operation::ReadPaged(6)],
+        true,
+        &[],
+    );
+    assert_eq!(results, vec![Ok(2), Ok(2)]);
+    assert_eq!(decoder.cvs[5], 2);
+    assert_eq!(decoder.page, 2);
+}
+
+#[test]
+fn register_write_and_read() {
+    let (results, decoder) = program(
+        vec![Operation::WriteRegister(5, 3), Operation::ReadRegister(5)],
+        true,
+        &[(29, 0x06)],
+    );
+    assert_eq!(results, vec![Ok(3), Ok(3)]);
+    assert_eq!(decoder.cvs[28], 3);
+}
+
+#[test]
+fn no_decoder() {
+    let (results, _) = program(
+        vec![
+            Operation::Direct(CvAccess::WriteByte(1, 3)),
+            Operation::Direct(CvAccess::VerifyByte(1, 3)),
+        ],
+        false,
+        &[],
+    );
+    assert_eq!(results, vec![Err(Error::NoAck), Err(Error::NoAck)]);
+}
+
+#[test]
+fn invalid_cv() {
+    use Operation::*;
+    for op in [
+        ReadDirect(0),
+        ReadDirect(1025),
+        WritePaged(0, 1),
+        Direct(CvAccess::WriteByte(0, 1)),
+        Direct(CvAccess::WriteBit(3, 8, true)),
+        ReadRegister(0),
+        WriteRegister(9, 1),
+    ] {
+        assert_eq!(op.check(), Err(Error::InvalidCv), "{:?}", op);
+    }
+    assert_eq!(ReadDirect(1024).check(), Ok(()));
+    assert_eq!(WriteRegister(8, 1).check(), Ok(()));
+}
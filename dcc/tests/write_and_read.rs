@@ -7,11 +7,14 @@ use test_log::test;
 use loco_core::{
     address::Address,
     drive::{Direction, Speed},
+    functions::{Function, FunctionGroupNumber},
 };
 use loco_dcc::{
+    cv::CvAccess,
+    function::{DccFunctionGroup, FunctionGroupByte},
     message::Message,
     reader::{PinDecoder, Reader},
-    writer::{PinEncoder, Writer},
+    writer::{Bit, Encoder, PinEncoder, Writer},
 };
 
 type DccWriter = Writer<PinEncoder<PushPullPin, SimTimer>>;
@@ -115,3 +118,222 @@ fn single_message() {
         Message::Drive(Address { num: 2 }, Direction::Forward, Speed::Steps128(4)),
     ]);
 }
+
+#[test]
+fn function_groups() {
+    use FunctionGroupNumber::*;
+    write_and_read_messages(vec![
+        Message::FunctionGroup(Address { num: 3 }, G1, 0b0001_0101.into()),
+        Message::FunctionGroup(Address { num: 3 }, G2, 0b0000_1010.into()),
+        Message::FunctionGroup(Address { num: 3 }, G3, 0b1001_0000.into()),
+        Message::FunctionGroup(Address { num: 1234 }, G4, 0b1000_0001.into()),
+        Message::FunctionGroup(Address { num: 1234 }, G5, 0b0111_1110.into()),
+        Message::FunctionGroup(Address { num: 42 }, G6, 0b1100_0011.into()),
+        Message::FunctionGroup(Address { num: 42 }, G7, 0b0011_1100.into()),
+        Message::FunctionGroup(Address { num: 42 }, G8, 0b1010_1010.into()),
+        Message::FunctionGroup(Address { num: 42 }, G9, 0b0101_0101.into()),
+        Message::FunctionGroup(Address { num: 42 }, G10, 0b1111_1111.into()),
+    ]);
+}
+
+#[test]
+fn accessories() {
+    write_and_read_messages(vec![
+        Message::BasicAccessory(Address { num: 1 }, false, true),
+        Message::BasicAccessory(Address { num: 2 }, true, true),
+        Message::BasicAccessory(Address { num: 255 }, true, false),
+        Message::BasicAccessory(Address { num: 2044 }, false, true),
+        Message::ExtendedAccessory(Address { num: 1 }, 0),
+        Message::ExtendedAccessory(Address { num: 1000 }, 31),
+        Message::ExtendedAccessory(Address { num: 2044 }, 255),
+    ]);
+}
+
+#[test]
+fn accessory_bytes() {
+    let mut buf = [0; 8];
+    let msg = Message::BasicAccessory(Address { num: 1 }, true, true);
+    assert_eq!(msg.to_buf(&mut buf), 3);
+    assert_eq!(buf[..3], [0b1000_0001, 0b1111_1001, 0b0111_1000]);
+    // first output of decoder address 0 can't be addressed
+    assert_eq!(
+        Message::from_bytes(&[0b1000_0000, 0b1111_1000, 0b0111_1000]),
+        Message::Unknown(Address { num: 0 })
+    );
+    let msg = Message::ExtendedAccessory(Address { num: 5 }, 0x1F);
+    assert_eq!(msg.to_buf(&mut buf), 4);
+    assert_eq!(buf[..4], [0b1000_0010, 0b0111_0001, 0x1F, 0b1110_1100]);
+    // extended accessory packets without the aspect byte are unknown
+    assert_eq!(
+        Message::from_bytes(&[0b1000_0010, 0b0111_0001, 0b1111_0011]),
+        Message::Unknown(Address { num: 5 })
+    );
+}
+
+#[test]
+fn program_on_main() {
+    write_and_read_messages(vec![
+        Message::ProgramOnMain(Address { num: 3 }, CvAccess::WriteByte(3, 20)),
+        Message::ProgramOnMain(Address { num: 3 }, CvAccess::VerifyByte(1024, 0xFF)),
+        Message::ProgramOnMain(Address { num: 4000 }, CvAccess::WriteBit(29, 5, true)),
+        Message::ProgramOnMain(Address { num: 4000 }, CvAccess::VerifyBit(29, 1, false)),
+    ]);
+    let mut buf = [0; 8];
+    let msg = Message::ProgramOnMain(Address { num: 3 }, CvAccess::WriteByte(3, 20));
+    assert_eq!(msg.to_buf(&mut buf), 5);
+    assert_eq!(
+        buf[..5],
+        [0x03, 0b1110_1100, 0x02, 20, 0x03 ^ 0b1110_1100 ^ 0x02 ^ 20]
+    );
+}
+
+#[test]
+fn function_group_of_function() {
+    use loco_core::functions::Function::*;
+    use FunctionGroupNumber::*;
+    for (f, g) in [
+        (F0, G1),
+        (F4, G1),
+        (F5, G2),
+        (F9, G3),
+        (F12, G3),
+        (F13, G4),
+        (F28, G5),
+        (F29, G6),
+        (F60, G9),
+        (F61, G10),
+        (F68, G10),
+    ] {
+        assert_eq!(FunctionGroupNumber::from_function(f), g);
+    }
+    let mut data = FunctionGroupByte::from(0x00);
+    data.set(Function::F10, true);
+    let msg = Message::FunctionGroup(Address { num: 3 }, G3, data);
+    let mut buf = [0; 8];
+    assert_eq!(msg.to_buf(&mut buf), 3);
+    assert_eq!(buf[..3], [0x03, 0b1010_0010, 0x03 ^ 0b1010_0010]);
+    assert_eq!(Message::from_bytes(&buf[..3]), msg);
+}
+
+#[test]
+fn railcom_cutout() {
+    use embedded_hal::digital::blocking::InputPin;
+    let wire_dcc = Wire::new_with_pull(WireState::High);
+    let wire_cutout = Wire::new_with_pull(WireState::Low);
+    let cutout_pin = wire_cutout.connect_input_pin();
+
+    let mut clock = SimClock::new();
+    let encoder = PinEncoder::new(wire_dcc.connect_push_pull_pin(), clock.get_timer())
+        .with_cutout(wire_cutout.connect_push_pull_pin());
+    let mut writer = Writer::new(encoder);
+    writer.set_railcom(true);
+    let mut reader = Reader::new(PinDecoder::new(
+        wire_dcc.connect_input_pin(),
+        clock.get_timer(),
+    ));
+
+    let msgs = vec![
+        Message::Drive(Address { num: 3 }, Direction::Forward, Speed::Steps128(56)),
+        Message::Idle,
+        Message::Drive(Address { num: 4 }, Direction::Backward, Speed::Steps128(4)),
+    ];
+    let mut written = 0;
+    let mut recv = vec![];
+    let mut cutouts = vec![];
+    let mut cutout_start = None;
+    while written < msgs.len() {
+        if clock.elapsed() > 500_u32.milliseconds() {
+            panic!("simulation timed out");
+        }
+        if writer.write(&msgs[written]).is_ok() {
+            written += 1;
+        }
+        if let Ok(msg) = reader.read() {
+            recv.push(msg);
+        }
+        let now = clock.elapsed().0 / 1000;
+        match (cutout_pin.is_high().unwrap(), cutout_start) {
+            (true, None) => cutout_start = Some(now),
+            (false, Some(start)) => {
+                cutouts.push(now - start);
+                cutout_start = None;
+            }
+            _ => {}
+        }
+        clock.tick(1000.nanoseconds());
+    }
+    assert_eq!(recv, msgs);
+    assert_eq!(cutouts.len(), 3);
+    for length in cutouts {
+        assert!((440..=445).contains(&length), "{}", length);
+    }
+}
+
+#[test]
+fn interleaved_cutout() {
+    use embedded_hal::digital::blocking::InputPin;
+    let wire_dcc = Wire::new_with_pull(WireState::High);
+    let wire_cutout = Wire::new_with_pull(WireState::Low);
+    let dcc_pin = wire_dcc.connect_input_pin();
+    let cutout_pin = wire_cutout.connect_input_pin();
+
+    let mut clock = SimClock::new();
+    let mut encoder = PinEncoder::new(wire_dcc.connect_push_pull_pin(), clock.get_timer())
+        .with_cutout(wire_cutout.connect_push_pull_pin());
+
+    let level = dcc_pin.is_high().unwrap();
+    // a bit written during the cutout is sent after the cutout
+    assert!(encoder.cutout().is_err());
+    let mut cutout = false;
+    while encoder.write(&Bit::One).is_err() {
+        cutout |= cutout_pin.is_high().unwrap();
+        clock.tick(1000.nanoseconds());
+    }
+    assert!(cutout);
+    assert!(cutout_pin.is_low().unwrap());
+    assert_eq!(dcc_pin.is_high().unwrap(), level);
+
+    // a cutout started during a bit follows the bit
+    assert!(encoder.write(&Bit::Zero).is_err());
+    let mut toggled = false;
+    while encoder.cutout().is_err() {
+        toggled |= dcc_pin.is_high().unwrap() != level;
+        clock.tick(1000.nanoseconds());
+    }
+    assert!(toggled);
+    assert_eq!(dcc_pin.is_high().unwrap(), level);
+    assert!(cutout_pin.is_low().unwrap());
+}
+
+#[test]
+fn long_address_bytes() {
+    // the upper address bits come first, after the long address marker
+    let msg = Message::FunctionGroup(Address { num: 1234 }, FunctionGroupNumber::G1, 0x10.into());
+    let mut buf = [0; 8];
+    assert_eq!(msg.to_buf(&mut buf), 4);
+    assert_eq!(buf[..4], [0xC4, 0xD2, 0x90, 0xC4 ^ 0xD2 ^ 0x90]);
+    assert_eq!(Message::from_bytes(&buf[..4]), msg);
+}
+
+#[test]
+fn speed_bytes() {
+    let drive = |speed| Message::Drive(Address { num: 3 }, Direction::Forward, speed);
+    for (speed, bytes) in [
+        (Speed::Steps14(240), vec![0x03, 0x6F]),
+        (Speed::Steps28(32), vec![0x03, 0x62]),
+        (Speed::Steps28(248), vec![0x03, 0x7F]),
+        (Speed::Steps128(20), vec![0x03, 0x3F, 0x8A]),
+        (Speed::Steps128(254), vec![0x03, 0x3F, 0xFF]),
+    ] {
+        let mut buf = [0; 8];
+        let len = drive(speed).to_buf(&mut buf);
+        let xor = bytes.iter().fold(0, |x, b| x ^ b);
+        assert_eq!(buf[..len - 1], bytes[..], "{:?}", speed);
+        assert_eq!(buf[len - 1], xor);
+    }
+    write_and_read_messages(vec![
+        drive(Speed::Steps28(32)),
+        drive(Speed::Steps28(248)),
+        drive(Speed::Steps128(254)),
+    ]);
+}
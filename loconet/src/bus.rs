@@ -0,0 +1,313 @@
+//! LocoNet bus access with carrier sense and collision detection
+//!
+//! All devices share a single line running at 16.66 kBaud. A device may
+//! only start sending after the line was idle for the carrier detect
+//! backoff plus its priority delay. Every sent byte is read back, a
+//! different byte means that another device sent at the same time. The
+//! message is aborted and sent again after the next backoff.
+
+use embedded_hal::serial::nb::{Read, Write};
+use embedded_hal::timer::nb::CountDown;
+use embedded_time::duration::*;
+use heapless::{Deque, Vec};
+use log::{debug, trace};
+
+use crate::{message_len, Error, Message};
+
+/// Maximum length of a message, longer messages are dropped
+pub const BUF_SIZE: usize = 32;
+/// Number of messages waiting to be sent
+const QUEUE_SIZE: usize = 8;
+
+/// Time of one bit in µs
+const BIT_TIME: u32 = 60;
+/// Time the line has to be idle before sending in bit times
+const CD_BACKOFF: u32 = 20;
+/// Time to wait for the echo of a sent byte in bit times
+const ECHO_TIMEOUT: u32 = 20;
+/// Attempts to send a message before it is dropped
+const MAX_ATTEMPTS: u8 = 25;
+
+#[derive(Debug, PartialEq)]
+enum State {
+    /// Line activity, the backoff has to be started
+    Busy,
+    /// Waiting for the backoff
+    Backoff,
+    Idle,
+    Sending,
+}
+
+/// Device on a LocoNet bus
+///
+/// The priority delay in bit times is added to the backoff. The command
+/// station uses priority 0, other devices should use a priority between
+/// 2 and 20. Sending a break after a collision is left to the UART, the
+/// message is repeated after the backoff.
+pub struct Bus<U, TIM> {
+    uart: U,
+    timer: TIM,
+    state: State,
+    priority: u8,
+    buf: [u8; BUF_SIZE],
+    len: usize,
+    queue: Deque<Vec<u8, BUF_SIZE>, QUEUE_SIZE>,
+    /// Written and read back bytes of the current message
+    sent: usize,
+    echoed: usize,
+    attempts: u8,
+}
+
+impl<U, TIM> Bus<U, TIM>
+where
+    U: Read<u8> + Write<u8>,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+{
+    pub fn new(uart: U, timer: TIM, priority: u8) -> Self {
+        Self {
+            uart,
+            timer,
+            state: State::Busy,
+            priority,
+            buf: [0; BUF_SIZE],
+            len: 0,
+            queue: Deque::new(),
+            sent: 0,
+            echoed: 0,
+            attempts: 0,
+        }
+    }
+
+    /// Queue a message to send when the line is idle
+    pub fn send(&mut self, msg: &Message) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        let data = Vec::from_slice(&buf[0..len]).map_err(|_| Error::QueueFull)?;
+        self.queue.push_back(data).map_err(|_| Error::QueueFull)
+    }
+
+    /// Send queued messages and return received messages
+    ///
+    /// Messages sent by this device are not returned.
+    pub fn run(&mut self) -> nb::Result<Message, Error> {
+        loop {
+            match self.uart.read() {
+                Ok(byte) => {
+                    if let Some(msg) = self.received(byte)? {
+                        return Ok(msg);
+                    }
+                    continue;
+                }
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(_)) => return Err(nb::Error::Other(Error::IOError)),
+            }
+            match self.state {
+                State::Busy => {
+                    self.start_timer(CD_BACKOFF + self.priority as u32)?;
+                    self.state = State::Backoff;
+                }
+                State::Backoff => {
+                    self.wait()?;
+                    self.state = State::Idle;
+                }
+                State::Idle => {
+                    if self.queue.is_empty() {
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    self.sent = 0;
+                    self.echoed = 0;
+                    self.state = State::Sending;
+                }
+                State::Sending => {
+                    let data = self.queue.front().ok_or(nb::Error::WouldBlock)?;
+                    if self.sent == self.echoed {
+                        let byte = data[self.sent];
+                        self.uart
+                            .write(byte)
+                            .map_err(|e| e.map(|_| Error::IOError))?;
+                        self.sent += 1;
+                        self.start_timer(ECHO_TIMEOUT)?;
+                    } else {
+                        self.wait()?;
+                        debug!("missing echo");
+                        self.collision()?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a byte read from the line
+    fn received(&mut self, byte: u8) -> Result<Option<Message>, Error> {
+        if self.state == State::Sending {
+            let data = self.queue.front();
+            let expected = data.and_then(|data| data.get(self.echoed));
+            if expected == Some(&byte) {
+                self.echoed += 1;
+                if data.map(|data| data.len()) == Some(self.echoed) {
+                    trace!("sent {:#04X?}", data);
+                    self.queue.pop_front();
+                    self.attempts = 0;
+                    self.state = State::Busy;
+                }
+                return Ok(None);
+            }
+            debug!("collision");
+            self.len = 0;
+            self.collision()?;
+            return Ok(None);
+        }
+        self.state = State::Busy;
+        if byte & 0x80 != 0 {
+            self.len = 0;
+        } else if self.len == 0 || self.len == BUF_SIZE {
+            return Ok(None);
+        }
+        self.buf[self.len] = byte;
+        self.len += 1;
+        if message_len(&self.buf[0..self.len]) != Some(self.len) {
+            return Ok(None);
+        }
+        let len = self.len;
+        self.len = 0;
+        let bytes = &self.buf[0..len];
+        match Message::from_bytes(bytes) {
+            Ok(msg) => Ok(Some(msg)),
+            Err(_) => {
+                debug!("invalid message {:#04X?}", bytes);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Abort the current message and drop it after too many attempts
+    fn collision(&mut self) -> Result<(), Error> {
+        self.state = State::Busy;
+        self.attempts += 1;
+        if self.attempts >= MAX_ATTEMPTS {
+            self.attempts = 0;
+            self.queue.pop_front();
+            return Err(Error::Collision);
+        }
+        Ok(())
+    }
+
+    fn start_timer(&mut self, bits: u32) -> Result<(), Error> {
+        self.timer
+            .start((bits * BIT_TIME).microseconds())
+            .map_err(|_| Error::TimerError)
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Error> {
+        self.timer.wait().map_err(|e| e.map(|_| Error::TimerError))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+    use crate::Switch;
+    use loco_core::drive::Speed;
+
+    fn bus() -> Bus<MockUart, MockTimer> {
+        Bus::new(MockUart::default(), MockTimer::new(), 0)
+    }
+
+    /// Run the bus until it is idle and nothing is left to send
+    fn run(bus: &mut Bus<MockUart, MockTimer>) -> std::vec::Vec<Result<Message, Error>> {
+        let mut received = std::vec::Vec::new();
+        for _ in 0..10000 {
+            match bus.run() {
+                Ok(msg) => received.push(Ok(msg)),
+                Err(nb::Error::WouldBlock) => {
+                    if bus.state == State::Idle && bus.queue.is_empty() {
+                        break;
+                    }
+                }
+                Err(nb::Error::Other(e)) => received.push(Err(e)),
+            }
+        }
+        received
+    }
+
+    #[test]
+    fn receive() {
+        let mut bus = bus();
+        bus.uart.rx.extend(&[
+            0x12, 0x83, 0x7C, 0xA0, 0x05, 0x20, 0x7B, 0xA0, 0x05, 0x20, 0x7A,
+        ]);
+        let received: std::vec::Vec<_> = run(&mut bus).into_iter().map(|r| r.unwrap()).collect();
+        assert_eq!(
+            received,
+            vec![Message::PowerOn, Message::LocoSpeed(5, Speed::Steps128(64))]
+        );
+        assert!(bus.uart.tx.is_empty());
+    }
+
+    #[test]
+    fn send_after_backoff() {
+        let mut bus = bus();
+        bus.send(&Message::PowerOn).unwrap();
+        assert!(bus.run().is_err());
+        assert!(bus.uart.tx.is_empty());
+        assert!(run(&mut bus).is_empty());
+        assert_eq!(bus.uart.tx, vec![0x83, 0x7C]);
+    }
+
+    #[test]
+    fn send_after_other_device() {
+        let mut bus = bus();
+        bus.send(&Message::PowerOff).unwrap();
+        for _ in 0..(CD_BACKOFF * BIT_TIME / 100) - 1 {
+            assert!(bus.run().is_err());
+        }
+        bus.uart.rx.extend(&[0x83, 0x7C]);
+        assert_eq!(bus.run().unwrap(), Message::PowerOn);
+        for _ in 0..(CD_BACKOFF * BIT_TIME / 100) - 1 {
+            assert!(bus.run().is_err());
+        }
+        assert!(bus.uart.tx.is_empty());
+        run(&mut bus);
+        assert_eq!(bus.uart.tx, vec![0x82, 0x7D]);
+    }
+
+    #[test]
+    fn receive_after_echo() {
+        let mut bus = bus();
+        bus.send(&Message::PowerOn).unwrap();
+        bus.state = State::Sending;
+        assert!(matches!(bus.received(0x83), Ok(None)));
+        assert!(matches!(bus.received(0x7C), Ok(None)));
+        assert!(bus.queue.is_empty());
+        assert!(matches!(bus.received(0x82), Ok(None)));
+        assert!(matches!(bus.received(0x7D), Ok(Some(Message::PowerOff))));
+    }
+
+    #[test]
+    fn collision() {
+        let mut bus = bus();
+        bus.uart.collisions = 1;
+        bus.send(&Message::SwitchRequest(Switch {
+            address: 10,
+            thrown: true,
+            activate: true,
+        }))
+        .unwrap();
+        assert!(run(&mut bus).is_empty());
+        assert_eq!(bus.uart.tx, vec![0xB0, 0xB0, 0x0A, 0x10, 0x55]);
+    }
+
+    #[test]
+    fn repeated_collisions() {
+        let mut bus = bus();
+        bus.uart.collisions = MAX_ATTEMPTS as usize;
+        bus.send(&Message::PowerOn).unwrap();
+        bus.send(&Message::PowerOff).unwrap();
+        let received = run(&mut bus);
+        assert!(matches!(received[..], [Err(Error::Collision)]));
+        assert_eq!(bus.uart.tx.len(), MAX_ATTEMPTS as usize + 2);
+        assert_eq!(bus.uart.tx[MAX_ATTEMPTS as usize..], [0x82, 0x7D]);
+    }
+}
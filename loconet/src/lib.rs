@@ -0,0 +1,663 @@
+#![cfg_attr(not(test), no_std)]
+
+use bitflags::bitflags;
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+};
+use loco_dcc::{function::FunctionGroupByte, speed::DccSpeed};
+
+pub mod bus;
+pub mod slot;
+
+#[cfg(test)]
+mod tests_mock;
+
+pub const OPC_BUSY: u8 = 0x81;
+pub const OPC_GPOFF: u8 = 0x82;
+pub const OPC_GPON: u8 = 0x83;
+pub const OPC_IDLE: u8 = 0x85;
+pub const OPC_LOCO_SPD: u8 = 0xA0;
+pub const OPC_LOCO_DIRF: u8 = 0xA1;
+pub const OPC_LOCO_SND: u8 = 0xA2;
+pub const OPC_SW_REQ: u8 = 0xB0;
+pub const OPC_INPUT_REP: u8 = 0xB2;
+pub const OPC_LONG_ACK: u8 = 0xB4;
+pub const OPC_SLOT_STAT1: u8 = 0xB5;
+pub const OPC_MOVE_SLOTS: u8 = 0xBA;
+pub const OPC_RQ_SL_DATA: u8 = 0xBB;
+pub const OPC_LOCO_ADR: u8 = 0xBF;
+pub const OPC_SL_RD_DATA: u8 = 0xE7;
+pub const OPC_WR_SL_DATA: u8 = 0xEF;
+
+/// Slot of the programming track
+pub const PROGRAMMING_SLOT: u8 = 0x7C;
+
+bitflags! {
+    /// Global track status of all slots
+    pub struct TrackStatus: u8 {
+        const POWER_ON = 0b0000_0001;
+        /// Cleared while all locos are stopped (`OPC_IDLE`)
+        const RUNNING = 0b0000_0010;
+        const LOCONET_1_1 = 0b0000_0100;
+        const PROGRAMMING_BUSY = 0b0000_1000;
+    }
+}
+
+bitflags! {
+    /// Programming task status of the programming slot
+    pub struct ProgrammingStatus: u8 {
+        const NO_DECODER = 0b0000_0001;
+        const WRITE_FAILED = 0b0000_0010;
+        const READ_FAILED = 0b0000_0100;
+        const ABORTED = 0b0000_1000;
+    }
+}
+
+/// Usage of a slot in the status byte
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlotState {
+    Free = 0x00,
+    /// Refreshed, but not used by a throttle
+    Common = 0x10,
+    /// Not refreshed
+    Idle = 0x20,
+    InUse = 0x30,
+}
+
+impl From<u8> for SlotState {
+    #[inline]
+    fn from(status: u8) -> Self {
+        match status & 0x30 {
+            0x00 => Self::Free,
+            0x10 => Self::Common,
+            0x20 => Self::Idle,
+            _ => Self::InUse,
+        }
+    }
+}
+
+/// Decoder types of the slot status byte
+pub const DECODER_28_STEPS: u8 = 0x00;
+pub const DECODER_14_STEPS: u8 = 0x02;
+pub const DECODER_128_STEPS: u8 = 0x03;
+
+/// Content of a loco slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct SlotData {
+    pub slot: u8,
+    /// Slot state and decoder type
+    pub status: u8,
+    pub address: Address,
+    pub speed: Speed,
+    pub direction: Direction,
+    /// F0 to F4
+    pub functions: FunctionGroupByte,
+    pub track: TrackStatus,
+    pub ss2: u8,
+    /// F5 to F8
+    pub sound: FunctionGroupByte,
+    /// Device ID of the throttle using the slot
+    pub id: u16,
+}
+
+impl SlotData {
+    /// Empty slot with 128 speed steps
+    pub fn new(slot: u8) -> Self {
+        Self {
+            slot,
+            status: DECODER_128_STEPS,
+            address: Address::new(0),
+            speed: Speed::Stop,
+            direction: Direction::Forward,
+            functions: FunctionGroupByte::from(0),
+            track: TrackStatus::empty(),
+            ss2: 0,
+            sound: FunctionGroupByte::from(0),
+            id: 0,
+        }
+    }
+
+    pub fn state(&self) -> SlotState {
+        SlotState::from(self.status)
+    }
+
+    pub fn set_state(&mut self, state: SlotState) {
+        self.status = self.status & !0x30 | state as u8;
+    }
+
+    fn to_buf(&self, buf: &mut [u8]) {
+        let [adr2, adr] = split7(self.address.num);
+        let [id2, id1] = split7(self.id);
+        buf[0] = self.slot;
+        buf[1] = self.status;
+        buf[2] = adr;
+        buf[3] = speed_byte(&self.speed);
+        buf[4] = dirf(self.direction, self.functions);
+        buf[5] = self.track.bits();
+        buf[6] = self.ss2;
+        buf[7] = adr2;
+        buf[8] = u8::from(self.sound) & 0x0F;
+        buf[9] = id1;
+        buf[10] = id2;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        let (direction, functions) = parse_dirf(bytes[4]);
+        Self {
+            slot: bytes[0],
+            status: bytes[1],
+            address: Address::new(join7(bytes[7], bytes[2])),
+            speed: Speed::from_byte_128_steps(bytes[3]),
+            direction,
+            functions,
+            track: TrackStatus::from_bits_truncate(bytes[5]),
+            ss2: bytes[6],
+            sound: FunctionGroupByte::from(bytes[8] & 0x0F),
+            id: join7(bytes[10], bytes[9]),
+        }
+    }
+}
+
+/// Programming mode of a programmer task
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProgrammingMode {
+    Paged = 0x20,
+    DirectByte = 0x28,
+    DirectBit = 0x08,
+    Register = 0x30,
+    /// Programming on main without feedback
+    OpsByte = 0x24,
+    OpsByteFeedback = 0x2C,
+    OpsBit = 0x04,
+    OpsBitFeedback = 0x0C,
+}
+
+impl ProgrammingMode {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        use ProgrammingMode::*;
+        match byte & 0x3C {
+            0x20 => Some(Paged),
+            0x28 => Some(DirectByte),
+            0x08 => Some(DirectBit),
+            0x30 => Some(Register),
+            0x24 => Some(OpsByte),
+            0x2C => Some(OpsByteFeedback),
+            0x04 => Some(OpsBit),
+            0x0C => Some(OpsBitFeedback),
+            _ => None,
+        }
+    }
+}
+
+/// Content of the programming slot
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProgrammerTask {
+    pub mode: ProgrammingMode,
+    pub write: bool,
+    pub status: ProgrammingStatus,
+    /// Loco to program on main
+    pub loco_address: Address,
+    pub track: TrackStatus,
+    /// CV from 1 to 1024
+    pub cv: u16,
+    pub value: u8,
+}
+
+impl ProgrammerTask {
+    fn to_buf(&self, buf: &mut [u8]) {
+        let [hopsa, lopsa] = split7(self.loco_address.num);
+        let cv = self.cv.wrapping_sub(1);
+        buf[0] = PROGRAMMING_SLOT;
+        buf[1] = self.mode as u8 | (self.write as u8) << 6;
+        buf[2] = self.status.bits();
+        buf[3] = hopsa;
+        buf[4] = lopsa;
+        buf[5] = self.track.bits();
+        buf[6] = (cv >> 7 & 0x01) as u8 | (cv >> 4 & 0x30) as u8 | (self.value >> 6 & 0x02);
+        buf[7] = cv as u8 & 0x7F;
+        buf[8] = self.value & 0x7F;
+        buf[9] = 0;
+        buf[10] = 0;
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let cvh = bytes[6] as u16;
+        let cv = (cvh & 0x01) << 7 | (cvh & 0x30) << 4 | bytes[7] as u16;
+        Ok(Self {
+            mode: ProgrammingMode::from_byte(bytes[1]).ok_or(Error::ParseError)?,
+            write: bytes[1] & 0x40 != 0,
+            status: ProgrammingStatus::from_bits_truncate(bytes[2]),
+            loco_address: Address::new(join7(bytes[3], bytes[4])),
+            track: TrackStatus::from_bits_truncate(bytes[5]),
+            cv: cv + 1,
+            value: bytes[8] & 0x7F | (bytes[6] & 0x02) << 6,
+        })
+    }
+}
+
+/// Switch command, addresses start at 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct Switch {
+    pub address: u16,
+    /// Switch the thrown (red) output instead of the closed (green) output
+    pub thrown: bool,
+    pub activate: bool,
+}
+
+/// Sensor report, addresses start at 0
+#[derive(Debug, Clone, PartialEq)]
+pub struct Input {
+    pub address: u16,
+    pub occupied: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    Busy,
+    PowerOff,
+    PowerOn,
+    /// Stop all locos
+    EmergencyStop,
+    LocoSpeed(u8, Speed),
+    /// Direction and F0 to F4 of a slot
+    LocoDirf(u8, Direction, FunctionGroupByte),
+    /// F5 to F8 of a slot
+    LocoSound(u8, FunctionGroupByte),
+    SwitchRequest(Switch),
+    InputReport(Input),
+    /// Answer to the request with the given opcode
+    LongAck(u8, u8),
+    SlotStatus(u8, u8),
+    /// Move a slot, moving a slot to itself marks it as in use
+    MoveSlots(u8, u8),
+    RequestSlotData(u8),
+    /// Request the slot of a loco
+    LocoAddress(Address),
+    ReadSlotData(SlotData),
+    WriteSlotData(SlotData),
+    /// Programming slot data with the result of a programmer task
+    ReadProgrammer(ProgrammerTask),
+    /// Start a programmer task
+    WriteProgrammer(ProgrammerTask),
+}
+
+#[derive(Debug)]
+pub enum Error {
+    ParseError,
+    IOError,
+    TimerError,
+    QueueFull,
+    /// Sending failed because of repeated collisions
+    Collision,
+    /// No free slot for a loco
+    SlotsFull,
+    /// Slot does not exist or cannot be moved
+    InvalidSlot,
+}
+
+/// Length of a message from its first two bytes
+///
+/// The length is encoded in the opcode or in the second byte for
+/// variable length messages.
+pub fn message_len(bytes: &[u8]) -> Option<usize> {
+    match bytes.first()? & 0x60 {
+        0x00 => Some(2),
+        0x20 => Some(4),
+        0x40 => Some(6),
+        _ => bytes.get(1).map(|len| *len as usize),
+    }
+}
+
+/// Checksum byte making the XOR of all bytes 0xFF
+#[inline]
+pub fn checksum(bytes: &[u8]) -> u8 {
+    !bytes.iter().fold(0, |acc, x| acc ^ x)
+}
+
+#[inline]
+fn add_checksum(buf: &mut [u8], len: usize) -> usize {
+    buf[len - 1] = checksum(&buf[0..len - 1]);
+    len
+}
+
+/// Split a 14 bit value into two 7 bit bytes
+#[inline]
+fn split7(value: u16) -> [u8; 2] {
+    [(value >> 7) as u8 & 0x7F, value as u8 & 0x7F]
+}
+
+#[inline]
+fn join7(h: u8, l: u8) -> u16 {
+    (h as u16 & 0x7F) << 7 | l as u16 & 0x7F
+}
+
+/// Speed byte with 128 speed steps, other speed steps are scaled
+#[inline]
+fn speed_byte(speed: &Speed) -> u8 {
+    match speed {
+        Speed::Steps14(s) | Speed::Steps28(s) => (s / 2) & 0x7F,
+        speed => speed.to_byte(),
+    }
+}
+
+#[inline]
+fn dirf(direction: Direction, functions: FunctionGroupByte) -> u8 {
+    let dir = match direction {
+        Direction::Forward => 0x00,
+        Direction::Backward => 0x20,
+    };
+    dir | u8::from(functions) & 0x1F
+}
+
+#[inline]
+fn parse_dirf(byte: u8) -> (Direction, FunctionGroupByte) {
+    let direction = if byte & 0x20 != 0 {
+        Direction::Backward
+    } else {
+        Direction::Forward
+    };
+    (direction, FunctionGroupByte::from(byte & 0x1F))
+}
+
+impl Message {
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use Message::*;
+        let len = match self {
+            Busy => {
+                buf[0] = OPC_BUSY;
+                2
+            }
+            PowerOff => {
+                buf[0] = OPC_GPOFF;
+                2
+            }
+            PowerOn => {
+                buf[0] = OPC_GPON;
+                2
+            }
+            EmergencyStop => {
+                buf[0] = OPC_IDLE;
+                2
+            }
+            LocoSpeed(slot, speed) => {
+                buf[0..3].copy_from_slice(&[OPC_LOCO_SPD, *slot, speed_byte(speed)]);
+                4
+            }
+            LocoDirf(slot, direction, functions) => {
+                buf[0..3].copy_from_slice(&[OPC_LOCO_DIRF, *slot, dirf(*direction, *functions)]);
+                4
+            }
+            LocoSound(slot, functions) => {
+                buf[0..3].copy_from_slice(&[OPC_LOCO_SND, *slot, u8::from(*functions) & 0x0F]);
+                4
+            }
+            SwitchRequest(switch) => {
+                let sw2 = (switch.address >> 7) as u8 & 0x0F
+                    | (!switch.thrown as u8) << 5
+                    | (switch.activate as u8) << 4;
+                buf[0..3].copy_from_slice(&[OPC_SW_REQ, switch.address as u8 & 0x7F, sw2]);
+                4
+            }
+            InputReport(input) => {
+                let in2 = 0x40
+                    | (input.address as u8 & 0x01) << 5
+                    | (input.occupied as u8) << 4
+                    | (input.address >> 8) as u8 & 0x0F;
+                buf[0..3].copy_from_slice(&[OPC_INPUT_REP, (input.address >> 1) as u8 & 0x7F, in2]);
+                4
+            }
+            LongAck(opcode, ack) => {
+                buf[0..3].copy_from_slice(&[OPC_LONG_ACK, opcode & 0x7F, *ack]);
+                4
+            }
+            SlotStatus(slot, status) => {
+                buf[0..3].copy_from_slice(&[OPC_SLOT_STAT1, *slot, *status]);
+                4
+            }
+            MoveSlots(src, dst) => {
+                buf[0..3].copy_from_slice(&[OPC_MOVE_SLOTS, *src, *dst]);
+                4
+            }
+            RequestSlotData(slot) => {
+                buf[0..3].copy_from_slice(&[OPC_RQ_SL_DATA, *slot, 0]);
+                4
+            }
+            LocoAddress(addr) => {
+                let [h, l] = split7(addr.num);
+                buf[0..3].copy_from_slice(&[OPC_LOCO_ADR, h, l]);
+                4
+            }
+            ReadSlotData(data) | WriteSlotData(data) => {
+                buf[0] = if let ReadSlotData(_) = self {
+                    OPC_SL_RD_DATA
+                } else {
+                    OPC_WR_SL_DATA
+                };
+                buf[1] = 14;
+                data.to_buf(&mut buf[2..13]);
+                14
+            }
+            ReadProgrammer(task) | WriteProgrammer(task) => {
+                buf[0] = if let ReadProgrammer(_) = self {
+                    OPC_SL_RD_DATA
+                } else {
+                    OPC_WR_SL_DATA
+                };
+                buf[1] = 14;
+                task.to_buf(&mut buf[2..13]);
+                14
+            }
+        };
+        add_checksum(buf, len)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use Message::*;
+        let len = message_len(bytes).ok_or(Error::ParseError)?;
+        if bytes.len() < len || checksum(&bytes[0..len]) != 0x00 {
+            return Err(Error::ParseError);
+        }
+        let msg = match bytes {
+            [OPC_BUSY, ..] => Busy,
+            [OPC_GPOFF, ..] => PowerOff,
+            [OPC_GPON, ..] => PowerOn,
+            [OPC_IDLE, ..] => EmergencyStop,
+            [OPC_LOCO_SPD, slot, spd, ..] => LocoSpeed(*slot, Speed::from_byte_128_steps(*spd)),
+            [OPC_LOCO_DIRF, slot, byte, ..] => {
+                let (direction, functions) = parse_dirf(*byte);
+                LocoDirf(*slot, direction, functions)
+            }
+            [OPC_LOCO_SND, slot, snd, ..] => LocoSound(*slot, FunctionGroupByte::from(snd & 0x0F)),
+            [OPC_SW_REQ, sw1, sw2, ..] => SwitchRequest(Switch {
+                address: (*sw2 as u16 & 0x0F) << 7 | *sw1 as u16,
+                thrown: sw2 & 0x20 == 0,
+                activate: sw2 & 0x10 != 0,
+            }),
+            [OPC_INPUT_REP, in1, in2, ..] => InputReport(Input {
+                address: (*in2 as u16 & 0x0F) << 8 | (*in1 as u16) << 1 | (*in2 as u16 & 0x20) >> 5,
+                occupied: in2 & 0x10 != 0,
+            }),
+            [OPC_LONG_ACK, opcode, ack, ..] => LongAck(opcode | 0x80, *ack),
+            [OPC_SLOT_STAT1, slot, status, ..] => SlotStatus(*slot, *status),
+            [OPC_MOVE_SLOTS, src, dst, ..] => MoveSlots(*src, *dst),
+            [OPC_RQ_SL_DATA, slot, ..] => RequestSlotData(*slot),
+            [OPC_LOCO_ADR, h, l, ..] => LocoAddress(Address::new(join7(*h, *l))),
+            [OPC_SL_RD_DATA, 14, PROGRAMMING_SLOT, ..] => {
+                ReadProgrammer(ProgrammerTask::from_bytes(&bytes[2..13])?)
+            }
+            [OPC_WR_SL_DATA, 14, PROGRAMMING_SLOT, ..] => {
+                WriteProgrammer(ProgrammerTask::from_bytes(&bytes[2..13])?)
+            }
+            [OPC_SL_RD_DATA, 14, ..] => ReadSlotData(SlotData::from_bytes(&bytes[2..13])),
+            [OPC_WR_SL_DATA, 14, ..] => WriteSlotData(SlotData::from_bytes(&bytes[2..13])),
+            _ => return Err(Error::ParseError),
+        };
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Append the checksum byte to a message
+    fn with_checksum(data: &[u8]) -> Vec<u8> {
+        let mut bytes = data.to_vec();
+        bytes.push(checksum(data));
+        bytes
+    }
+
+    fn assert_round_trip(msg: Message, bytes: &[u8]) {
+        let mut buf = [0; 16];
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], bytes, "{:?}", msg);
+        assert_eq!(Message::from_bytes(bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn messages() {
+        use Message::*;
+        let f0_f2 = FunctionGroupByte::from(0x12);
+        let messages = [
+            (Busy, vec![0x81, 0x7E]),
+            (PowerOff, vec![0x82, 0x7D]),
+            (PowerOn, vec![0x83, 0x7C]),
+            (EmergencyStop, vec![0x85, 0x7A]),
+            (
+                LocoSpeed(3, Speed::EmergencyStop),
+                with_checksum(&[0xA0, 0x03, 0x01]),
+            ),
+            (
+                LocoSpeed(3, Speed::Steps128(254)),
+                with_checksum(&[0xA0, 0x03, 0x7F]),
+            ),
+            (
+                LocoDirf(3, Direction::Forward, f0_f2),
+                with_checksum(&[0xA1, 0x03, 0x12]),
+            ),
+            (
+                LocoDirf(3, Direction::Backward, f0_f2),
+                with_checksum(&[0xA1, 0x03, 0x32]),
+            ),
+            (
+                LocoSound(3, FunctionGroupByte::from(0x05)),
+                with_checksum(&[0xA2, 0x03, 0x05]),
+            ),
+            (
+                SwitchRequest(Switch {
+                    address: 1000,
+                    thrown: false,
+                    activate: true,
+                }),
+                with_checksum(&[0xB0, 0x68, 0x37]),
+            ),
+            (
+                InputReport(Input {
+                    address: 1023,
+                    occupied: true,
+                }),
+                with_checksum(&[0xB2, 0x7F, 0x73]),
+            ),
+            (
+                InputReport(Input {
+                    address: 4,
+                    occupied: false,
+                }),
+                with_checksum(&[0xB2, 0x02, 0x40]),
+            ),
+            (LongAck(OPC_LOCO_ADR, 0), with_checksum(&[0xB4, 0x3F, 0x00])),
+            (SlotStatus(3, 0x13), with_checksum(&[0xB5, 0x03, 0x13])),
+            (MoveSlots(3, 3), with_checksum(&[0xBA, 0x03, 0x03])),
+            (RequestSlotData(3), with_checksum(&[0xBB, 0x03, 0x00])),
+            (
+                LocoAddress(Address::new(1234)),
+                with_checksum(&[0xBF, 0x09, 0x52]),
+            ),
+        ];
+        for (msg, bytes) in messages {
+            assert_round_trip(msg, &bytes);
+        }
+    }
+
+    #[test]
+    fn slot_data() {
+        let data = SlotData {
+            slot: 3,
+            status: 0x33,
+            address: Address::new(1234),
+            speed: Speed::Steps128(64),
+            direction: Direction::Backward,
+            functions: FunctionGroupByte::from(0x10),
+            track: TrackStatus::POWER_ON | TrackStatus::RUNNING | TrackStatus::LOCONET_1_1,
+            ss2: 0,
+            sound: FunctionGroupByte::from(0x01),
+            id: 0x1234,
+        };
+        assert_eq!(data.state(), SlotState::InUse);
+        let bytes = [
+            0x03, 0x33, 0x52, 0x20, 0x30, 0x07, 0x00, 0x09, 0x01, 0x34, 0x24,
+        ];
+        let mut read = vec![OPC_SL_RD_DATA, 0x0E];
+        read.extend(&bytes);
+        assert_round_trip(Message::ReadSlotData(data.clone()), &with_checksum(&read));
+        let mut write = vec![OPC_WR_SL_DATA, 0x0E];
+        write.extend(&bytes);
+        assert_round_trip(Message::WriteSlotData(data), &with_checksum(&write));
+    }
+
+    #[test]
+    fn programmer() {
+        let task = ProgrammerTask {
+            mode: ProgrammingMode::DirectByte,
+            write: true,
+            status: ProgrammingStatus::empty(),
+            loco_address: Address::new(0),
+            track: TrackStatus::empty(),
+            cv: 29,
+            value: 6,
+        };
+        let bytes = with_checksum(&[
+            0xEF, 0x0E, 0x7C, 0x68, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1C, 0x06, 0x00, 0x00,
+        ]);
+        assert_round_trip(Message::WriteProgrammer(task), &bytes);
+        let task = ProgrammerTask {
+            mode: ProgrammingMode::OpsByteFeedback,
+            write: false,
+            status: ProgrammingStatus::READ_FAILED,
+            loco_address: Address::new(1234),
+            track: TrackStatus::POWER_ON,
+            cv: 1024,
+            value: 0xFF,
+        };
+        let bytes = with_checksum(&[
+            0xE7, 0x0E, 0x7C, 0x2C, 0x04, 0x09, 0x52, 0x01, 0x33, 0x7F, 0x7F, 0x00, 0x00,
+        ]);
+        assert_round_trip(Message::ReadProgrammer(task), &bytes);
+    }
+
+    #[test]
+    fn lengths() {
+        assert_eq!(message_len(&[OPC_GPON]), Some(2));
+        assert_eq!(message_len(&[OPC_LOCO_SPD]), Some(4));
+        assert_eq!(message_len(&[0xD0]), Some(6));
+        assert_eq!(message_len(&[OPC_SL_RD_DATA]), None);
+        assert_eq!(message_len(&[OPC_SL_RD_DATA, 0x0E]), Some(14));
+        assert_eq!(message_len(&[]), None);
+    }
+
+    #[test]
+    fn parse_errors() {
+        // wrong checksum
+        assert!(Message::from_bytes(&[0x83, 0x7D]).is_err());
+        // truncated
+        assert!(Message::from_bytes(&[0xA0, 0x03, 0x01]).is_err());
+        // unknown opcode
+        assert!(Message::from_bytes(&with_checksum(&[0xA3, 0x03, 0x01])).is_err());
+        // invalid programming mode
+        let bytes = with_checksum(&[
+            0xEF, 0x0E, 0x7C, 0x3C, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1C, 0x06, 0x00, 0x00,
+        ]);
+        assert!(Message::from_bytes(&bytes).is_err());
+    }
+}
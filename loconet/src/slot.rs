@@ -0,0 +1,292 @@
+//! Slot table of a LocoNet command station
+//!
+//! Throttles request a slot for a loco address and control the loco
+//! with the slot number afterwards. Slot 0 is used for dispatching, slots
+//! above `MAX_SLOT` are reserved for special functions like programming.
+
+use loco_core::{address::Address, drive::Speed};
+use log::debug;
+
+use crate::{
+    Error, Message, SlotData, SlotState, TrackStatus, OPC_LOCO_ADR, OPC_MOVE_SLOTS, OPC_WR_SL_DATA,
+};
+
+/// Highest slot usable for locos
+pub const MAX_SLOT: u8 = 119;
+/// Number of loco slots
+pub const SLOTS: usize = MAX_SLOT as usize;
+
+/// Long acknowledgement of a rejected request
+pub const ACK_FAILED: u8 = 0x00;
+/// Long acknowledgement of an accepted slot write
+pub const ACK_OK: u8 = 0x7F;
+
+/// Loco slots of a command station
+pub struct SlotTable {
+    slots: [SlotData; SLOTS],
+    track: TrackStatus,
+    /// Slot put into slot 0 for another throttle
+    dispatched: Option<u8>,
+}
+
+impl Default for SlotTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SlotTable {
+    pub fn new() -> Self {
+        let mut slots = [(); SLOTS].map(|_| SlotData::new(0));
+        for (i, slot) in slots.iter_mut().enumerate() {
+            slot.slot = i as u8 + 1;
+        }
+        Self {
+            slots,
+            track: TrackStatus::POWER_ON | TrackStatus::RUNNING,
+            dispatched: None,
+        }
+    }
+
+    pub fn track(&self) -> TrackStatus {
+        self.track
+    }
+
+    pub fn set_track(&mut self, track: TrackStatus) {
+        self.track = track;
+    }
+
+    pub fn get(&self, slot: u8) -> Option<&SlotData> {
+        self.slots.get((slot as usize).checked_sub(1)?)
+    }
+
+    pub fn get_mut(&mut self, slot: u8) -> Option<&mut SlotData> {
+        self.slots.get_mut((slot as usize).checked_sub(1)?)
+    }
+
+    /// Find the slot of a loco
+    pub fn find(&self, addr: Address) -> Option<&SlotData> {
+        self.slots
+            .iter()
+            .find(|slot| slot.state() != SlotState::Free && slot.address == addr)
+    }
+
+    /// Get the slot of a loco, allocating a free slot if needed
+    pub fn request(&mut self, addr: Address) -> Result<u8, Error> {
+        if let Some(slot) = self.find(addr) {
+            return Ok(slot.slot);
+        }
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| slot.state() == SlotState::Free)
+            .ok_or(Error::SlotsFull)?;
+        let num = slot.slot;
+        *slot = SlotData::new(num);
+        slot.address = addr;
+        slot.set_state(SlotState::Common);
+        Ok(num)
+    }
+
+    /// Move a slot and return the resulting slot
+    ///
+    /// Moving a slot to itself marks it as in use, moving a slot to slot 0
+    /// dispatches it and moving slot 0 gets the dispatched slot.
+    pub fn move_slots(&mut self, src: u8, dst: u8) -> Result<u8, Error> {
+        if src == 0 {
+            let num = self.dispatched.take().ok_or(Error::InvalidSlot)?;
+            self.set_state(num, SlotState::InUse)?;
+            return Ok(num);
+        }
+        if self.get(src).map(|slot| slot.state()) == Some(SlotState::Free) {
+            return Err(Error::InvalidSlot);
+        }
+        if src == dst {
+            self.set_state(src, SlotState::InUse)?;
+            return Ok(src);
+        }
+        if dst == 0 {
+            self.set_state(src, SlotState::Common)?;
+            self.dispatched = Some(src);
+            return Ok(src);
+        }
+        match self.get(dst).map(|slot| slot.state()) {
+            Some(SlotState::Free) => {}
+            _ => return Err(Error::InvalidSlot),
+        }
+        let mut data = self.get(src).ok_or(Error::InvalidSlot)?.clone();
+        data.slot = dst;
+        data.set_state(SlotState::InUse);
+        *self.get_mut(dst).ok_or(Error::InvalidSlot)? = data;
+        self.set_state(src, SlotState::Free)?;
+        Ok(dst)
+    }
+
+    fn set_state(&mut self, slot: u8, state: SlotState) -> Result<(), Error> {
+        self.get_mut(slot)
+            .ok_or(Error::InvalidSlot)?
+            .set_state(state);
+        Ok(())
+    }
+
+    /// Slot data message including the global track status
+    pub fn slot_data(&self, slot: u8) -> Option<Message> {
+        let mut data = self.get(slot)?.clone();
+        data.track = self.track;
+        Some(Message::ReadSlotData(data))
+    }
+
+    /// Update the slot table with a message and get the reply
+    ///
+    /// Messages not concerning loco slots are ignored.
+    pub fn handle(&mut self, msg: &Message) -> Option<Message> {
+        use Message::*;
+        match msg {
+            PowerOn => {
+                self.track
+                    .insert(TrackStatus::POWER_ON | TrackStatus::RUNNING);
+                None
+            }
+            PowerOff => {
+                self.track.remove(TrackStatus::POWER_ON);
+                None
+            }
+            EmergencyStop => {
+                self.track.remove(TrackStatus::RUNNING);
+                for slot in self.slots.iter_mut() {
+                    slot.speed = Speed::EmergencyStop;
+                }
+                None
+            }
+            LocoAddress(addr) => match self.request(*addr) {
+                Ok(slot) => self.slot_data(slot),
+                Err(_) => Some(LongAck(OPC_LOCO_ADR, ACK_FAILED)),
+            },
+            RequestSlotData(slot) => self.slot_data(*slot),
+            MoveSlots(src, dst) => match self.move_slots(*src, *dst) {
+                Ok(slot) => self.slot_data(slot),
+                Err(_) => Some(LongAck(OPC_MOVE_SLOTS, ACK_FAILED)),
+            },
+            LocoSpeed(slot, speed) => {
+                self.get_mut(*slot)?.speed = *speed;
+                None
+            }
+            LocoDirf(slot, direction, functions) => {
+                let data = self.get_mut(*slot)?;
+                data.direction = *direction;
+                data.functions = *functions;
+                None
+            }
+            LocoSound(slot, functions) => {
+                self.get_mut(*slot)?.sound = *functions;
+                None
+            }
+            SlotStatus(slot, status) => {
+                self.get_mut(*slot)?.status = *status;
+                None
+            }
+            WriteSlotData(data) => {
+                *self.get_mut(data.slot)? = data.clone();
+                Some(LongAck(OPC_WR_SL_DATA, ACK_OK))
+            }
+            msg => {
+                debug!("ignored message {:?}", msg);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Message::LongAck, DECODER_128_STEPS};
+    use loco_core::drive::Direction;
+    use loco_dcc::function::FunctionGroupByte;
+
+    fn slot_of(reply: Option<Message>) -> SlotData {
+        match reply {
+            Some(Message::ReadSlotData(data)) => data,
+            reply => panic!("unexpected reply {:?}", reply),
+        }
+    }
+
+    #[test]
+    fn request_and_drive() {
+        let mut table = SlotTable::new();
+        let data = slot_of(table.handle(&Message::LocoAddress(Address::new(3))));
+        assert_eq!(data.slot, 1);
+        assert_eq!(data.state(), SlotState::Common);
+        assert_eq!(data.track, TrackStatus::POWER_ON | TrackStatus::RUNNING);
+        let data = slot_of(table.handle(&Message::LocoAddress(Address::new(4))));
+        assert_eq!(data.slot, 2);
+        let data = slot_of(table.handle(&Message::LocoAddress(Address::new(3))));
+        assert_eq!(data.slot, 1);
+
+        let data = slot_of(table.handle(&Message::MoveSlots(1, 1)));
+        assert_eq!(data.state(), SlotState::InUse);
+        let functions = FunctionGroupByte::from(0x10);
+        assert_eq!(
+            table.handle(&Message::LocoSpeed(1, Speed::Steps128(64))),
+            None
+        );
+        table.handle(&Message::LocoDirf(1, Direction::Backward, functions));
+        let data = slot_of(table.handle(&Message::RequestSlotData(1)));
+        assert_eq!(data.speed, Speed::Steps128(64));
+        assert_eq!(data.direction, Direction::Backward);
+        assert_eq!(data.functions, functions);
+
+        table.handle(&Message::EmergencyStop);
+        let data = slot_of(table.handle(&Message::RequestSlotData(1)));
+        assert_eq!(data.speed, Speed::EmergencyStop);
+        assert_eq!(data.track, TrackStatus::POWER_ON);
+    }
+
+    #[test]
+    fn slots_full() {
+        let mut table = SlotTable::new();
+        for addr in 0..SLOTS as u16 {
+            table.request(Address::new(addr + 1)).unwrap();
+        }
+        assert_eq!(
+            table.handle(&Message::LocoAddress(Address::new(1000))),
+            Some(LongAck(OPC_LOCO_ADR, ACK_FAILED))
+        );
+        table.handle(&Message::SlotStatus(5, DECODER_128_STEPS));
+        let data = slot_of(table.handle(&Message::LocoAddress(Address::new(1000))));
+        assert_eq!(data.slot, 5);
+    }
+
+    #[test]
+    fn move_and_dispatch() {
+        let mut table = SlotTable::new();
+        let slot = table.request(Address::new(3)).unwrap();
+        assert_eq!(table.move_slots(slot, 10).unwrap(), 10);
+        assert_eq!(table.get(slot).unwrap().state(), SlotState::Free);
+        assert_eq!(table.find(Address::new(3)).unwrap().slot, 10);
+        assert_eq!(
+            table.handle(&Message::MoveSlots(slot, 11)),
+            Some(LongAck(OPC_MOVE_SLOTS, ACK_FAILED))
+        );
+
+        assert_eq!(table.move_slots(10, 0).unwrap(), 10);
+        assert_eq!(table.get(10).unwrap().state(), SlotState::Common);
+        let data = slot_of(table.handle(&Message::MoveSlots(0, 0)));
+        assert_eq!(data.slot, 10);
+        assert_eq!(data.state(), SlotState::InUse);
+        assert!(table.move_slots(0, 0).is_err());
+    }
+
+    #[test]
+    fn write_slot() {
+        let mut table = SlotTable::new();
+        let mut data = table.get(7).unwrap().clone();
+        data.address = Address::new(1234);
+        data.set_state(SlotState::InUse);
+        assert_eq!(
+            table.handle(&Message::WriteSlotData(data)),
+            Some(LongAck(OPC_WR_SL_DATA, ACK_OK))
+        );
+        assert_eq!(table.find(Address::new(1234)).unwrap().slot, 7);
+    }
+}
@@ -0,0 +1,42 @@
+pub use embedded_hal::serial::nb::{Read, Write};
+pub use loco_mock::MockTimer;
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+/// UART on a line reading back all written bytes
+///
+/// The given number of written bytes are read back changed to simulate
+/// collisions.
+#[derive(Default)]
+pub struct MockUart {
+    pub rx: VecDeque<u8>,
+    pub tx: Vec<u8>,
+    pub collisions: usize,
+}
+
+impl Read<u8> for MockUart {
+    type Error = ();
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write<u8> for MockUart {
+    type Error = ();
+
+    fn write(&mut self, byte: u8) -> nb::Result<(), ()> {
+        self.tx.push(byte);
+        if self.collisions > 0 {
+            self.collisions -= 1;
+            self.rx.push_back(byte ^ 0x01);
+        } else {
+            self.rx.push_back(byte);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+//! Mocks shared by the tests of the loco crates
+
+use embedded_hal::timer::nb::CountDown;
+use embedded_time::duration::*;
+
+/// Timer expiring after one `wait` call per started 100µs
+#[derive(Default)]
+pub struct MockTimer {
+    count: u32,
+}
+
+impl MockTimer {
+    pub fn new() -> Self {
+        MockTimer { count: 0 }
+    }
+}
+
+impl CountDown for MockTimer {
+    type Error = ();
+    type Time = Microseconds<u32>;
+
+    fn start<T: Into<Microseconds<u32>>>(&mut self, timeout: T) -> Result<(), Self::Error> {
+        self.count = timeout.into().0 / 100;
+        Ok(())
+    }
+
+    fn wait(&mut self) -> nb::Result<(), Self::Error> {
+        if self.count > 0 {
+            self.count -= 1;
+        }
+        if self.count > 0 {
+            Err(nb::Error::WouldBlock)
+        } else {
+            Ok(())
+        }
+    }
+}
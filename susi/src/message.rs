@@ -2,7 +2,7 @@ use num_traits::{FromPrimitive, ToPrimitive};
 
 use loco_core::{analog::AnalogNumber, drive::Direction, functions::FunctionGroupNumber};
 
-use loco_dcc::function::FunctionGroupByte;
+use loco_dcc::{cv::CvAccess, function::FunctionGroupByte};
 
 pub trait Byte<T> {
     fn from_byte(byte: u8) -> T;
@@ -44,6 +44,7 @@ pub enum Msg {
     },
     CVBitManipulation {
         addr: u8,
+        /// K bit, set to write the bit and cleared to verify it
         check: bool,
         value: bool,
         position: u8,
@@ -56,6 +57,8 @@ pub enum Msg {
 }
 
 static MASK7: u8 = 0b01111111;
+/// Decoder CV of the first SUSI CV
+const CV_OFFSET: u16 = 897;
 
 #[allow(clippy::len_without_is_empty)]
 impl Msg {
@@ -140,6 +143,31 @@ impl Msg {
         }
     }
 
+    /// Get the CV access instruction of CV messages
+    ///
+    /// SUSI CVs are mapped to the decoder CVs 897 to 1024, so the
+    /// instruction can be applied to a `loco_dcc::store::CvStore`.
+    pub fn cv_access(&self) -> Option<CvAccess> {
+        let cv = |addr: &u8| CV_OFFSET + (addr & MASK7) as u16;
+        match self {
+            Msg::CVByteCheck { addr, value } => Some(CvAccess::VerifyByte(cv(addr), *value)),
+            Msg::CVBitManipulation {
+                addr,
+                check: true,
+                value,
+                position,
+            } => Some(CvAccess::WriteBit(cv(addr), *position, *value)),
+            Msg::CVBitManipulation {
+                addr,
+                value,
+                position,
+                ..
+            } => Some(CvAccess::VerifyBit(cv(addr), *position, *value)),
+            Msg::CVByteSet { addr, value } => Some(CvAccess::WriteByte(cv(addr), *value)),
+            _ => None,
+        }
+    }
+
     pub fn to_bytes(&self) -> [u8; 3] {
         match self {
             Msg::Noop => [0x00, 0x00, 0x00],
@@ -274,4 +302,18 @@ mod tests {
         };
         assert!(msg.needs_ack());
     }
+
+    // test mapping of CV messages to decoder CVs
+    #[test]
+    fn cv_access() {
+        let msg = Msg::from_bytes(&[119, 0x80, 0xAA]);
+        assert_eq!(msg.cv_access(), Some(CvAccess::VerifyByte(897, 0xAA)));
+        let msg = Msg::from_bytes(&[123, 0x81, 0b1111_1101]);
+        assert_eq!(msg.cv_access(), Some(CvAccess::WriteBit(898, 5, true)));
+        let msg = Msg::from_bytes(&[123, 0x81, 0b1110_0101]);
+        assert_eq!(msg.cv_access(), Some(CvAccess::VerifyBit(898, 5, false)));
+        let msg = Msg::from_bytes(&[127, 0xFF, 0x03]);
+        assert_eq!(msg.cv_access(), Some(CvAccess::WriteByte(1024, 0x03)));
+        assert_eq!(Msg::TriggerPulse.cv_access(), None);
+    }
 }
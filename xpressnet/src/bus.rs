@@ -0,0 +1,664 @@
+//! XpressNet bus master and devices on the RS-485 bus
+//!
+//! The bus uses 9 bit words at 62.5 kBaud. The master sends call bytes
+//! with the ninth bit set, all other words have it cleared. Devices use
+//! slots 1 to 31 and may only send a request right after the master
+//! sent a normal inquiry to their slot.
+
+use embedded_hal::serial::nb::{Read, Write};
+use embedded_hal::timer::nb::CountDown;
+use embedded_time::duration::*;
+use heapless::{Deque, Vec};
+use log::{debug, trace};
+
+use crate::{CentralMessage, DeviceMessage, Error};
+use loco_core::Bits;
+
+/// Number of device slots, slot 0 addresses all devices
+pub const SLOTS: u8 = 32;
+/// Maximum length of a message including header and XOR byte
+pub const BUF_SIZE: usize = 17;
+/// Number of messages waiting to be sent
+const QUEUE_SIZE: usize = 8;
+
+/// Call byte types, combined with a slot
+pub const CALL_REQUEST_ACK: u8 = 0x00;
+pub const CALL_INQUIRY: u8 = 0x40;
+pub const CALL_MESSAGE: u8 = 0x60;
+/// Ninth bit marking a call byte
+pub const CALL_BIT: u16 = 0x100;
+
+/// Time to send one word including start, stop and ninth bit in µs
+const WORD_TIME: u32 = 176;
+/// Time a device may take to start its request after the call byte in µs
+const RESPONSE_TIME: u32 = 110;
+
+/// Acknowledgement response of a device
+const ACK_RESPONSE: [u8; 2] = [0x20, 0x20];
+
+/// Get the call byte of a type for a slot, including the parity bit
+///
+/// The parity bit in bit 7 makes the number of set bits even.
+pub fn call_byte(kind: u8, slot: u8) -> u16 {
+    let byte = kind | (slot & 0x1F);
+    let parity = (byte.count_ones() as u8 & 0x01) << 7;
+    CALL_BIT | (byte | parity) as u16
+}
+
+/// Parse a call byte into type and slot, checking the parity
+pub fn parse_call_byte(word: u16) -> Option<(u8, u8)> {
+    let byte = word as u8;
+    if word & CALL_BIT == 0 || byte.count_ones() & 0x01 != 0 {
+        return None;
+    }
+    Some((byte & 0x60, byte & 0x1F))
+}
+
+#[derive(Debug, PartialEq)]
+enum State {
+    Idle,
+    Receiving,
+}
+
+/// Master of an XpressNet bus polling all device slots
+///
+/// Requests of devices are returned by `run` and have to be answered
+/// with `send` or `broadcast`. Transfer errors and unknown requests are
+/// answered by the master. If too many replies are waiting to be sent,
+/// requests are answered with `StationBusy`.
+pub struct Master<U, TIM> {
+    uart: U,
+    timer: TIM,
+    state: State,
+    slot: u8,
+    buf: [u8; BUF_SIZE],
+    len: usize,
+    queue: Deque<(u16, Vec<u8, BUF_SIZE>), QUEUE_SIZE>,
+    /// Slots that have to acknowledge a transfer error
+    ack: u32,
+}
+
+impl<U, TIM> Master<U, TIM>
+where
+    U: Read<u16> + Write<u16>,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+{
+    pub fn new(uart: U, timer: TIM) -> Self {
+        Self {
+            uart,
+            timer,
+            state: State::Idle,
+            slot: 0,
+            buf: [0; BUF_SIZE],
+            len: 0,
+            queue: Deque::new(),
+            ack: 0,
+        }
+    }
+
+    /// Queue a message to the device in the given slot
+    pub fn send<S: Bits<u8>>(&mut self, slot: u8, msg: &CentralMessage<S>) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        let data = Vec::from_slice(&buf[0..len]).map_err(|_| Error::QueueFull)?;
+        self.queue
+            .push_back((call_byte(CALL_MESSAGE, slot), data))
+            .map_err(|_| Error::QueueFull)
+    }
+
+    /// Queue a message to all devices
+    pub fn broadcast<S: Bits<u8>>(&mut self, msg: &CentralMessage<S>) -> Result<(), Error> {
+        self.send(0, msg)
+    }
+
+    /// Poll the devices and return the next request
+    ///
+    /// Queued messages are sent before the next slot is polled.
+    pub fn run(&mut self) -> nb::Result<(u8, DeviceMessage), Error> {
+        loop {
+            match self.state {
+                State::Idle => {
+                    if let Some((call, data)) = self.queue.pop_front() {
+                        self.write(call, &data)?;
+                        continue;
+                    }
+                    self.slot = self.slot % (SLOTS - 1) + 1;
+                    let kind = if self.ack & (1 << self.slot) != 0 {
+                        CALL_REQUEST_ACK
+                    } else {
+                        CALL_INQUIRY
+                    };
+                    self.write(call_byte(kind, self.slot), &[])?;
+                    self.len = 0;
+                    self.start_timer(2 * WORD_TIME + RESPONSE_TIME)?;
+                    self.state = State::Receiving;
+                    return Err(nb::Error::WouldBlock);
+                }
+                State::Receiving => match self.uart.read() {
+                    Ok(word) if word & CALL_BIT != 0 => {
+                        debug!("unexpected call byte {:#05X}", word);
+                    }
+                    Ok(word) => {
+                        self.buf[self.len] = word as u8;
+                        self.len += 1;
+                        if self.len == (self.buf[0] & 0x0F) as usize + 2 {
+                            self.state = State::Idle;
+                            return self.received();
+                        }
+                        self.start_timer(2 * WORD_TIME)?;
+                    }
+                    Err(nb::Error::WouldBlock) => {
+                        self.timer
+                            .wait()
+                            .map_err(|e| e.map(|_| Error::TimerError))?;
+                        self.state = State::Idle;
+                        if self.len > 0 {
+                            debug!("incomplete request from slot {}", self.slot);
+                            self.transfer_error()?;
+                        }
+                        return Err(nb::Error::WouldBlock);
+                    }
+                    Err(nb::Error::Other(_)) => {
+                        self.state = State::Idle;
+                        return Err(nb::Error::Other(Error::IOError));
+                    }
+                },
+            }
+        }
+    }
+
+    /// Handle a complete request of the current slot
+    fn received(&mut self) -> nb::Result<(u8, DeviceMessage), Error> {
+        let bytes = &self.buf[0..self.len];
+        trace!("slot {}: {:#04X?}", self.slot, bytes);
+        let x = bytes.iter().fold(0, |acc, x| acc ^ x);
+        if x != 0 {
+            self.transfer_error()?;
+            return Err(nb::Error::WouldBlock);
+        }
+        if bytes == ACK_RESPONSE {
+            self.ack &= !(1 << self.slot);
+            return Err(nb::Error::WouldBlock);
+        }
+        match DeviceMessage::from_bytes(bytes) {
+            Ok(_) if self.queue.is_full() => {
+                self.reply(&CentralMessage::<crate::CentralState>::StationBusy)?;
+                Err(nb::Error::WouldBlock)
+            }
+            Ok(msg) => Ok((self.slot, msg)),
+            Err(_) => {
+                self.reply(&CentralMessage::<crate::CentralState>::UnknownCommand)?;
+                Err(nb::Error::WouldBlock)
+            }
+        }
+    }
+
+    /// Answer a broken request and request an acknowledgement on the next poll
+    fn transfer_error(&mut self) -> Result<(), Error> {
+        self.ack |= 1 << self.slot;
+        self.reply(&CentralMessage::<crate::CentralState>::TransferError)
+    }
+
+    /// Send a message to the current slot right away
+    fn reply<S: Bits<u8>>(&mut self, msg: &CentralMessage<S>) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        self.write(call_byte(CALL_MESSAGE, self.slot), &buf[0..len])
+    }
+
+    fn write(&mut self, call: u16, data: &[u8]) -> Result<(), Error> {
+        let words = core::iter::once(call).chain(data.iter().map(|b| *b as u16));
+        for word in words {
+            nb::block!(self.uart.write(word)).map_err(|_| Error::IOError)?;
+        }
+        Ok(())
+    }
+
+    fn start_timer(&mut self, us: u32) -> Result<(), Error> {
+        self.timer
+            .start(us.microseconds())
+            .map_err(|_| Error::TimerError)
+    }
+}
+
+/// Device on an XpressNet bus answering the call bytes for its slot
+///
+/// Requests queued with `send` are sent on the next normal inquiry.
+/// Requests answered with `TransferError` or `StationBusy` are repeated
+/// on the following inquiry. Messages to the slot and broadcasts are
+/// returned by `run`.
+pub struct Slave<U, TIM> {
+    uart: U,
+    timer: TIM,
+    slot: u8,
+    state: State,
+    broadcast: bool,
+    buf: [u8; BUF_SIZE],
+    len: usize,
+    queue: Deque<Vec<u8, BUF_SIZE>, QUEUE_SIZE>,
+    /// Last sent request, repeated if `retry` is set
+    last: Option<Vec<u8, BUF_SIZE>>,
+    retry: bool,
+}
+
+impl<U, TIM> Slave<U, TIM>
+where
+    U: Read<u16> + Write<u16>,
+    TIM: CountDown,
+    TIM::Time: From<Microseconds<u32>>,
+{
+    pub fn new(uart: U, timer: TIM, slot: u8) -> Self {
+        Self {
+            uart,
+            timer,
+            slot,
+            state: State::Idle,
+            broadcast: false,
+            buf: [0; BUF_SIZE],
+            len: 0,
+            queue: Deque::new(),
+            last: None,
+            retry: false,
+        }
+    }
+
+    /// Queue a request to send on the next inquiry
+    pub fn send(&mut self, msg: &DeviceMessage) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        let data = Vec::from_slice(&buf[0..len]).map_err(|_| Error::QueueFull)?;
+        self.queue.push_back(data).map_err(|_| Error::QueueFull)
+    }
+
+    /// Listen on the bus, answer call bytes and return received messages
+    pub fn run<S: Bits<u8> + From<u8>>(&mut self) -> nb::Result<CentralMessage<S>, Error> {
+        loop {
+            let word = match self.uart.read() {
+                Ok(word) => word,
+                Err(nb::Error::WouldBlock) => {
+                    if self.state == State::Receiving {
+                        self.timer
+                            .wait()
+                            .map_err(|e| e.map(|_| Error::TimerError))?;
+                        debug!("incomplete message");
+                        self.state = State::Idle;
+                    }
+                    return Err(nb::Error::WouldBlock);
+                }
+                Err(nb::Error::Other(_)) => return Err(nb::Error::Other(Error::IOError)),
+            };
+            if word & CALL_BIT != 0 {
+                self.state = State::Idle;
+                match parse_call_byte(word) {
+                    Some((CALL_INQUIRY, slot)) if slot == self.slot => self.answer()?,
+                    Some((CALL_REQUEST_ACK, slot)) if slot == self.slot => {
+                        self.write(&ACK_RESPONSE)?
+                    }
+                    Some((CALL_MESSAGE, slot)) if slot == self.slot || slot == 0 => {
+                        self.broadcast = slot == 0;
+                        self.len = 0;
+                        self.state = State::Receiving;
+                        self.start_timer(2 * WORD_TIME)?;
+                    }
+                    _ => {}
+                }
+            } else if self.state == State::Receiving {
+                self.buf[self.len] = word as u8;
+                self.len += 1;
+                if self.len == (self.buf[0] & 0x0F) as usize + 2 {
+                    self.state = State::Idle;
+                    if let Some(msg) = self.received() {
+                        return Ok(msg);
+                    }
+                } else {
+                    self.start_timer(2 * WORD_TIME)?;
+                }
+            }
+        }
+    }
+
+    /// Handle a complete message to this slot or all devices
+    fn received<S: Bits<u8> + From<u8>>(&mut self) -> Option<CentralMessage<S>> {
+        let bytes = &self.buf[0..self.len];
+        trace!("slot {}: {:#04X?}", self.slot, bytes);
+        match CentralMessage::from_bytes(bytes) {
+            Ok(CentralMessage::TransferError) | Ok(CentralMessage::StationBusy) => {
+                self.retry = true;
+                None
+            }
+            Ok(msg) => {
+                if !self.broadcast {
+                    self.retry = false;
+                }
+                Some(msg)
+            }
+            Err(_) => {
+                debug!("invalid message {:#04X?}", bytes);
+                None
+            }
+        }
+    }
+
+    /// Answer a normal inquiry with the next request
+    fn answer(&mut self) -> Result<(), Error> {
+        if self.retry {
+            self.retry = false;
+            if let Some(last) = self.last.clone() {
+                return self.write(&last);
+            }
+        }
+        if let Some(data) = self.queue.pop_front() {
+            self.write(&data)?;
+            self.last = Some(data);
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+        for byte in data {
+            nb::block!(self.uart.write(*byte as u16)).map_err(|_| Error::IOError)?;
+        }
+        Ok(())
+    }
+
+    fn start_timer(&mut self, us: u32) -> Result<(), Error> {
+        self.timer
+            .start(us.microseconds())
+            .map_err(|_| Error::TimerError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+    use crate::CentralState;
+
+    fn master() -> Master<MockUart, MockTimer> {
+        Master::new(MockUart::default(), MockTimer::new())
+    }
+
+    /// Number of inquiries and acknowledgement requests sent
+    fn polls(tx: &[u16]) -> usize {
+        tx.iter()
+            .filter(|w| matches!(parse_call_byte(**w), Some((k, _)) if k != CALL_MESSAGE))
+            .count()
+    }
+
+    /// Run the master until it polled the given number of slots
+    fn run(
+        master: &mut Master<MockUart, MockTimer>,
+        n: usize,
+    ) -> std::vec::Vec<(u8, DeviceMessage)> {
+        let mut requests = std::vec::Vec::new();
+        while polls(&master.uart.tx) < n || master.state == State::Receiving {
+            match master.run() {
+                Ok(req) => requests.push(req),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => panic!("{:?}", e),
+            }
+        }
+        requests
+    }
+
+    /// Words of a call byte followed by a message
+    fn words(call: u16, bytes: &[u8]) -> std::vec::Vec<u16> {
+        let mut words = vec![call];
+        words.extend(bytes.iter().map(|b| *b as u16));
+        words
+    }
+
+    fn contains(tx: &[u16], words: &[u16]) -> bool {
+        tx.windows(words.len()).any(|w| w == words)
+    }
+
+    #[test]
+    fn call_byte_parity() {
+        assert_eq!(call_byte(CALL_INQUIRY, 1), 0x141);
+        assert_eq!(call_byte(CALL_INQUIRY, 3), 0x1C3);
+        assert_eq!(call_byte(CALL_REQUEST_ACK, 1), 0x181);
+        assert_eq!(call_byte(CALL_MESSAGE, 0), 0x160);
+        for kind in [CALL_REQUEST_ACK, CALL_INQUIRY, CALL_MESSAGE].iter() {
+            for slot in 0..SLOTS {
+                let word = call_byte(*kind, slot);
+                assert_eq!((word as u8).count_ones() % 2, 0);
+                assert_eq!(parse_call_byte(word), Some((*kind, slot)));
+            }
+        }
+        assert_eq!(parse_call_byte(0x1C1), None);
+        assert_eq!(parse_call_byte(0x041), None);
+    }
+
+    #[test]
+    fn poll_all_slots() {
+        let mut master = master();
+        assert!(run(&mut master, 32).is_empty());
+        let expected: std::vec::Vec<u16> = (1..SLOTS)
+            .chain(1..2)
+            .map(|slot| call_byte(CALL_INQUIRY, slot))
+            .collect();
+        assert_eq!(master.uart.tx, expected);
+    }
+
+    #[test]
+    fn request_and_reply() {
+        let mut master = master();
+        master
+            .uart
+            .respond(call_byte(CALL_INQUIRY, 5), &[0x21, 0x24, 0x05]);
+        assert_eq!(run(&mut master, 5), vec![(5, DeviceMessage::GetState)]);
+        master
+            .send(5, &CentralMessage::State(CentralState::EMERGENCY_OFF))
+            .unwrap();
+        run(&mut master, 6);
+        let reply = words(call_byte(CALL_MESSAGE, 5), &[0x62, 0x22, 0x01, 0x41]);
+        assert!(contains(&master.uart.tx, &reply));
+        assert_eq!(master.uart.tx.last(), Some(&call_byte(CALL_INQUIRY, 6)));
+    }
+
+    #[test]
+    fn transfer_error() {
+        let mut master = master();
+        master
+            .uart
+            .respond(call_byte(CALL_INQUIRY, 3), &[0x21, 0x24, 0x04]);
+        master
+            .uart
+            .respond(call_byte(CALL_REQUEST_ACK, 3), &[0x20, 0x20]);
+        run(&mut master, 34);
+        let error = words(call_byte(CALL_MESSAGE, 3), &[0x61, 0x80, 0xE1]);
+        assert!(contains(&master.uart.tx, &error));
+        assert!(contains(&master.uart.tx, &[call_byte(CALL_REQUEST_ACK, 3)]));
+        assert_eq!(master.ack, 0);
+        run(&mut master, 65);
+        assert_eq!(master.uart.tx.last(), Some(&call_byte(CALL_INQUIRY, 3)));
+    }
+
+    #[test]
+    fn incomplete_request() {
+        let mut master = master();
+        master
+            .uart
+            .respond(call_byte(CALL_INQUIRY, 1), &[0x21, 0x24]);
+        assert!(run(&mut master, 2).is_empty());
+        let error = words(call_byte(CALL_MESSAGE, 1), &[0x61, 0x80, 0xE1]);
+        assert!(contains(&master.uart.tx, &error));
+        assert_eq!(master.ack, 1 << 1);
+    }
+
+    #[test]
+    fn unknown_command() {
+        let mut master = master();
+        master
+            .uart
+            .respond(call_byte(CALL_INQUIRY, 1), &[0x21, 0x7F, 0x5E]);
+        assert!(run(&mut master, 2).is_empty());
+        let error = words(call_byte(CALL_MESSAGE, 1), &[0x61, 0x82, 0xE3]);
+        assert!(contains(&master.uart.tx, &error));
+    }
+
+    #[test]
+    fn broadcast() {
+        let mut master = master();
+        run(&mut master, 1);
+        master
+            .broadcast(&CentralMessage::<CentralState>::TrackPowerOff)
+            .unwrap();
+        run(&mut master, 2);
+        let expected = words(0x160, &[0x61, 0x00, 0x61]);
+        assert!(contains(&master.uart.tx, &expected));
+    }
+
+    #[test]
+    fn busy() {
+        let mut master = master();
+        master
+            .uart
+            .respond(call_byte(CALL_INQUIRY, 1), &[0x21, 0x24, 0x05]);
+        assert!(master.run().is_err());
+        for _ in 0..QUEUE_SIZE {
+            master
+                .broadcast(&CentralMessage::<CentralState>::TrackPowerOff)
+                .unwrap();
+        }
+        assert!(matches!(
+            master.broadcast(&CentralMessage::<CentralState>::TrackPowerOff),
+            Err(Error::QueueFull)
+        ));
+        assert!(run(&mut master, 2).is_empty());
+        let busy = words(call_byte(CALL_MESSAGE, 1), &[0x61, 0x81, 0xE0]);
+        assert!(contains(&master.uart.tx, &busy));
+    }
+
+    fn slave() -> Slave<MockUart, MockTimer> {
+        Slave::new(MockUart::default(), MockTimer::new(), 5)
+    }
+
+    /// Run the slave until all received words are handled
+    fn listen(
+        slave: &mut Slave<MockUart, MockTimer>,
+    ) -> std::vec::Vec<CentralMessage<CentralState>> {
+        let mut messages = std::vec::Vec::new();
+        while !slave.uart.rx.is_empty() || slave.state == State::Receiving {
+            match slave.run() {
+                Ok(msg) => messages.push(msg),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => panic!("{:?}", e),
+            }
+        }
+        messages
+    }
+
+    #[test]
+    fn slave_answers_inquiry() {
+        let mut slave = slave();
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        assert!(listen(&mut slave).is_empty());
+        assert!(slave.uart.tx.is_empty());
+        slave.send(&DeviceMessage::GetState).unwrap();
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 4));
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        listen(&mut slave);
+        assert_eq!(slave.uart.tx, vec![0x21, 0x24, 0x05]);
+    }
+
+    #[test]
+    fn slave_receives_messages() {
+        let mut slave = slave();
+        let messages = [
+            words(call_byte(CALL_MESSAGE, 4), &[0x61, 0x01, 0x60]),
+            words(call_byte(CALL_MESSAGE, 5), &[0x62, 0x22, 0x01, 0x41]),
+            words(call_byte(CALL_MESSAGE, 0), &[0x61, 0x00, 0x61]),
+            words(call_byte(CALL_MESSAGE, 5), &[0x62, 0x22, 0x01, 0x40]),
+        ];
+        for words in messages.iter() {
+            slave.uart.rx.extend(words);
+        }
+        assert_eq!(
+            listen(&mut slave),
+            vec![
+                CentralMessage::State(CentralState::EMERGENCY_OFF),
+                CentralMessage::TrackPowerOff,
+            ]
+        );
+        assert!(slave.uart.tx.is_empty());
+    }
+
+    #[test]
+    fn slave_incomplete_message() {
+        let mut slave = slave();
+        slave
+            .uart
+            .rx
+            .extend(words(call_byte(CALL_MESSAGE, 5), &[0x62, 0x22]));
+        assert!(listen(&mut slave).is_empty());
+        slave
+            .uart
+            .rx
+            .extend(words(call_byte(CALL_MESSAGE, 5), &[0x61, 0x00, 0x61]));
+        assert_eq!(listen(&mut slave), vec![CentralMessage::TrackPowerOff]);
+    }
+
+    #[test]
+    fn slave_repeats_request() {
+        let mut slave = slave();
+        slave.send(&DeviceMessage::GetState).unwrap();
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        slave
+            .uart
+            .rx
+            .extend(words(call_byte(CALL_MESSAGE, 5), &[0x61, 0x80, 0xE1]));
+        slave.uart.rx.push_back(call_byte(CALL_REQUEST_ACK, 5));
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        slave
+            .uart
+            .rx
+            .extend(words(call_byte(CALL_MESSAGE, 5), &[0x61, 0x81, 0xE0]));
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        slave
+            .uart
+            .rx
+            .extend(words(call_byte(CALL_MESSAGE, 5), &[0x61, 0x82, 0xE3]));
+        slave.uart.rx.push_back(call_byte(CALL_INQUIRY, 5));
+        assert_eq!(
+            listen(&mut slave),
+            vec![CentralMessage::<CentralState>::UnknownCommand]
+        );
+        let request = [0x21, 0x24, 0x05];
+        let expected: std::vec::Vec<u16> = request
+            .iter()
+            .chain(ACK_RESPONSE.iter())
+            .chain(request.iter())
+            .chain(request.iter())
+            .map(|b| *b as u16)
+            .collect();
+        assert_eq!(slave.uart.tx, expected);
+    }
+
+    #[test]
+    fn master_and_slave() {
+        let mut master = master();
+        let mut slave = slave();
+        slave.send(&DeviceMessage::GetState).unwrap();
+        let mut reply = None;
+        for _ in 0..1000 {
+            slave.uart.rx.extend(master.uart.tx.drain(..));
+            master.uart.rx.extend(slave.uart.tx.drain(..));
+            match master.run() {
+                Ok((slot, DeviceMessage::GetState)) => master
+                    .send(slot, &CentralMessage::State(CentralState::empty()))
+                    .unwrap(),
+                Ok(req) => panic!("unexpected {:?}", req),
+                Err(nb::Error::WouldBlock) => {}
+                Err(nb::Error::Other(e)) => panic!("{:?}", e),
+            }
+            if let Ok(msg) = slave.run::<CentralState>() {
+                reply = Some(msg);
+                break;
+            }
+        }
+        assert_eq!(reply, Some(CentralMessage::State(CentralState::empty())));
+    }
+}
@@ -0,0 +1,380 @@
+//! Serial framing of the LI100, LI101F and LI-USB PC interfaces
+//!
+//! The LI100 and LI101F pass XpressNet messages unchanged and add a few
+//! messages of the interface itself. The LI-USB and the LAN interfaces
+//! prefix every message with `0xFF 0xFE`, or with `0xFF 0xFD` for
+//! broadcasts of the command station.
+
+use embedded_io::blocking::{Read, Write};
+use loco_core::{mov, xor, Bits};
+use log::debug;
+
+use crate::bus::BUF_SIZE;
+use crate::{CentralMessage, DeviceMessage, Error};
+
+/// Prefix of replies on the LI-USB
+const PREFIX_REPLY: [u8; 2] = [0xFF, 0xFE];
+/// Prefix of broadcasts on the LI-USB
+const PREFIX_BROADCAST: [u8; 2] = [0xFF, 0xFD];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Framing {
+    /// Plain XpressNet messages used by the LI100 and LI101F
+    Li100,
+    /// Messages prefixed with `0xFF 0xFE` or `0xFF 0xFD`
+    LiUsb,
+}
+
+/// Message of the PC to the interface itself
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterfaceRequest {
+    GetVersion,
+    /// Set the XpressNet slot, 0 or values above 31 only query it
+    Address(u8),
+    /// Set the baud rate (1 to 4 for 19200 to 115200), 0 only queries it
+    Baudrate(u8),
+}
+
+/// Message of the interface itself to the PC
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InterfaceReply {
+    /// The command was sent to the command station
+    Ok,
+    /// Timeout in the communication with the PC
+    PcError,
+    /// Error in the communication with the command station
+    StationError,
+    UnknownError,
+    /// The command station does not poll the interface anymore
+    NoTimeslot,
+    BufferOverflow,
+    /// Hardware and software version
+    Version(u8, u8),
+    Address(u8),
+    Baudrate(u8),
+}
+
+/// Message received from the PC
+#[derive(Debug, Clone, PartialEq)]
+pub enum Request {
+    XpressNet(DeviceMessage),
+    Interface(InterfaceRequest),
+}
+
+impl InterfaceRequest {
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use InterfaceRequest::*;
+        match self {
+            GetVersion => mov!(buf[0..2] <- &[0xF0, 0xF0]),
+            Address(addr) => mov!(buf[0..4] <- &xor!([0xF2, 0x01, *addr])),
+            Baudrate(baud) => mov!(buf[0..4] <- &xor!([0xF2, 0x02, *baud])),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use InterfaceRequest::*;
+        match bytes {
+            [0xF0, 0xF0, ..] => Ok(GetVersion),
+            [0xF2, 0x01, addr, x, ..] if *x == 0xF3 ^ addr => Ok(Address(*addr)),
+            [0xF2, 0x02, baud, x, ..] if *x == 0xF0 ^ baud => Ok(Baudrate(*baud)),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+impl InterfaceReply {
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use InterfaceReply::*;
+        match self {
+            Ok => mov!(buf[0..3] <- &[0x01, 0x04, 0x05]),
+            PcError => mov!(buf[0..3] <- &[0x01, 0x01, 0x00]),
+            StationError => mov!(buf[0..3] <- &[0x01, 0x02, 0x03]),
+            UnknownError => mov!(buf[0..3] <- &[0x01, 0x03, 0x02]),
+            NoTimeslot => mov!(buf[0..3] <- &[0x01, 0x05, 0x04]),
+            BufferOverflow => mov!(buf[0..3] <- &[0x01, 0x06, 0x07]),
+            Version(hw, sw) => mov!(buf[0..4] <- &xor!([0x02, *hw, *sw])),
+            Address(addr) => mov!(buf[0..4] <- &xor!([0xF2, 0x01, *addr])),
+            Baudrate(baud) => mov!(buf[0..4] <- &xor!([0xF2, 0x02, *baud])),
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use InterfaceReply::*;
+        match bytes {
+            [0x01, 0x04, 0x05, ..] => Result::Ok(Ok),
+            [0x01, 0x01, 0x00, ..] => Result::Ok(PcError),
+            [0x01, 0x02, 0x03, ..] => Result::Ok(StationError),
+            [0x01, 0x03, 0x02, ..] => Result::Ok(UnknownError),
+            [0x01, 0x05, 0x04, ..] => Result::Ok(NoTimeslot),
+            [0x01, 0x06, 0x07, ..] => Result::Ok(BufferOverflow),
+            [0x02, hw, sw, x, ..] if *x == 0x02 ^ hw ^ sw => Result::Ok(Version(*hw, *sw)),
+            [0xF2, 0x01, addr, x, ..] if *x == 0xF3 ^ addr => Result::Ok(Address(*addr)),
+            [0xF2, 0x02, baud, x, ..] if *x == 0xF0 ^ baud => Result::Ok(Baudrate(*baud)),
+            _ => Err(Error::ParseError),
+        }
+    }
+}
+
+/// Command station side of a PC interface on a serial port
+///
+/// Reading blocks until a complete message was received. Broken messages
+/// are dropped and reported as `ParseError`, the caller should answer
+/// them with `InterfaceReply::PcError`.
+pub struct Interface<IO> {
+    io: IO,
+    framing: Framing,
+    buf: [u8; 2 * BUF_SIZE],
+    len: usize,
+    /// Length of the last returned frame, removed on the next read
+    consumed: usize,
+}
+
+impl<IO> Interface<IO> {
+    pub fn new(io: IO, framing: Framing) -> Self {
+        Self {
+            io,
+            framing,
+            buf: [0; 2 * BUF_SIZE],
+            len: 0,
+            consumed: 0,
+        }
+    }
+
+    pub fn framing(&self) -> Framing {
+        self.framing
+    }
+
+    pub fn release(self) -> IO {
+        self.io
+    }
+
+    /// Get start and end of the next complete frame in the buffer,
+    /// dropping bytes before a valid LI-USB prefix
+    fn frame(&mut self) -> Option<(usize, usize)> {
+        let start = match self.framing {
+            Framing::Li100 => 0,
+            Framing::LiUsb => {
+                while self.len > 0 && self.buf[0] != 0xFF
+                    || self.len > 1 && self.buf[1] != 0xFE && self.buf[1] != 0xFD
+                {
+                    debug!("dropped byte {:#04X}", self.buf[0]);
+                    self.consume(1);
+                }
+                2
+            }
+        };
+        if self.len <= start {
+            return None;
+        }
+        let end = start + (self.buf[start] & 0x0F) as usize + 2;
+        if self.len < end {
+            return None;
+        }
+        Some((start, end))
+    }
+
+    fn consume(&mut self, len: usize) {
+        self.buf.copy_within(len..self.len, 0);
+        self.len -= len;
+    }
+}
+
+impl<IO: Read> Interface<IO> {
+    /// Read the next frame without the LI-USB prefix
+    pub fn read_frame(&mut self) -> Result<&[u8], Error> {
+        self.consume(self.consumed);
+        self.consumed = 0;
+        loop {
+            if let Some((start, end)) = self.frame() {
+                self.consumed = end;
+                return Ok(&self.buf[start..end]);
+            }
+            let n = self
+                .io
+                .read(&mut self.buf[self.len..])
+                .map_err(|_| Error::IOError)?;
+            if n == 0 {
+                return Err(Error::IOError);
+            }
+            self.len += n;
+        }
+    }
+
+    /// Read the next request of the PC
+    pub fn read(&mut self) -> Result<Request, Error> {
+        let bytes = self.read_frame()?;
+        if let Ok(req) = InterfaceRequest::from_bytes(bytes) {
+            return Ok(Request::Interface(req));
+        }
+        match DeviceMessage::from_bytes(bytes) {
+            Ok(msg) => Ok(Request::XpressNet(msg)),
+            Err(e) => {
+                debug!("invalid request {:#04X?}", bytes);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl<IO: Write> Interface<IO> {
+    /// Send a reply of the command station to the PC
+    pub fn write<S: Bits<u8>>(&mut self, msg: &CentralMessage<S>) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        self.write_frame(&PREFIX_REPLY, &buf[0..len])
+    }
+
+    /// Send a broadcast of the command station to the PC
+    pub fn broadcast<S: Bits<u8>>(&mut self, msg: &CentralMessage<S>) -> Result<(), Error> {
+        let mut buf = [0; BUF_SIZE];
+        let len = msg.to_buf(&mut buf);
+        self.write_frame(&PREFIX_BROADCAST, &buf[0..len])
+    }
+
+    /// Send a message of the interface itself to the PC
+    pub fn reply(&mut self, msg: &InterfaceReply) -> Result<(), Error> {
+        let mut buf = [0; 4];
+        let len = msg.to_buf(&mut buf);
+        self.write_frame(&PREFIX_REPLY, &buf[0..len])
+    }
+
+    fn write_frame(&mut self, prefix: &[u8], bytes: &[u8]) -> Result<(), Error> {
+        if self.framing == Framing::LiUsb {
+            self.io.write_all(prefix).map_err(|_| Error::IOError)?;
+        }
+        self.io.write_all(bytes).map_err(|_| Error::IOError)?;
+        self.io.flush().map_err(|_| Error::IOError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::MockSerial;
+    use crate::CentralState;
+    use loco_core::address::Address;
+    use loco_core::drive::{Direction, Speed};
+
+    fn interface(framing: Framing, rx: &[u8]) -> Interface<MockSerial> {
+        let mut serial = MockSerial::default();
+        serial.rx.extend(rx);
+        Interface::new(serial, framing)
+    }
+
+    #[test]
+    fn interface_messages() {
+        let requests = [
+            (InterfaceRequest::GetVersion, vec![0xF0, 0xF0]),
+            (InterfaceRequest::Address(5), vec![0xF2, 0x01, 0x05, 0xF6]),
+            (InterfaceRequest::Baudrate(1), vec![0xF2, 0x02, 0x01, 0xF1]),
+        ];
+        for (req, bytes) in requests.iter() {
+            let mut buf = [0; 4];
+            let len = req.to_buf(&mut buf);
+            assert_eq!(&buf[0..len], &bytes[..]);
+            assert_eq!(InterfaceRequest::from_bytes(bytes).unwrap(), *req);
+        }
+        let replies = [
+            (InterfaceReply::Ok, vec![0x01, 0x04, 0x05]),
+            (InterfaceReply::PcError, vec![0x01, 0x01, 0x00]),
+            (InterfaceReply::StationError, vec![0x01, 0x02, 0x03]),
+            (InterfaceReply::UnknownError, vec![0x01, 0x03, 0x02]),
+            (InterfaceReply::NoTimeslot, vec![0x01, 0x05, 0x04]),
+            (InterfaceReply::BufferOverflow, vec![0x01, 0x06, 0x07]),
+            (
+                InterfaceReply::Version(0x30, 0x01),
+                vec![0x02, 0x30, 0x01, 0x33],
+            ),
+            (InterfaceReply::Address(5), vec![0xF2, 0x01, 0x05, 0xF6]),
+            (InterfaceReply::Baudrate(1), vec![0xF2, 0x02, 0x01, 0xF1]),
+        ];
+        for (reply, bytes) in replies.iter() {
+            let mut buf = [0; 4];
+            let len = reply.to_buf(&mut buf);
+            assert_eq!(&buf[0..len], &bytes[..]);
+            assert_eq!(InterfaceReply::from_bytes(bytes).unwrap(), *reply);
+        }
+        assert!(InterfaceRequest::from_bytes(&[0xF2, 0x01, 0x05, 0xF5]).is_err());
+        assert!(InterfaceReply::from_bytes(&[0x02, 0x30, 0x01, 0x32]).is_err());
+    }
+
+    #[test]
+    fn li100_requests() {
+        let mut li = interface(
+            Framing::Li100,
+            &[
+                0xF0, 0xF0, 0x21, 0x81, 0xA0, 0xE4, 0x13, 0x00, 0x03, 0x85, 0x71, 0x21, 0x81, 0xA1,
+            ],
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::Interface(InterfaceRequest::GetVersion)
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::XpressNet(DeviceMessage::TrackPowerOn)
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::XpressNet(DeviceMessage::LocoDrive(
+                Address::from(3),
+                Direction::Forward,
+                Speed::Steps128(10)
+            ))
+        );
+        assert!(matches!(li.read(), Err(Error::ParseError)));
+        assert!(matches!(li.read(), Err(Error::IOError)));
+    }
+
+    #[test]
+    fn li_usb_requests() {
+        let mut li = interface(
+            Framing::LiUsb,
+            &[
+                0xFF, 0xFE, 0xF0, 0xF0, 0x12, 0xFF, 0xFE, 0x21, 0x24, 0x05, 0xFF, 0xFE, 0xF2, 0x01,
+                0x05, 0xF6,
+            ],
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::Interface(InterfaceRequest::GetVersion)
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::XpressNet(DeviceMessage::GetState)
+        );
+        assert_eq!(
+            li.read().unwrap(),
+            Request::Interface(InterfaceRequest::Address(5))
+        );
+    }
+
+    #[test]
+    fn li_usb_replies() {
+        let mut li = interface(Framing::LiUsb, &[]);
+        li.reply(&InterfaceReply::Ok).unwrap();
+        li.write(&CentralMessage::State(CentralState::EMERGENCY_OFF))
+            .unwrap();
+        li.broadcast(&CentralMessage::<CentralState>::TrackPowerOff)
+            .unwrap();
+        assert_eq!(
+            li.release().tx,
+            vec![
+                0xFF, 0xFE, 0x01, 0x04, 0x05, 0xFF, 0xFE, 0x62, 0x22, 0x01, 0x41, 0xFF, 0xFD, 0x61,
+                0x00, 0x61,
+            ]
+        );
+    }
+
+    #[test]
+    fn li100_replies() {
+        let mut li = interface(Framing::Li100, &[]);
+        li.reply(&InterfaceReply::Version(0x30, 0x01)).unwrap();
+        li.broadcast(&CentralMessage::<CentralState>::TrackPowerOn)
+            .unwrap();
+        assert_eq!(
+            li.release().tx,
+            vec![0x02, 0x30, 0x01, 0x33, 0x61, 0x01, 0x60]
+        );
+    }
+}
@@ -1,7 +1,10 @@
+#![cfg_attr(not(test), no_std)]
+
 use bitflags::bitflags;
 #[cfg(feature = "z21")]
 use loco_core::functions::Function;
 use loco_core::{
+    add_xor,
     address::Address,
     drive::{Direction, Speed},
     functions::FunctionGroupNumber,
@@ -12,6 +15,12 @@ use log::debug;
 #[cfg(feature = "z21")]
 use num_traits::cast::FromPrimitive;
 
+pub mod bus;
+pub mod interface;
+
+#[cfg(test)]
+mod tests_mock;
+
 bitflags! {
     pub struct CentralState: u8 {
         const EMERGENCY_OFF = 0b0000_0001;
@@ -30,14 +39,53 @@ impl Bits<u8> for CentralState {
     }
 }
 
-#[derive(Debug, Clone)]
-#[allow(dead_code)] // FIXME: add tests and methods to use accessory
+impl From<u8> for CentralState {
+    #[inline]
+    fn from(bits: u8) -> Self {
+        Self::from_bits_truncate(bits)
+    }
+}
+
+/// Command for a turnout, addresses start at 0
+#[derive(Debug, Clone, PartialEq)]
 pub struct Accessory {
-    address: u8,
-    data: u8,
+    pub address: u16,
+    /// Switch output 2 (thrown) instead of output 1 (closed)
+    pub thrown: bool,
+    pub activate: bool,
+    /// Queue the command in a Z21 instead of sending it right away
+    pub queue: bool,
+}
+
+/// Position of a turnout as reported by the central
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TurnoutState {
+    NotSwitched,
+    Closed,
+    Thrown,
+    Invalid,
+}
+
+impl From<u8> for TurnoutState {
+    #[inline]
+    fn from(bits: u8) -> Self {
+        match bits & 0x03 {
+            0 => Self::NotSwitched,
+            1 => Self::Closed,
+            2 => Self::Thrown,
+            _ => Self::Invalid,
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+impl From<TurnoutState> for u8 {
+    #[inline]
+    fn from(state: TurnoutState) -> Self {
+        state as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum SearchResult {
     Loco(Address),
     DoubleHeading(Address),
@@ -46,37 +94,61 @@ pub enum SearchResult {
     None,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum CentralError {
-    ConsistError,
-    ConsistOccupied,
-    AlreadyInConsist,
-    ConsistSpeedNotZero,
-    LocoNotInConsist,
-    NoConsistBase,
-    DeleteNotPossible,
-    StackOverflow,
+    ConsistError = 0x81,
+    ConsistOccupied = 0x82,
+    AlreadyInConsist = 0x83,
+    ConsistSpeedNotZero = 0x84,
+    LocoNotInConsist = 0x85,
+    NoConsistBase = 0x86,
+    DeleteNotPossible = 0x87,
+    StackOverflow = 0x88,
 }
 
-#[derive(Debug, Clone)]
+impl CentralError {
+    pub fn from_byte(byte: u8) -> Option<CentralError> {
+        use CentralError::*;
+        match byte {
+            0x81 => Some(ConsistError),
+            0x82 => Some(ConsistOccupied),
+            0x83 => Some(AlreadyInConsist),
+            0x84 => Some(ConsistSpeedNotZero),
+            0x85 => Some(LocoNotInConsist),
+            0x86 => Some(NoConsistBase),
+            0x87 => Some(DeleteNotPossible),
+            0x88 => Some(StackOverflow),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        *self as u8
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum CentralMessage<S: Bits<u8>> {
     TrackPowerOn,
     TrackPowerOff,
     EmergencyStop,
     ProgrammingModeOn,
-    FeedbackBroadcast([(u8, u8); 7]),
+    /// Address and state of up to seven feedback or accessory decoders,
+    /// also the reply to `DeviceMessage::GetAccessory`
+    FeedbackBroadcast(heapless::Vec<(u8, u8), 7>),
     ProgrammingShortCircuit,
     ProgrammingNoData,
     ProgrammingBusy,
     ProgrammingReady,
+    /// Register or paged mode result
     ProgrammingDataPaged(u8, u8),
+    /// Direct mode result for a CV (1 to 1024)
     ProgrammingDataDirect(u16, u8),
     Version(u8, u8),
     State(S),
     TransferError,
     StationBusy,
     UnknownCommand,
-    AccessoryResponse(Accessory), // FIXME
     LocoInformation {
         is_free: bool,
         direction: Direction,
@@ -84,6 +156,7 @@ pub enum CentralMessage<S: Bits<u8>> {
         f0: FunctionGroupByte,
         f1: FunctionGroupByte,
     },
+    /// State of F13 to F28
     FunctionState {
         f3: FunctionGroupByte,
         f4: FunctionGroupByte,
@@ -110,10 +183,12 @@ pub enum CentralMessage<S: Bits<u8>> {
         other_address: Address,
     },
     LocoOccupied(Address),
+    /// Momentary functions of F0 to F12
     FunctionToggled0 {
         f0: FunctionGroupByte,
         f1: FunctionGroupByte,
     },
+    /// Momentary functions of F13 to F28
     FunctionToggled1 {
         f2: FunctionGroupByte,
         f3: FunctionGroupByte,
@@ -133,27 +208,164 @@ pub enum CentralMessage<S: Bits<u8>> {
         double_heading: bool,
         smart_search: bool,
     },
+    #[cfg(feature = "z21")]
+    Z21TurnoutInformation(u16, TurnoutState),
+    /// Value of a CV (1 to 1024) read or written in direct mode
+    #[cfg(feature = "z21")]
+    Z21ProgrammingDataDirect(u16, u8),
 }
 
 #[derive(Debug)]
 pub enum Error {
     ParseError,
+    IOError,
+    TimerError,
+    QueueFull,
+}
+
+/// Identification byte of a loco information with speed steps and busy flag
+#[inline]
+fn loco_id(kind: u8, is_free: bool, speed: &Speed) -> u8 {
+    let steps = match speed {
+        Speed::Steps14(_) => 0,
+        Speed::Steps28(_) => 2,
+        _ => 4,
+    };
+    kind | (!is_free as u8) << 3 | steps
+}
+
+/// Parse a speed byte with the speed steps of an identification byte
+#[inline]
+fn loco_speed(id: u8, byte: u8) -> Speed {
+    match id & 0x07 {
+        0 => Speed::from_byte_14_steps(byte),
+        // 27 speed steps are handled like 28 speed steps
+        1 | 2 => Speed::from_byte_28_steps(byte),
+        _ => Speed::from_byte_128_steps(byte),
+    }
 }
 
 impl<S: Bits<u8>> CentralMessage<S> {
     pub fn to_buf(&self, buf: &mut [u8]) -> usize {
-        #[cfg(feature = "z21")]
-        use loco_core::add_xor;
+        self.encode(buf, FIRST_LONG_ADDRESS)
+    }
+
+    /// Encode the message for the Z21 LAN protocol, which sends
+    /// addresses from 128 on as long addresses
+    #[cfg(feature = "z21")]
+    pub fn to_z21_buf(&self, buf: &mut [u8]) -> usize {
+        self.encode(buf, Z21_FIRST_LONG_ADDRESS)
+    }
+
+    fn encode(&self, buf: &mut [u8], first_long: u16) -> usize {
         use CentralMessage::*;
+        let rv =
+            |direction: &Direction, speed: &Speed| direction.to_advanced_byte() | speed.to_byte();
         match self {
             TrackPowerOn => mov!(buf[0..3] <- &xor!([0x61, 0x01])),
             TrackPowerOff => mov!(buf[0..3] <- &xor!([0x61, 0x00])),
             EmergencyStop => mov!(buf[0..3] <- &xor!([0x81, 0x00])),
+            ProgrammingModeOn => mov!(buf[0..3] <- &xor!([0x61, 0x02])),
+            FeedbackBroadcast(items) => {
+                buf[0] = 0x40 | (items.len() * 2) as u8;
+                for (i, (addr, data)) in items.iter().enumerate() {
+                    buf[1 + i * 2] = *addr;
+                    buf[2 + i * 2] = *data;
+                }
+                add_xor(buf, items.len() * 2 + 2)
+            }
+            ProgrammingShortCircuit => mov!(buf[0..3] <- &xor!([0x61, 0x12])),
+            ProgrammingNoData => mov!(buf[0..3] <- &xor!([0x61, 0x13])),
+            ProgrammingBusy => mov!(buf[0..3] <- &xor!([0x61, 0x1F])),
+            ProgrammingReady => mov!(buf[0..3] <- &xor!([0x61, 0x11])),
+            ProgrammingDataPaged(cv, value) => {
+                mov!(buf[0..5] <- &xor!([0x63, 0x10, *cv, *value]))
+            }
+            ProgrammingDataDirect(cv, value) => {
+                let [h, l] = cv.to_be_bytes();
+                mov!(buf[0..5] <- &xor!([0x63, 0x14 | (h & 0x03), l, *value]))
+            }
             Version(u, l) => mov!(buf[0..5] <- &xor!([0x63, 0x21, *u, *l])),
             State(state) => mov!(buf[0..4] <- &xor!([0x62, 0x22, state.bits()])),
             TransferError => mov!(buf[0..3] <- &xor!([0x61, 0x80])),
             StationBusy => mov!(buf[0..3] <- &xor!([0x61, 0x81])),
             UnknownCommand => mov!(buf[0..3] <- &xor!([0x61, 0x82])),
+            LocoInformation {
+                is_free,
+                direction,
+                speed,
+                f0,
+                f1,
+            } => {
+                let id = loco_id(0x00, *is_free, speed);
+                let (f0, f1) = (u8::from(*f0), u8::from(*f1));
+                mov!(buf[0..6] <- &xor!([0xE4, id, rv(direction, speed), f0, f1]))
+            }
+            FunctionState { f3, f4 } => {
+                mov!(buf[0..5] <- &xor!([0xE3, 0x52, u8::from(*f3), u8::from(*f4)]))
+            }
+            LocoConsistInformation {
+                is_free,
+                direction,
+                speed,
+                f0,
+                f1,
+                consist_address,
+            } => {
+                let id = loco_id(0x10, *is_free, speed);
+                let (f0, f1) = (u8::from(*f0), u8::from(*f1));
+                let speed = rv(direction, speed);
+                mov!(buf[0..7] <- &xor!([0xE5, id, speed, f0, f1, *consist_address]))
+            }
+            LocoConsistBaseInformation {
+                is_free,
+                direction,
+                speed,
+            } => {
+                let id = loco_id(0x20, *is_free, speed);
+                mov!(buf[0..4] <- &xor!([0xE2, id, rv(direction, speed)]))
+            }
+            LocoDoubleHeadingInformation {
+                is_free,
+                direction,
+                speed,
+                f0,
+                f1,
+                other_address,
+            } => {
+                let id = loco_id(0x60, *is_free, speed);
+                let (f0, f1) = (u8::from(*f0), u8::from(*f1));
+                let [h, l] = loco_address_bytes(other_address, first_long);
+                let speed = rv(direction, speed);
+                mov!(buf[0..8] <- &xor!([0xE6, id, speed, f0, f1, h, l]))
+            }
+            LocoOccupied(addr) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                mov!(buf[0..5] <- &xor!([0xE3, 0x40, h, l]))
+            }
+            FunctionToggled0 { f0, f1 } => {
+                mov!(buf[0..5] <- &xor!([0xE3, 0x50, u8::from(*f0), u8::from(*f1)]))
+            }
+            FunctionToggled1 { f2, f3 } => {
+                mov!(buf[0..5] <- &xor!([0xE3, 0x51, u8::from(*f2), u8::from(*f3)]))
+            }
+            SearchResult(result) => {
+                let (kind, [h, l]) = match result {
+                    self::SearchResult::Loco(addr) => (0x30, loco_address_bytes(addr, first_long)),
+                    self::SearchResult::DoubleHeading(addr) => {
+                        (0x31, loco_address_bytes(addr, first_long))
+                    }
+                    self::SearchResult::ConsistBase(addr) => {
+                        (0x32, loco_address_bytes(addr, first_long))
+                    }
+                    self::SearchResult::Consist(addr) => {
+                        (0x33, loco_address_bytes(addr, first_long))
+                    }
+                    self::SearchResult::None => (0x34, [0x00, 0x00]),
+                };
+                mov!(buf[0..5] <- &xor!([0xE3, kind, h, l]))
+            }
+            Error(error) => mov!(buf[0..3] <- &xor!([0xE1, error.to_byte()])),
             #[cfg(feature = "z21")]
             Z21LocoInformation {
                 loco_address,
@@ -168,15 +380,9 @@ impl<S: Bits<u8>> CentralMessage<S> {
                 smart_search,
             } => {
                 buf[0] = 0xEF;
-                mov!(buf[1..=2] <- &loco_address.num.to_le_bytes());
-                let code = match speed {
-                    Speed::Steps14(_) => 0,
-                    Speed::Steps28(_) => 2,
-                    Speed::Steps128(_) => 4,
-                    _ => 4,
-                };
-                buf[3] = (*is_free as u8) << 3 | code;
-                buf[4] = direction.to_advanced_byte() | speed.to_byte();
+                mov!(buf[1..=2] <- &loco_address_bytes(loco_address, first_long));
+                buf[3] = loco_id(0x00, *is_free, speed);
+                buf[4] = rv(direction, speed);
                 buf[5] = (u8::from(*f0) & 0x3F)
                     | ((*smart_search as u8) << 5)
                     | ((*double_heading as u8) << 6);
@@ -185,12 +391,177 @@ impl<S: Bits<u8>> CentralMessage<S> {
                 buf[8] = u8::from(*f3);
                 add_xor(buf, 10)
             }
-            _ => unimplemented!(),
+            #[cfg(feature = "z21")]
+            Z21TurnoutInformation(addr, state) => {
+                let [h, l] = addr.to_be_bytes();
+                mov!(buf[0..5] <- &xor!([0x43, h, l, u8::from(*state)]))
+            }
+            #[cfg(feature = "z21")]
+            Z21ProgrammingDataDirect(cv, value) => {
+                let [h, l] = cv_bytes(*cv);
+                mov!(buf[0..6] <- &xor!([0x64, 0x14, h, l, *value]))
+            }
+        }
+    }
+}
+
+impl<S: Bits<u8> + From<u8>> CentralMessage<S> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        let check_xor = |len: usize, result: Self| {
+            if bytes.len() < len {
+                return Err(crate::Error::ParseError);
+            }
+            let x = bytes[0..len - 1].iter().fold(0, |acc, x| acc ^ x);
+            if x != bytes[len - 1] {
+                Err(crate::Error::ParseError)
+            } else {
+                Ok(result)
+            }
+        };
+        let direction = |byte: u8| Direction::from_advanced_byte(byte);
+        use CentralMessage::*;
+        match bytes {
+            [0x61, 0x01, 0x60, ..] => Ok(TrackPowerOn),
+            [0x61, 0x00, 0x61, ..] => Ok(TrackPowerOff),
+            [0x61, 0x02, 0x63, ..] => Ok(ProgrammingModeOn),
+            [0x81, 0x00, 0x81, ..] => Ok(EmergencyStop),
+            [0x61, 0x11, 0x70, ..] => Ok(ProgrammingReady),
+            [0x61, 0x12, 0x73, ..] => Ok(ProgrammingShortCircuit),
+            [0x61, 0x13, 0x72, ..] => Ok(ProgrammingNoData),
+            [0x61, 0x1F, 0x7E, ..] => Ok(ProgrammingBusy),
+            [0x61, 0x80, 0xE1, ..] => Ok(TransferError),
+            [0x61, 0x81, 0xE0, ..] => Ok(StationBusy),
+            [0x61, 0x82, 0xE3, ..] => Ok(UnknownCommand),
+            [0x62, 0x22, state, ..] => check_xor(4, State(S::from(*state))),
+            [0x63, 0x10, cv, value, ..] => check_xor(5, ProgrammingDataPaged(*cv, *value)),
+            [0x63, id @ 0x14..=0x17, cv, value, ..] => {
+                check_xor(5, ProgrammingDataDirect(extended_cv(*id, *cv), *value))
+            }
+            [0x63, 0x21, u, l, ..] => check_xor(5, Version(*u, *l)),
+            [header, ..] if header & 0xF0 == 0x40 && header & 0x01 == 0 && *header != 0x40 => {
+                let len = (header & 0x0F) as usize + 2;
+                if bytes.len() < len {
+                    return Err(crate::Error::ParseError);
+                }
+                let items = bytes[1..len - 1].chunks(2).map(|c| (c[0], c[1]));
+                check_xor(len, FeedbackBroadcast(items.collect()))
+            }
+            [0xE1, code, ..] => match CentralError::from_byte(*code) {
+                Some(error) => check_xor(3, Error(error)),
+                None => Err(crate::Error::ParseError),
+            },
+            [0xE2, id, rv, ..] if id & 0xF0 == 0x20 => check_xor(
+                4,
+                LocoConsistBaseInformation {
+                    is_free: id & 0x08 == 0,
+                    direction: direction(*rv),
+                    speed: loco_speed(*id, *rv),
+                },
+            ),
+            [0xE3, kind @ 0x30..=0x34, h, l, ..] => {
+                let addr = loco_address(*h, *l);
+                let result = match kind {
+                    0x30 => self::SearchResult::Loco(addr),
+                    0x31 => self::SearchResult::DoubleHeading(addr),
+                    0x32 => self::SearchResult::ConsistBase(addr),
+                    0x33 => self::SearchResult::Consist(addr),
+                    _ => self::SearchResult::None,
+                };
+                check_xor(5, SearchResult(result))
+            }
+            [0xE3, 0x40, h, l, ..] => check_xor(5, LocoOccupied(loco_address(*h, *l))),
+            [0xE3, 0x50, f0, f1, ..] => check_xor(
+                5,
+                FunctionToggled0 {
+                    f0: (*f0).into(),
+                    f1: (*f1).into(),
+                },
+            ),
+            [0xE3, 0x51, f2, f3, ..] => check_xor(
+                5,
+                FunctionToggled1 {
+                    f2: (*f2).into(),
+                    f3: (*f3).into(),
+                },
+            ),
+            [0xE3, 0x52, f3, f4, ..] => check_xor(
+                5,
+                FunctionState {
+                    f3: (*f3).into(),
+                    f4: (*f4).into(),
+                },
+            ),
+            [0xE4, id, rv, f0, f1, ..] if id & 0xF0 == 0x00 => check_xor(
+                6,
+                LocoInformation {
+                    is_free: id & 0x08 == 0,
+                    direction: direction(*rv),
+                    speed: loco_speed(*id, *rv),
+                    f0: (*f0).into(),
+                    f1: (*f1).into(),
+                },
+            ),
+            [0xE5, id, rv, f0, f1, base, ..] if id & 0xF0 == 0x10 => check_xor(
+                7,
+                LocoConsistInformation {
+                    is_free: id & 0x08 == 0,
+                    direction: direction(*rv),
+                    speed: loco_speed(*id, *rv),
+                    f0: (*f0).into(),
+                    f1: (*f1).into(),
+                    consist_address: *base,
+                },
+            ),
+            [0xE6, id, rv, f0, f1, h, l, ..] if id & 0xF0 == 0x60 => check_xor(
+                8,
+                LocoDoubleHeadingInformation {
+                    is_free: id & 0x08 == 0,
+                    direction: direction(*rv),
+                    speed: loco_speed(*id, *rv),
+                    f0: (*f0).into(),
+                    f1: (*f1).into(),
+                    other_address: loco_address(*h, *l),
+                },
+            ),
+            #[cfg(feature = "z21")]
+            [0x43, h, l, zz, ..] => check_xor(
+                5,
+                Z21TurnoutInformation(u16::from_be_bytes([*h, *l]), TurnoutState::from(*zz)),
+            ),
+            #[cfg(feature = "z21")]
+            [0x64, 0x14, h, l, value, ..] => check_xor(
+                6,
+                Z21ProgrammingDataDirect(u16::from_be_bytes([*h & 0x03, *l]) + 1, *value),
+            ),
+            #[cfg(feature = "z21")]
+            [0xEF, h, l, db2, db3, db4, f1, f2, f3, _, ..] => {
+                // newer firmware sends more function bytes
+                check_xor(
+                    bytes.len(),
+                    Z21LocoInformation {
+                        loco_address: loco_address(*h, *l),
+                        is_free: db2 & 0x08 == 0,
+                        direction: direction(*db3),
+                        speed: loco_speed(*db2, *db3),
+                        f0: (db4 & 0x1F).into(),
+                        f1: (*f1).into(),
+                        f2: (*f2).into(),
+                        f3: (*f3).into(),
+                        double_heading: db4 & 0x40 == 0x40,
+                        smart_search: db4 & 0x20 == 0x20,
+                    },
+                )
+            }
+            _ => {
+                debug!("unknown message: {:#04X?}", bytes);
+                Err(crate::Error::ParseError)
+            }
         }
     }
 }
 
-#[derive(Debug, Clone)]
+/// Functions that are refreshed by the command station
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum RefreshMode {
     F0ToF4 = 0x0,
     F0ToF8 = 0x1,
@@ -199,8 +570,26 @@ pub enum RefreshMode {
     F0ToF28 = 0xF,
 }
 
+impl RefreshMode {
+    pub fn from_byte(byte: u8) -> Option<RefreshMode> {
+        use RefreshMode::*;
+        match byte {
+            0x0 => Some(F0ToF4),
+            0x1 => Some(F0ToF8),
+            0x3 => Some(F0ToF12),
+            0x7 => Some(F0ToF20),
+            0xF => Some(F0ToF28),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(&self) -> u8 {
+        *self as u8
+    }
+}
+
 #[cfg(feature = "z21")]
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum FunctionSwitch {
     On,
     Off,
@@ -227,32 +616,53 @@ impl FunctionSwitch {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DeviceMessage {
     TrackPowerOn,
     TrackPowerOff,
     EmergencyStop,
     LocoEmergencyStop(Address),
     ProgrammingReadRegister(u8),
+    /// Read a CV (1 to 1024) in direct mode
     ProgrammingReadDirect(u16),
+    /// Read a CV in paged mode, CV 256 is sent as 0
     ProgrammingReadPaged(u8),
     ProgrammingGetResult,
     ProgrammingWriteRegister(u8, u8),
+    /// Write a CV (1 to 1024) in direct mode
     ProgrammingWriteDirect(u16, u8),
+    /// Write a CV in paged mode, CV 256 is sent as 0
     ProgrammingWritePaged(u8, u8),
     GetVersion,
     GetState,
-    GetAccessory(Accessory),     // FIXME
-    ControlAccessory(Accessory), // FIXME
+    /// Request the state of the turnout pair holding the given turnout
+    GetAccessory(u16),
+    ControlAccessory(Accessory),
     GetLocoInformation(Address),
-    GetFunctionToggled0(u16),
-    GetFunctionToggled1(u16),
-    GetFunctionState(u16),
+    /// Request which functions of F0 to F12 are momentary
+    GetFunctionToggled0(Address),
+    /// Request which functions of F13 to F28 are momentary
+    GetFunctionToggled1(Address),
+    /// Request the state of F13 to F28
+    GetFunctionState(Address),
     LocoDrive(Address, Direction, Speed),
-    SetFunctionGroup(FunctionGroupNumber, FunctionGroupByte),
-    SetFunctionToggled(FunctionGroupNumber, FunctionGroupByte),
+    SetFunctionGroup(Address, FunctionGroupNumber, FunctionGroupByte),
+    /// Set which functions of a group are momentary
+    SetFunctionToggled(Address, ToggledGroup, FunctionGroupByte),
     #[cfg(feature = "z21")]
     Z21SetFunction(Address, FunctionSwitch, Function),
+    #[cfg(feature = "z21")]
+    Z21GetLocoInformation(Address),
+    #[cfg(feature = "z21")]
+    Z21GetTurnoutInformation(u16),
+    #[cfg(feature = "z21")]
+    Z21SetTurnout(Accessory),
+    /// Read a CV (1 to 1024) in direct mode
+    #[cfg(feature = "z21")]
+    Z21ProgrammingReadDirect(u16),
+    /// Write a CV (1 to 1024) in direct mode
+    #[cfg(feature = "z21")]
+    Z21ProgrammingWriteDirect(u16, u8),
     SetRefreshMode(RefreshMode),
     AddDoubleHeading(Address, Address),
     RemoveDoubleHeading(Address),
@@ -297,9 +707,73 @@ pub enum DeviceMessage {
     RemoveFromStack(Address),
 }
 
+/// First loco address sent as long address
+const FIRST_LONG_ADDRESS: u16 = 100;
+/// First loco address sent as long address by the Z21 LAN protocol
+#[cfg(feature = "z21")]
+const Z21_FIRST_LONG_ADDRESS: u16 = 128;
+
+/// Parse a loco address, long addresses have the upper two bits set
+#[inline]
+fn loco_address(h: u8, l: u8) -> Address {
+    Address::new(u16::from_be_bytes([h & 0x3F, l]))
+}
+
+/// Encode a loco address, addresses from `first_long` on are long addresses
+#[inline]
+fn loco_address_bytes(addr: &Address, first_long: u16) -> [u8; 2] {
+    let [h, l] = addr.num.to_be_bytes();
+    if addr.num >= first_long {
+        [h | 0xC0, l]
+    } else {
+        [h, l]
+    }
+}
+
+/// Function groups of F0 to F28, the only ones with momentary functions
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ToggledGroup {
+    G1 = 1,
+    G2,
+    G3,
+    G4,
+    G5,
+}
+
+/// Identification bytes to set the functions of a group
+const FUNCTION_GROUP_CODES: [(FunctionGroupNumber, u8); 10] = {
+    use FunctionGroupNumber::*;
+    [
+        (G1, 0x20),
+        (G2, 0x21),
+        (G3, 0x22),
+        (G4, 0x23),
+        (G5, 0x28),
+        (G6, 0x29),
+        (G7, 0x2A),
+        (G8, 0x2B),
+        (G9, 0x50),
+        (G10, 0x51),
+    ]
+};
+
+/// Identification bytes to set which functions of a group are momentary
+const TOGGLED_GROUP_CODES: [(ToggledGroup, u8); 5] = {
+    use ToggledGroup::*;
+    [(G1, 0x24), (G2, 0x25), (G3, 0x26), (G4, 0x27), (G5, 0x2C)]
+};
+
+/// Operations mode programming modes
+const POM_READ: u8 = 0xE4;
+const POM_WRITE_BIT: u8 = 0xE8;
+const POM_WRITE: u8 = 0xEC;
+
 impl DeviceMessage {
     pub fn from_bytes(bytes: &[u8]) -> Result<DeviceMessage, Error> {
         let check_xor = |len: usize, result: DeviceMessage| {
+            if bytes.len() < len {
+                return Err(Error::ParseError);
+            }
             let x = bytes[0..len - 1].iter().fold(0, |acc, x| acc ^ x);
             if x != bytes[len - 1] {
                 Err(Error::ParseError)
@@ -307,21 +781,83 @@ impl DeviceMessage {
                 Ok(result)
             }
         };
+        let group = |code: u8| FUNCTION_GROUP_CODES.iter().find(|g| g.1 == code);
+        let toggled = |code: u8| TOGGLED_GROUP_CODES.iter().find(|g| g.1 == code);
         use DeviceMessage::*;
         match bytes {
             [0x21, 0x81, 0xA0, ..] => Ok(TrackPowerOn),
             [0x21, 0x80, 0xA1, ..] => Ok(TrackPowerOff),
+            [0x21, 0x10, 0x31, ..] => Ok(ProgrammingGetResult),
             [0x80, 0x80, ..] => Ok(EmergencyStop),
-            [0x92, h, l, _, ..] => check_xor(
-                4,
-                LocoEmergencyStop(Address::new(u16::from_le_bytes([*l, *h]))),
-            ),
+            [0x92, h, l, _, ..] => check_xor(4, LocoEmergencyStop(loco_address(*h, *l))),
             [0x21, 0x21, 0x00, ..] => Ok(GetVersion),
             [0x21, 0x24, 0x05, ..] => Ok(GetState),
+            [0x22, 0x11, reg, _, ..] => check_xor(4, ProgrammingReadRegister(*reg)),
+            [0x22, 0x14, cv, _, ..] => check_xor(4, ProgrammingReadPaged(*cv)),
+            [0x22, 0x15, cv, _, ..] => {
+                let cv = if *cv == 0 { 256 } else { *cv as u16 };
+                check_xor(4, ProgrammingReadDirect(cv))
+            }
+            [0x22, id @ 0x18..=0x1B, cv, _, ..] => {
+                check_xor(4, ProgrammingReadDirect(extended_cv(*id, *cv)))
+            }
+            [0x22, 0x26, mode, _, ..] => match RefreshMode::from_byte(*mode) {
+                Some(mode) => check_xor(4, SetRefreshMode(mode)),
+                None => Err(Error::ParseError),
+            },
+            [0x23, 0x12, reg, value, _, ..] => check_xor(5, ProgrammingWriteRegister(*reg, *value)),
+            [0x23, 0x16, cv, value, _, ..] => {
+                let cv = if *cv == 0 { 256 } else { *cv as u16 };
+                check_xor(5, ProgrammingWriteDirect(cv, *value))
+            }
+            [0x23, 0x17, cv, value, _, ..] => check_xor(5, ProgrammingWritePaged(*cv, *value)),
+            [0x23, id @ 0x1C..=0x1F, cv, value, _, ..] => {
+                check_xor(5, ProgrammingWriteDirect(extended_cv(*id, *cv), *value))
+            }
+            [0x42, addr, nibble, _, ..] if nibble & 0xFE == 0x80 => check_xor(
+                4,
+                GetAccessory(*addr as u16 * 4 + (*nibble as u16 & 0x01) * 2),
+            ),
+            [0x52, addr, data, _, ..] if data & 0xF0 == 0x80 => check_xor(
+                4,
+                ControlAccessory(Accessory {
+                    address: *addr as u16 * 4 + (*data as u16 >> 1 & 0x03),
+                    thrown: data & 0x01 == 0x01,
+                    activate: data & 0x08 == 0x08,
+                    queue: false,
+                }),
+            ),
+            [0xE2, id @ (0x03 | 0x04), base, _, ..] => check_xor(
+                4,
+                SearchConsistBase {
+                    forward: *id == 0x03,
+                    base_address: *base,
+                },
+            ),
+            [0xE3, 0x00, h, l, _, ..] => check_xor(5, GetLocoInformation(loco_address(*h, *l))),
+            [0xE3, id @ (0x05 | 0x06), h, l, _, ..] => check_xor(
+                5,
+                SearchLocoInStack {
+                    forward: *id == 0x05,
+                    loco_address: loco_address(*h, *l),
+                },
+            ),
+            [0xE3, 0x07, h, l, _, ..] => check_xor(5, GetFunctionToggled0(loco_address(*h, *l))),
+            [0xE3, 0x08, h, l, _, ..] => check_xor(5, GetFunctionToggled1(loco_address(*h, *l))),
+            [0xE3, 0x09, h, l, _, ..] => check_xor(5, GetFunctionState(loco_address(*h, *l))),
+            [0xE3, 0x44, h, l, _, ..] => check_xor(5, RemoveFromStack(loco_address(*h, *l))),
+            [0xE4, id @ (0x01 | 0x02), base, h, l, _, ..] => check_xor(
+                6,
+                SearchConsistMember {
+                    forward: *id == 0x01,
+                    loco_address: loco_address(*h, *l),
+                    base_address: *base,
+                },
+            ),
             [0xE4, 0x10, h, l, rv, _, ..] => check_xor(
                 6,
                 LocoDrive(
-                    Address::new(u16::from_le_bytes([*l, *h])),
+                    loco_address(*h, *l),
                     Direction::from_advanced_byte(*rv),
                     Speed::from_byte_14_steps(*rv),
                 ),
@@ -329,7 +865,7 @@ impl DeviceMessage {
             [0xE4, 0x12, h, l, rv, _, ..] => check_xor(
                 6,
                 LocoDrive(
-                    Address::new(u16::from_le_bytes([*l, *h])),
+                    loco_address(*h, *l),
                     Direction::from_advanced_byte(*rv),
                     Speed::from_byte_28_steps(*rv),
                 ),
@@ -337,16 +873,102 @@ impl DeviceMessage {
             [0xE4, 0x13, h, l, rv, _, ..] => check_xor(
                 6,
                 LocoDrive(
-                    Address::new(u16::from_le_bytes([*l, *h])),
+                    loco_address(*h, *l),
                     Direction::from_advanced_byte(*rv),
                     Speed::from_byte_128_steps(*rv),
                 ),
             ),
+            [0xE4, id, h, l, data, _, ..] if group(*id).is_some() => {
+                let (group, ..) = group(*id).unwrap();
+                check_xor(
+                    6,
+                    SetFunctionGroup(loco_address(*h, *l), *group, (*data).into()),
+                )
+            }
+            [0xE4, id, h, l, data, _, ..] if toggled(*id).is_some() => {
+                let (group, ..) = toggled(*id).unwrap();
+                check_xor(
+                    6,
+                    SetFunctionToggled(loco_address(*h, *l), *group, (*data).into()),
+                )
+            }
+            [0xE4, id @ (0x40 | 0x41), h, l, base, _, ..] => check_xor(
+                6,
+                AddConsist {
+                    inverted: *id == 0x41,
+                    loco_address: loco_address(*h, *l),
+                    base_address: *base,
+                },
+            ),
+            [0xE4, 0x42, h, l, base, _, ..] => check_xor(
+                6,
+                RemoveConsist {
+                    loco_address: loco_address(*h, *l),
+                    base_address: *base,
+                },
+            ),
+            [0xE5, 0x43, h, l, 0x00, 0x00, _, ..] => {
+                check_xor(7, RemoveDoubleHeading(loco_address(*h, *l)))
+            }
+            [0xE5, 0x43, h1, l1, h2, l2, _, ..] => check_xor(
+                7,
+                AddDoubleHeading(loco_address(*h1, *l1), loco_address(*h2, *l2)),
+            ),
+            [0xE6, 0x30, h, l, mode, cv, value, _, ..] => {
+                let loco_address = loco_address(*h, *l);
+                let cv_address = u16::from_be_bytes([mode & 0x03, *cv]) + 1;
+                let msg = match mode & 0xFC {
+                    POM_READ => ProgrammingOnMainRead {
+                        loco_address,
+                        cv_address,
+                        value: *value,
+                    },
+                    POM_WRITE_BIT if value & 0xF0 == 0xF0 => ProgrammingOnMainWriteBit {
+                        loco_address,
+                        cv_address,
+                        position: value & 0x07,
+                        value: value & 0x08 == 0x08,
+                    },
+                    POM_WRITE => ProgrammingOnMainWrite {
+                        loco_address,
+                        cv_address,
+                        value: *value,
+                    },
+                    _ => return Err(Error::ParseError),
+                };
+                check_xor(8, msg)
+            }
             #[cfg(feature = "z21")]
-            [0xE4, 0xF8, h, l, b, _, ..] => check_xor(
+            [0xE3, 0xF0, h, l, _, ..] => check_xor(5, Z21GetLocoInformation(loco_address(*h, *l))),
+            #[cfg(feature = "z21")]
+            [0x43, h, l, _, ..] => {
+                check_xor(4, Z21GetTurnoutInformation(u16::from_be_bytes([*h, *l])))
+            }
+            #[cfg(feature = "z21")]
+            [0x53, h, l, db2, _, ..] => check_xor(
+                5,
+                Z21SetTurnout(Accessory {
+                    address: u16::from_be_bytes([*h, *l]),
+                    thrown: db2 & 0x01 == 0x01,
+                    activate: db2 & 0x08 == 0x08,
+                    queue: db2 & 0x20 == 0x20,
+                }),
+            ),
+            #[cfg(feature = "z21")]
+            [0x23, 0x11, h, l, _, ..] => check_xor(
+                5,
+                Z21ProgrammingReadDirect(u16::from_be_bytes([*h & 0x03, *l]) + 1),
+            ),
+            #[cfg(feature = "z21")]
+            [0x24, 0x12, h, l, value, _, ..] => check_xor(
+                6,
+                Z21ProgrammingWriteDirect(u16::from_be_bytes([*h & 0x03, *l]) + 1, *value),
+            ),
+            #[cfg(feature = "z21")]
+            [0xE4, 0xF8, h, l, b, _, ..] if b & 0xC0 != 0xC0 => check_xor(
                 6,
                 Z21SetFunction(
-                    Address::new(u16::from_le_bytes([*l, *h])),
+                    loco_address(*h, *l),
                     FunctionSwitch::from_byte(*b),
                     Function::from_u8(*b & 0x3F).unwrap(),
                 ),
@@ -357,4 +979,720 @@ impl DeviceMessage {
             }
         }
     }
+
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        self.encode(buf, FIRST_LONG_ADDRESS)
+    }
+
+    /// Encode the message for the Z21 LAN protocol, which sends
+    /// addresses from 128 on as long addresses
+    #[cfg(feature = "z21")]
+    pub fn to_z21_buf(&self, buf: &mut [u8]) -> usize {
+        self.encode(buf, Z21_FIRST_LONG_ADDRESS)
+    }
+
+    fn encode(&self, buf: &mut [u8], first_long: u16) -> usize {
+        use DeviceMessage::*;
+        match self {
+            TrackPowerOn => mov!(buf[0..3] <- &xor!([0x21, 0x81])),
+            TrackPowerOff => mov!(buf[0..3] <- &xor!([0x21, 0x80])),
+            EmergencyStop => mov!(buf[0..2] <- &[0x80, 0x80]),
+            LocoEmergencyStop(addr) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                mov!(buf[0..4] <- &xor!([0x92, h, l]))
+            }
+            ProgrammingReadRegister(reg) => mov!(buf[0..4] <- &xor!([0x22, 0x11, *reg])),
+            ProgrammingReadDirect(cv) if *cv <= 256 => {
+                mov!(buf[0..4] <- &xor!([0x22, 0x15, *cv as u8]))
+            }
+            ProgrammingReadDirect(cv) => {
+                let [h, l] = cv.to_be_bytes();
+                mov!(buf[0..4] <- &xor!([0x22, 0x18 | (h & 0x03), l]))
+            }
+            ProgrammingReadPaged(cv) => mov!(buf[0..4] <- &xor!([0x22, 0x14, *cv])),
+            ProgrammingGetResult => mov!(buf[0..3] <- &xor!([0x21, 0x10])),
+            ProgrammingWriteRegister(reg, value) => {
+                mov!(buf[0..5] <- &xor!([0x23, 0x12, *reg, *value]))
+            }
+            ProgrammingWriteDirect(cv, value) if *cv <= 256 => {
+                mov!(buf[0..5] <- &xor!([0x23, 0x16, *cv as u8, *value]))
+            }
+            ProgrammingWriteDirect(cv, value) => {
+                let [h, l] = cv.to_be_bytes();
+                mov!(buf[0..5] <- &xor!([0x23, 0x1C | (h & 0x03), l, *value]))
+            }
+            ProgrammingWritePaged(cv, value) => {
+                mov!(buf[0..5] <- &xor!([0x23, 0x17, *cv, *value]))
+            }
+            GetVersion => mov!(buf[0..3] <- &xor!([0x21, 0x21])),
+            GetState => mov!(buf[0..3] <- &xor!([0x21, 0x24])),
+            GetAccessory(addr) => {
+                let nibble = 0x80 | (addr >> 1 & 0x01) as u8;
+                mov!(buf[0..4] <- &xor!([0x42, (addr / 4) as u8, nibble]))
+            }
+            ControlAccessory(accessory) => {
+                let addr = accessory.address;
+                let data = 0x80
+                    | (accessory.activate as u8) << 3
+                    | ((addr & 0x03) as u8) << 1
+                    | accessory.thrown as u8;
+                mov!(buf[0..4] <- &xor!([0x52, (addr / 4) as u8, data]))
+            }
+            GetLocoInformation(addr) => loco_request(buf, first_long, 0x00, addr),
+            GetFunctionToggled0(addr) => loco_request(buf, first_long, 0x07, addr),
+            GetFunctionToggled1(addr) => loco_request(buf, first_long, 0x08, addr),
+            GetFunctionState(addr) => loco_request(buf, first_long, 0x09, addr),
+            LocoDrive(addr, direction, speed) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                let code = match speed {
+                    Speed::Steps14(_) => 0x10,
+                    Speed::Steps28(_) => 0x12,
+                    _ => 0x13,
+                };
+                let rv = direction.to_advanced_byte() | speed.to_byte();
+                mov!(buf[0..6] <- &xor!([0xE4, code, h, l, rv]))
+            }
+            SetFunctionGroup(addr, group, data) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                let (_, code) = FUNCTION_GROUP_CODES[*group as usize - 1];
+                mov!(buf[0..6] <- &xor!([0xE4, code, h, l, u8::from(*data)]))
+            }
+            SetFunctionToggled(addr, group, data) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                let (_, code) = TOGGLED_GROUP_CODES[*group as usize - 1];
+                mov!(buf[0..6] <- &xor!([0xE4, code, h, l, u8::from(*data)]))
+            }
+            #[cfg(feature = "z21")]
+            Z21SetFunction(addr, switch, function) => {
+                use num_traits::ToPrimitive;
+                let [h, l] = loco_address_bytes(addr, first_long);
+                let b = switch.to_byte() | function.to_u8().unwrap();
+                mov!(buf[0..6] <- &xor!([0xE4, 0xF8, h, l, b]))
+            }
+            #[cfg(feature = "z21")]
+            Z21GetLocoInformation(addr) => loco_request(buf, first_long, 0xF0, addr),
+            #[cfg(feature = "z21")]
+            Z21GetTurnoutInformation(addr) => {
+                let [h, l] = addr.to_be_bytes();
+                mov!(buf[0..4] <- &xor!([0x43, h, l]))
+            }
+            #[cfg(feature = "z21")]
+            Z21SetTurnout(accessory) => {
+                let [h, l] = accessory.address.to_be_bytes();
+                let db2 = 0x80
+                    | (accessory.queue as u8) << 5
+                    | (accessory.activate as u8) << 3
+                    | accessory.thrown as u8;
+                mov!(buf[0..5] <- &xor!([0x53, h, l, db2]))
+            }
+            #[cfg(feature = "z21")]
+            Z21ProgrammingReadDirect(cv) => {
+                let [h, l] = cv_bytes(*cv);
+                mov!(buf[0..5] <- &xor!([0x23, 0x11, h, l]))
+            }
+            #[cfg(feature = "z21")]
+            Z21ProgrammingWriteDirect(cv, value) => {
+                let [h, l] = cv_bytes(*cv);
+                mov!(buf[0..6] <- &xor!([0x24, 0x12, h, l, *value]))
+            }
+            SetRefreshMode(mode) => mov!(buf[0..4] <- &xor!([0x22, 0x26, mode.to_byte()])),
+            AddDoubleHeading(a, b) => {
+                let [h1, l1] = loco_address_bytes(a, first_long);
+                let [h2, l2] = loco_address_bytes(b, first_long);
+                mov!(buf[0..7] <- &xor!([0xE5, 0x43, h1, l1, h2, l2]))
+            }
+            RemoveDoubleHeading(addr) => {
+                let [h, l] = loco_address_bytes(addr, first_long);
+                mov!(buf[0..7] <- &xor!([0xE5, 0x43, h, l, 0x00, 0x00]))
+            }
+            ProgrammingOnMainWrite {
+                loco_address,
+                cv_address,
+                value,
+            } => pom_request(
+                buf,
+                first_long,
+                loco_address,
+                POM_WRITE,
+                *cv_address,
+                *value,
+            ),
+            ProgrammingOnMainRead {
+                loco_address,
+                cv_address,
+                value,
+            } => pom_request(buf, first_long, loco_address, POM_READ, *cv_address, *value),
+            ProgrammingOnMainWriteBit {
+                loco_address,
+                cv_address,
+                position,
+                value,
+            } => {
+                let data = 0xF0 | (*value as u8) << 3 | (position & 0x07);
+                pom_request(
+                    buf,
+                    first_long,
+                    loco_address,
+                    POM_WRITE_BIT,
+                    *cv_address,
+                    data,
+                )
+            }
+            AddConsist {
+                inverted,
+                loco_address,
+                base_address,
+            } => {
+                let [h, l] = loco_address_bytes(loco_address, first_long);
+                let id = 0x40 | *inverted as u8;
+                mov!(buf[0..6] <- &xor!([0xE4, id, h, l, *base_address]))
+            }
+            RemoveConsist {
+                loco_address,
+                base_address,
+            } => {
+                let [h, l] = loco_address_bytes(loco_address, first_long);
+                mov!(buf[0..6] <- &xor!([0xE4, 0x42, h, l, *base_address]))
+            }
+            SearchConsistMember {
+                forward,
+                loco_address,
+                base_address,
+            } => {
+                let [h, l] = loco_address_bytes(loco_address, first_long);
+                let id = if *forward { 0x01 } else { 0x02 };
+                mov!(buf[0..6] <- &xor!([0xE4, id, *base_address, h, l]))
+            }
+            SearchConsistBase {
+                forward,
+                base_address,
+            } => {
+                let id = if *forward { 0x03 } else { 0x04 };
+                mov!(buf[0..4] <- &xor!([0xE2, id, *base_address]))
+            }
+            SearchLocoInStack {
+                forward,
+                loco_address,
+            } => loco_request(
+                buf,
+                first_long,
+                if *forward { 0x05 } else { 0x06 },
+                loco_address,
+            ),
+            RemoveFromStack(addr) => loco_request(buf, first_long, 0x44, addr),
+        }
+    }
+}
+
+/// Parse a CV of the extended direct mode, CV 1024 is sent as 0
+#[inline]
+fn extended_cv(id: u8, cv: u8) -> u16 {
+    match u16::from_be_bytes([id & 0x03, cv]) {
+        0 => 1024,
+        cv => cv,
+    }
+}
+
+/// Write a request with a loco address as data
+fn loco_request(buf: &mut [u8], first_long: u16, id: u8, addr: &Address) -> usize {
+    let [h, l] = loco_address_bytes(addr, first_long);
+    mov!(buf[0..5] <- &xor!([0xE3, id, h, l]))
+}
+
+fn pom_request(
+    buf: &mut [u8],
+    first_long: u16,
+    addr: &Address,
+    mode: u8,
+    cv: u16,
+    data: u8,
+) -> usize {
+    let [h, l] = loco_address_bytes(addr, first_long);
+    let [cv_h, cv_l] = cv_bytes(cv);
+    mov!(buf[0..8] <- &xor!([0xE6, 0x30, h, l, mode | cv_h, cv_l, data]))
+}
+
+/// CV number (1 to 1024) sent as 10 bits starting at 0, others wrap around
+fn cv_bytes(cv: u16) -> [u8; 2] {
+    (cv.wrapping_sub(1) & 0x03FF).to_be_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loco_core::functions::FunctionGroupNumber::*;
+
+    /// Append the XOR byte to a message
+    fn with_xor(data: &[u8]) -> Vec<u8> {
+        let mut bytes = data.to_vec();
+        bytes.push(data.iter().fold(0, |acc, x| acc ^ x));
+        bytes
+    }
+
+    fn assert_round_trip(msg: DeviceMessage, bytes: &[u8]) {
+        let mut buf = [0; 16];
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], bytes, "{:?}", msg);
+        assert_eq!(DeviceMessage::from_bytes(bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn device_messages() {
+        use DeviceMessage::*;
+        let short = Address::new(3);
+        let long = Address::new(1234);
+        let messages = [
+            (TrackPowerOn, with_xor(&[0x21, 0x81])),
+            (TrackPowerOff, with_xor(&[0x21, 0x80])),
+            (EmergencyStop, vec![0x80, 0x80]),
+            (LocoEmergencyStop(long), with_xor(&[0x92, 0xC4, 0xD2])),
+            (ProgrammingReadRegister(2), with_xor(&[0x22, 0x11, 0x02])),
+            (ProgrammingReadDirect(29), with_xor(&[0x22, 0x15, 0x1D])),
+            (ProgrammingReadDirect(256), with_xor(&[0x22, 0x15, 0x00])),
+            (ProgrammingReadDirect(513), with_xor(&[0x22, 0x1A, 0x01])),
+            (ProgrammingReadDirect(1024), with_xor(&[0x22, 0x18, 0x00])),
+            (ProgrammingReadPaged(29), with_xor(&[0x22, 0x14, 0x1D])),
+            (ProgrammingGetResult, with_xor(&[0x21, 0x10])),
+            (
+                ProgrammingWriteRegister(2, 5),
+                with_xor(&[0x23, 0x12, 0x02, 0x05]),
+            ),
+            (
+                ProgrammingWriteDirect(29, 5),
+                with_xor(&[0x23, 0x16, 0x1D, 0x05]),
+            ),
+            (
+                ProgrammingWriteDirect(300, 5),
+                with_xor(&[0x23, 0x1D, 0x2C, 0x05]),
+            ),
+            (
+                ProgrammingWritePaged(29, 5),
+                with_xor(&[0x23, 0x17, 0x1D, 0x05]),
+            ),
+            (GetVersion, with_xor(&[0x21, 0x21])),
+            (GetState, with_xor(&[0x21, 0x24])),
+            (GetAccessory(22), with_xor(&[0x42, 0x05, 0x81])),
+            (
+                ControlAccessory(Accessory {
+                    address: 22,
+                    thrown: true,
+                    activate: true,
+                    queue: false,
+                }),
+                with_xor(&[0x52, 0x05, 0x8D]),
+            ),
+            (
+                GetLocoInformation(long),
+                with_xor(&[0xE3, 0x00, 0xC4, 0xD2]),
+            ),
+            (
+                GetFunctionToggled0(short),
+                with_xor(&[0xE3, 0x07, 0x00, 0x03]),
+            ),
+            (
+                GetFunctionToggled1(short),
+                with_xor(&[0xE3, 0x08, 0x00, 0x03]),
+            ),
+            (GetFunctionState(short), with_xor(&[0xE3, 0x09, 0x00, 0x03])),
+            (
+                LocoDrive(short, Direction::Forward, Speed::Steps14(5 * 16)),
+                with_xor(&[0xE4, 0x10, 0x00, 0x03, 0x85]),
+            ),
+            (
+                LocoDrive(short, Direction::Backward, Speed::Steps28(13 * 8)),
+                with_xor(&[0xE4, 0x12, 0x00, 0x03, 0x16]),
+            ),
+            (
+                LocoDrive(long, Direction::Forward, Speed::Steps128(254)),
+                with_xor(&[0xE4, 0x13, 0xC4, 0xD2, 0xFF]),
+            ),
+            (
+                SetFunctionGroup(short, G1, 0x11.into()),
+                with_xor(&[0xE4, 0x20, 0x00, 0x03, 0x11]),
+            ),
+            (
+                SetFunctionGroup(short, G5, 0x80.into()),
+                with_xor(&[0xE4, 0x28, 0x00, 0x03, 0x80]),
+            ),
+            (
+                SetFunctionGroup(short, G10, 0x01.into()),
+                with_xor(&[0xE4, 0x51, 0x00, 0x03, 0x01]),
+            ),
+            (
+                SetFunctionToggled(short, ToggledGroup::G2, 0x03.into()),
+                with_xor(&[0xE4, 0x25, 0x00, 0x03, 0x03]),
+            ),
+            (
+                SetFunctionToggled(short, ToggledGroup::G5, 0x03.into()),
+                with_xor(&[0xE4, 0x2C, 0x00, 0x03, 0x03]),
+            ),
+            (
+                SetRefreshMode(RefreshMode::F0ToF20),
+                with_xor(&[0x22, 0x26, 0x07]),
+            ),
+            (
+                AddDoubleHeading(short, long),
+                with_xor(&[0xE5, 0x43, 0x00, 0x03, 0xC4, 0xD2]),
+            ),
+            (
+                RemoveDoubleHeading(short),
+                with_xor(&[0xE5, 0x43, 0x00, 0x03, 0x00, 0x00]),
+            ),
+            (
+                ProgrammingOnMainWrite {
+                    loco_address: short,
+                    cv_address: 29,
+                    value: 5,
+                },
+                with_xor(&[0xE6, 0x30, 0x00, 0x03, 0xEC, 0x1C, 0x05]),
+            ),
+            (
+                ProgrammingOnMainRead {
+                    loco_address: short,
+                    cv_address: 1024,
+                    value: 0,
+                },
+                with_xor(&[0xE6, 0x30, 0x00, 0x03, 0xE7, 0xFF, 0x00]),
+            ),
+            (
+                ProgrammingOnMainWriteBit {
+                    loco_address: long,
+                    cv_address: 29,
+                    position: 5,
+                    value: true,
+                },
+                with_xor(&[0xE6, 0x30, 0xC4, 0xD2, 0xE8, 0x1C, 0xFD]),
+            ),
+            (
+                AddConsist {
+                    inverted: true,
+                    loco_address: short,
+                    base_address: 10,
+                },
+                with_xor(&[0xE4, 0x41, 0x00, 0x03, 0x0A]),
+            ),
+            (
+                RemoveConsist {
+                    loco_address: short,
+                    base_address: 10,
+                },
+                with_xor(&[0xE4, 0x42, 0x00, 0x03, 0x0A]),
+            ),
+            (
+                SearchConsistMember {
+                    forward: false,
+                    loco_address: short,
+                    base_address: 10,
+                },
+                with_xor(&[0xE4, 0x02, 0x0A, 0x00, 0x03]),
+            ),
+            (
+                SearchConsistBase {
+                    forward: true,
+                    base_address: 10,
+                },
+                with_xor(&[0xE2, 0x03, 0x0A]),
+            ),
+            (
+                SearchLocoInStack {
+                    forward: false,
+                    loco_address: long,
+                },
+                with_xor(&[0xE3, 0x06, 0xC4, 0xD2]),
+            ),
+            (RemoveFromStack(short), with_xor(&[0xE3, 0x44, 0x00, 0x03])),
+        ];
+        for (msg, bytes) in messages {
+            assert_round_trip(msg, &bytes);
+        }
+    }
+
+    #[cfg(feature = "z21")]
+    #[test]
+    fn z21_device_messages() {
+        use DeviceMessage::*;
+        let long = Address::new(1234);
+        let messages = [
+            (
+                Z21SetFunction(long, FunctionSwitch::Toggle, Function::F5),
+                with_xor(&[0xE4, 0xF8, 0xC4, 0xD2, 0x85]),
+            ),
+            (
+                Z21GetLocoInformation(long),
+                with_xor(&[0xE3, 0xF0, 0xC4, 0xD2]),
+            ),
+            (Z21GetTurnoutInformation(258), with_xor(&[0x43, 0x01, 0x02])),
+            (
+                Z21SetTurnout(Accessory {
+                    address: 258,
+                    thrown: false,
+                    activate: true,
+                    queue: true,
+                }),
+                with_xor(&[0x53, 0x01, 0x02, 0xA8]),
+            ),
+            (
+                Z21ProgrammingReadDirect(29),
+                with_xor(&[0x23, 0x11, 0x00, 0x1C]),
+            ),
+            (
+                Z21ProgrammingWriteDirect(1024, 5),
+                with_xor(&[0x24, 0x12, 0x03, 0xFF, 0x05]),
+            ),
+        ];
+        for (msg, bytes) in messages {
+            assert_round_trip(msg, &bytes);
+        }
+    }
+
+    // test that XpressNet sends addresses from 100 on as long
+    // addresses and the Z21 those from 128 on
+    #[test]
+    fn long_addresses() {
+        let mut buf = [0; 16];
+        let msg = DeviceMessage::LocoEmergencyStop(Address::new(100));
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], with_xor(&[0x92, 0xC0, 0x64]));
+        let msg = DeviceMessage::LocoEmergencyStop(Address::new(99));
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], with_xor(&[0x92, 0x00, 0x63]));
+        #[cfg(feature = "z21")]
+        {
+            let msg = DeviceMessage::LocoEmergencyStop(Address::new(127));
+            let len = msg.to_z21_buf(&mut buf);
+            assert_eq!(&buf[0..len], with_xor(&[0x92, 0x00, 0x7F]));
+            let msg = DeviceMessage::LocoEmergencyStop(Address::new(128));
+            let len = msg.to_z21_buf(&mut buf);
+            assert_eq!(&buf[0..len], with_xor(&[0x92, 0xC0, 0x80]));
+        }
+    }
+
+    // test that CV 0 wraps around instead of underflowing
+    #[test]
+    fn cv_out_of_range() {
+        let mut buf = [0; 16];
+        let msg = DeviceMessage::ProgrammingOnMainWrite {
+            loco_address: Address::new(3),
+            cv_address: 0,
+            value: 5,
+        };
+        let len = msg.to_buf(&mut buf);
+        let bytes = with_xor(&[0xE6, 0x30, 0x00, 0x03, 0xEF, 0xFF, 0x05]);
+        assert_eq!(&buf[0..len], bytes);
+        #[cfg(feature = "z21")]
+        {
+            let len = DeviceMessage::Z21ProgrammingReadDirect(0).to_buf(&mut buf);
+            assert_eq!(&buf[0..len], with_xor(&[0x23, 0x11, 0x03, 0xFF]));
+            let msg = CentralMessage::<CentralState>::Z21ProgrammingDataDirect(0, 5);
+            let len = msg.to_buf(&mut buf);
+            assert_eq!(&buf[0..len], with_xor(&[0x64, 0x14, 0x03, 0xFF, 0x05]));
+        }
+    }
+
+    // parse all messages starting with any three bytes, filled up
+    // with the third byte, and check that they are encoded to the
+    // same message
+    #[test]
+    fn parse_and_back() {
+        for a in 0x00..=0xFF {
+            let len = (a & 0x0F) as usize + 2;
+            for b in 0x00..=0xFF {
+                for c in 0x00..=0xFF {
+                    let mut buf = [c; 17];
+                    buf[0] = a;
+                    buf[1] = b;
+                    buf[len - 1] = buf[0..len - 1].iter().fold(0, |acc, x| acc ^ x);
+                    if let Ok(msg) = DeviceMessage::from_bytes(&buf[0..len]) {
+                        let mut buf2 = [0; 17];
+                        let len2 = msg.to_buf(&mut buf2);
+                        let msg2 = DeviceMessage::from_bytes(&buf2[0..len2]).unwrap();
+                        assert_eq!(msg2, msg);
+                    }
+                }
+            }
+        }
+    }
+
+    fn assert_central_round_trip(msg: CentralMessage<CentralState>, bytes: &[u8]) {
+        let mut buf = [0; 16];
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], bytes, "{:?}", msg);
+        assert_eq!(CentralMessage::from_bytes(bytes).unwrap(), msg);
+    }
+
+    #[test]
+    fn central_messages() {
+        use CentralMessage::*;
+        let items = heapless::Vec::from_slice(&[(0x05, 0x51), (0x06, 0x12)]).unwrap();
+        let messages = [
+            (TrackPowerOn, with_xor(&[0x61, 0x01])),
+            (TrackPowerOff, with_xor(&[0x61, 0x00])),
+            (EmergencyStop, with_xor(&[0x81, 0x00])),
+            (ProgrammingModeOn, with_xor(&[0x61, 0x02])),
+            (
+                FeedbackBroadcast(items),
+                with_xor(&[0x44, 0x05, 0x51, 0x06, 0x12]),
+            ),
+            (ProgrammingShortCircuit, with_xor(&[0x61, 0x12])),
+            (ProgrammingNoData, with_xor(&[0x61, 0x13])),
+            (ProgrammingBusy, with_xor(&[0x61, 0x1F])),
+            (ProgrammingReady, with_xor(&[0x61, 0x11])),
+            (
+                ProgrammingDataPaged(3, 5),
+                with_xor(&[0x63, 0x10, 0x03, 0x05]),
+            ),
+            (
+                ProgrammingDataDirect(29, 5),
+                with_xor(&[0x63, 0x14, 0x1D, 0x05]),
+            ),
+            (
+                ProgrammingDataDirect(1000, 5),
+                with_xor(&[0x63, 0x17, 0xE8, 0x05]),
+            ),
+            (Version(0x36, 0x00), with_xor(&[0x63, 0x21, 0x36, 0x00])),
+            (
+                State(CentralState::EMERGENCY_OFF),
+                with_xor(&[0x62, 0x22, 0x01]),
+            ),
+            (TransferError, with_xor(&[0x61, 0x80])),
+            (StationBusy, with_xor(&[0x61, 0x81])),
+            (UnknownCommand, with_xor(&[0x61, 0x82])),
+            (
+                LocoInformation {
+                    is_free: false,
+                    direction: Direction::Forward,
+                    speed: Speed::Steps128(20),
+                    f0: 0x11.into(),
+                    f1: 0x80.into(),
+                },
+                with_xor(&[0xE4, 0x0C, 0x8A, 0x11, 0x80]),
+            ),
+            (
+                FunctionState {
+                    f3: 0x01.into(),
+                    f4: 0x80.into(),
+                },
+                with_xor(&[0xE3, 0x52, 0x01, 0x80]),
+            ),
+            (
+                LocoConsistInformation {
+                    is_free: true,
+                    direction: Direction::Backward,
+                    speed: Speed::Steps28(13 * 8),
+                    f0: 0x00.into(),
+                    f1: 0x00.into(),
+                    consist_address: 10,
+                },
+                with_xor(&[0xE5, 0x12, 0x16, 0x00, 0x00, 0x0A]),
+            ),
+            (
+                LocoConsistBaseInformation {
+                    is_free: true,
+                    direction: Direction::Forward,
+                    speed: Speed::Steps14(5 * 16),
+                },
+                with_xor(&[0xE2, 0x20, 0x85]),
+            ),
+            (
+                LocoDoubleHeadingInformation {
+                    is_free: true,
+                    direction: Direction::Forward,
+                    speed: Speed::Stop,
+                    f0: 0x10.into(),
+                    f1: 0x00.into(),
+                    other_address: Address::new(1234),
+                },
+                with_xor(&[0xE6, 0x64, 0x80, 0x10, 0x00, 0xC4, 0xD2]),
+            ),
+            (
+                LocoOccupied(Address::new(3)),
+                with_xor(&[0xE3, 0x40, 0x00, 0x03]),
+            ),
+            (
+                FunctionToggled0 {
+                    f0: 0x1F.into(),
+                    f1: 0xFF.into(),
+                },
+                with_xor(&[0xE3, 0x50, 0x1F, 0xFF]),
+            ),
+            (
+                FunctionToggled1 {
+                    f2: 0x01.into(),
+                    f3: 0x02.into(),
+                },
+                with_xor(&[0xE3, 0x51, 0x01, 0x02]),
+            ),
+            (
+                SearchResult(super::SearchResult::Loco(Address::new(1234))),
+                with_xor(&[0xE3, 0x30, 0xC4, 0xD2]),
+            ),
+            (
+                SearchResult(super::SearchResult::Consist(Address::new(3))),
+                with_xor(&[0xE3, 0x33, 0x00, 0x03]),
+            ),
+            (
+                SearchResult(super::SearchResult::None),
+                with_xor(&[0xE3, 0x34, 0x00, 0x00]),
+            ),
+            (Error(CentralError::StackOverflow), with_xor(&[0xE1, 0x88])),
+        ];
+        for (msg, bytes) in messages {
+            assert_central_round_trip(msg, &bytes);
+        }
+    }
+
+    #[cfg(feature = "z21")]
+    #[test]
+    fn z21_central_messages() {
+        use CentralMessage::*;
+        assert_central_round_trip(
+            Z21TurnoutInformation(258, TurnoutState::Closed),
+            &with_xor(&[0x43, 0x01, 0x02, 0x01]),
+        );
+        assert_central_round_trip(
+            Z21ProgrammingDataDirect(29, 5),
+            &with_xor(&[0x64, 0x14, 0x00, 0x1C, 0x05]),
+        );
+    }
+
+    #[test]
+    fn central_parse_and_back() {
+        for a in 0x00..=0xFF {
+            let len = (a & 0x0F) as usize + 2;
+            for b in 0x00..=0xFF {
+                for c in 0x00..=0xFF {
+                    let mut buf = [c; 17];
+                    buf[0] = a;
+                    buf[1] = b;
+                    buf[len - 1] = buf[0..len - 1].iter().fold(0, |acc, x| acc ^ x);
+                    let msg = CentralMessage::<CentralState>::from_bytes(&buf[0..len]);
+                    if let Ok(msg) = msg {
+                        let mut buf2 = [0; 17];
+                        let len2 = msg.to_buf(&mut buf2);
+                        let msg2 = CentralMessage::from_bytes(&buf2[0..len2]).unwrap();
+                        assert_eq!(msg2, msg);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn parse_errors() {
+        // wrong XOR
+        assert!(DeviceMessage::from_bytes(&[0x21, 0x81, 0xA1]).is_err());
+        // truncated
+        assert!(DeviceMessage::from_bytes(&[0xE4, 0x13, 0x00, 0x03]).is_err());
+        // invalid refresh mode
+        assert!(DeviceMessage::from_bytes(&with_xor(&[0x22, 0x26, 0x02])).is_err());
+        // bit write without the fixed bits
+        let bytes = with_xor(&[0xE6, 0x30, 0x00, 0x03, 0xE8, 0x1C, 0x05]);
+        assert!(DeviceMessage::from_bytes(&bytes).is_err());
+        // unknown error code
+        let bytes = with_xor(&[0xE1, 0x89]);
+        assert!(CentralMessage::<CentralState>::from_bytes(&bytes).is_err());
+        // feedback broadcast without all pairs
+        let bytes = [0x46, 0x05, 0x51, 0x06, 0x12];
+        assert!(CentralMessage::<CentralState>::from_bytes(&bytes).is_err());
+    }
 }
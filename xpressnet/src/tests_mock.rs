@@ -0,0 +1,84 @@
+pub use embedded_hal::serial::nb::{Read, Write};
+pub use loco_mock::MockTimer;
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+use crate::bus::CALL_BIT;
+
+/// 9 bit UART recording all written words
+///
+/// Words queued for a slot are received after a call byte for that slot
+/// was written, matching the call type if given.
+#[derive(Default)]
+pub struct MockUart {
+    pub rx: VecDeque<u16>,
+    pub tx: Vec<u16>,
+    pub responses: Vec<(u16, Vec<u16>)>,
+}
+
+impl MockUart {
+    /// Respond with the bytes after the given call byte
+    pub fn respond(&mut self, call: u16, bytes: &[u8]) {
+        let words = bytes.iter().map(|b| *b as u16).collect();
+        self.responses.push((call, words));
+    }
+}
+
+impl Read<u16> for MockUart {
+    type Error = ();
+
+    fn read(&mut self) -> nb::Result<u16, ()> {
+        self.rx.pop_front().ok_or(nb::Error::WouldBlock)
+    }
+}
+
+impl Write<u16> for MockUart {
+    type Error = ();
+
+    fn write(&mut self, word: u16) -> nb::Result<(), ()> {
+        self.tx.push(word);
+        if word & CALL_BIT != 0 {
+            if let Some(i) = self.responses.iter().position(|(c, _)| *c == word) {
+                let (_, words) = self.responses.remove(i);
+                self.rx.extend(words);
+            }
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> nb::Result<(), ()> {
+        Ok(())
+    }
+}
+
+/// Serial port returning the received bytes in chunks of up to 3 bytes
+#[derive(Default)]
+pub struct MockSerial {
+    pub rx: VecDeque<u8>,
+    pub tx: Vec<u8>,
+}
+
+impl embedded_io::Io for MockSerial {
+    type Error = core::convert::Infallible;
+}
+
+impl embedded_io::blocking::Read for MockSerial {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let len = buf.len().min(self.rx.len()).min(3);
+        for (b, rx) in buf.iter_mut().zip(self.rx.drain(0..len)) {
+            *b = rx;
+        }
+        Ok(len)
+    }
+}
+
+impl embedded_io::blocking::Write for MockSerial {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.tx.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,47 @@
+use embedded_nal::{UdpClientStack, UdpFullStack};
+use loco_command_station::Station;
+use loco_dcc::writer::{Bit, Encoder};
+use loco_z21::Server;
+use log::{error, info};
+use std::time::{Duration, Instant};
+
+/// Encoder that drops all bits, taking roughly as long as writing them
+struct NullEncoder;
+
+impl Encoder for NullEncoder {
+    fn write(&mut self, _bit: &Bit) -> nb::Result<(), loco_dcc::Error> {
+        std::thread::sleep(Duration::from_micros(116));
+        Ok(())
+    }
+}
+
+fn main() {
+    use env_logger::Env;
+    use std_embedded_nal::Stack;
+
+    const PORT: u16 = 21105;
+
+    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+
+    let mut stack = Stack::default();
+    let mut sock = stack.socket().unwrap();
+    stack.bind(&mut sock, PORT).unwrap();
+
+    info!("listening on port {}", PORT);
+
+    let station: Station<_, 32> = Station::new(NullEncoder);
+    let mut server = Server::new(sock, station);
+    let mut second = Instant::now();
+    loop {
+        if second.elapsed() >= Duration::from_secs(1) {
+            second += Duration::from_secs(1);
+            server.tick();
+        }
+        match server.run(&mut stack) {
+            Err(nb::Error::Other(e)) => error!("{:?}", e),
+            Ok(()) | Err(nb::Error::WouldBlock) => {}
+        }
+        // the station only ever returns WouldBlock
+        let _ = server.station_mut().run();
+    }
+}
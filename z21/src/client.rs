@@ -0,0 +1,409 @@
+//! Z21 client controlling an existing central
+
+use embedded_nal::{SocketAddr, UdpClientStack};
+use loco_core::{
+    address::Address,
+    drive::{Direction, Speed},
+    functions::Function,
+};
+use loco_xpressnet as xnet;
+use log::{debug, trace};
+
+use crate::message::*;
+use crate::{Error, BUF_SIZE};
+
+/// A client of a Z21 central
+///
+/// The request methods only send a message. Replies and broadcasts of
+/// the central are read with `receive`, one message at a time.
+pub struct Client<S> {
+    socket: S,
+    recv_buf: [u8; BUF_SIZE],
+    recv_len: usize,
+    recv_pos: usize,
+    send_buf: [u8; BUF_SIZE],
+}
+
+impl<S> Client<S>
+where
+    S: Sized,
+{
+    /// Open a socket to the central at the given address
+    pub fn connect<U, EU>(stack: &mut U, remote: SocketAddr) -> Result<Self, Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let mut socket = stack.socket().map_err(|_| Error::Connect)?;
+        stack
+            .connect(&mut socket, remote)
+            .map_err(|_| Error::Connect)?;
+        Ok(Self {
+            socket,
+            recv_buf: [0; BUF_SIZE],
+            recv_len: 0,
+            recv_pos: 0,
+            send_buf: [0; BUF_SIZE],
+        })
+    }
+
+    /// Close the socket, without logging off at the central
+    pub fn close<U, EU>(self, stack: &mut U) -> Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        stack.close(self.socket).map_err(|_| Error::Connect)
+    }
+
+    pub fn send<U, EU>(&mut self, stack: &mut U, message: &ClientMessage) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let len = message.to_buf(&mut self.send_buf);
+        debug!("sending: {:?}", message);
+        trace!("{:#04X?}", &self.send_buf[0..len]);
+        stack
+            .send(&mut self.socket, &self.send_buf[0..len])
+            .map_err(|e| e.map(|_| Error::Send))
+    }
+
+    /// Receive the next message from the central
+    ///
+    /// A datagram may hold several messages, which are returned one
+    /// after another. Malformed datagrams are dropped.
+    pub fn receive<U, EU>(&mut self, stack: &mut U) -> nb::Result<CentralMessage, Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        if self.recv_pos >= self.recv_len {
+            let (num, _) = stack
+                .receive(&mut self.socket, &mut self.recv_buf)
+                .map_err(|e| e.map(|_| Error::Receive))?;
+            trace!("{:#04X?}", &self.recv_buf[0..num]);
+            self.recv_len = num;
+            self.recv_pos = 0;
+        }
+        let start = self.recv_pos;
+        let left = self.recv_len - start;
+        let len = if left >= 2 {
+            u16::from_le_bytes([self.recv_buf[start], self.recv_buf[start + 1]]) as usize
+        } else {
+            0
+        };
+        if len < 4 || len > left {
+            self.recv_pos = self.recv_len;
+            return Err(nb::Error::Other(Error::ParseCommand));
+        }
+        self.recv_pos += len;
+        let msg = CentralMessage::from_bytes(&self.recv_buf[start..start + len])?;
+        debug!("received: {:?}", msg);
+        Ok(msg)
+    }
+
+    fn send_xnet<U, EU>(
+        &mut self,
+        stack: &mut U,
+        message: xnet::DeviceMessage,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::XpressNet(message))
+    }
+
+    pub fn get_serial_number<U, EU>(&mut self, stack: &mut U) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::GetSerialNumber)
+    }
+
+    pub fn get_system_state<U, EU>(&mut self, stack: &mut U) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::GetSystemState)
+    }
+
+    /// Subscribe to broadcasts of the central
+    pub fn set_broadcast_flags<U, EU>(
+        &mut self,
+        stack: &mut U,
+        flags: BroadcastFlags,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::SetBroadcastFlags(flags))
+    }
+
+    /// Unregister from the central
+    pub fn logoff<U, EU>(&mut self, stack: &mut U) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::Logoff)
+    }
+
+    /// Request the R-BUS feedback of modules 1-10 (group 0) or 11-20 (group 1)
+    pub fn get_rbus_data<U, EU>(&mut self, stack: &mut U, group: u8) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::GetRBusData(group))
+    }
+
+    /// Set the address of the only R-BUS module connected, 0 ends programming
+    pub fn program_rbus_module<U, EU>(&mut self, stack: &mut U, addr: u8) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::ProgramRBusModule(addr))
+    }
+
+    /// Request the state of a LocoNet detector
+    ///
+    /// The kind is one of the `LOCONET_REQUEST_*` constants.
+    pub fn get_loconet_detector<U, EU>(
+        &mut self,
+        stack: &mut U,
+        kind: u8,
+        addr: u16,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::GetLocoNetDetector(kind, addr))
+    }
+
+    /// Request the state of all CAN detectors with the given network ID
+    pub fn get_can_detector<U, EU>(
+        &mut self,
+        stack: &mut U,
+        network_id: u16,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send(stack, &ClientMessage::GetCanDetector(network_id))
+    }
+
+    pub fn set_track_power<U, EU>(&mut self, stack: &mut U, on: bool) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        if on {
+            self.send_xnet(stack, xnet::DeviceMessage::TrackPowerOn)
+        } else {
+            self.send_xnet(stack, xnet::DeviceMessage::TrackPowerOff)
+        }
+    }
+
+    /// Stop all locos, keeping the track powered
+    pub fn emergency_stop<U, EU>(&mut self, stack: &mut U) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(stack, xnet::DeviceMessage::EmergencyStop)
+    }
+
+    /// Switch a turnout, addresses start at 0
+    ///
+    /// Without queue mode the central expects a deactivating command
+    /// after the activating one.
+    pub fn set_turnout<U, EU>(
+        &mut self,
+        stack: &mut U,
+        accessory: xnet::Accessory,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(stack, xnet::DeviceMessage::Z21SetTurnout(accessory))
+    }
+
+    /// Request the position of a turnout, addresses start at 0
+    pub fn get_turnout_info<U, EU>(&mut self, stack: &mut U, addr: u16) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(stack, xnet::DeviceMessage::Z21GetTurnoutInformation(addr))
+    }
+
+    /// Request the state of a loco and subscribe to its changes
+    pub fn get_loco_info<U, EU>(&mut self, stack: &mut U, addr: Address) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(stack, xnet::DeviceMessage::Z21GetLocoInformation(addr))
+    }
+
+    pub fn set_loco_drive<U, EU>(
+        &mut self,
+        stack: &mut U,
+        addr: Address,
+        direction: Direction,
+        speed: Speed,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(
+            stack,
+            xnet::DeviceMessage::LocoDrive(addr, direction, speed),
+        )
+    }
+
+    pub fn set_loco_function<U, EU>(
+        &mut self,
+        stack: &mut U,
+        addr: Address,
+        function: Function,
+        switch: xnet::FunctionSwitch,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(
+            stack,
+            xnet::DeviceMessage::Z21SetFunction(addr, switch, function),
+        )
+    }
+
+    /// Read a CV on the programming track
+    ///
+    /// The central answers with `Z21ProgrammingDataDirect` or `ProgrammingNoData`.
+    pub fn read_cv<U, EU>(&mut self, stack: &mut U, cv: u16) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(stack, xnet::DeviceMessage::Z21ProgrammingReadDirect(cv))
+    }
+
+    /// Write a CV on the programming track
+    pub fn write_cv<U, EU>(&mut self, stack: &mut U, cv: u16, value: u8) -> nb::Result<(), Error>
+    where
+        U: UdpClientStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_xnet(
+            stack,
+            xnet::DeviceMessage::Z21ProgrammingWriteDirect(cv, value),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+
+    fn setup() -> (Client<()>, MockStack) {
+        let mut stack = MockStack::default();
+        let z21 = Client::connect(&mut stack, client(100)).unwrap();
+        (z21, stack)
+    }
+
+    #[test]
+    fn requests() {
+        let (mut z21, mut stack) = setup();
+        let addr = Address::new(3);
+        z21.set_track_power(&mut stack, true).unwrap();
+        z21.get_loco_info(&mut stack, addr).unwrap();
+        z21.set_loco_drive(&mut stack, addr, Direction::Forward, Speed::Steps128(20))
+            .unwrap();
+        z21.set_loco_function(&mut stack, addr, Function::F5, xnet::FunctionSwitch::Toggle)
+            .unwrap();
+        z21.read_cv(&mut stack, 29).unwrap();
+        z21.write_cv(&mut stack, 29, 5).unwrap();
+        let accessory = xnet::Accessory {
+            address: 5,
+            thrown: true,
+            activate: true,
+            queue: true,
+        };
+        z21.set_turnout(&mut stack, accessory).unwrap();
+        z21.get_turnout_info(&mut stack, 5).unwrap();
+        z21.emergency_stop(&mut stack).unwrap();
+        z21.logoff(&mut stack).unwrap();
+        let expected: [&[u8]; 10] = [
+            &[0x07, 0x00, 0x40, 0x00, 0x21, 0x81, 0xA0],
+            &[0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0x00, 0x03, 0x10],
+            &[0x0A, 0x00, 0x40, 0x00, 0xE4, 0x13, 0x00, 0x03, 0x8A, 0x7E],
+            &[0x0A, 0x00, 0x40, 0x00, 0xE4, 0xF8, 0x00, 0x03, 0x85, 0x9A],
+            &[0x09, 0x00, 0x40, 0x00, 0x23, 0x11, 0x00, 0x1C, 0x2E],
+            &[0x0A, 0x00, 0x40, 0x00, 0x24, 0x12, 0x00, 0x1C, 0x05, 0x2F],
+            &[0x09, 0x00, 0x40, 0x00, 0x53, 0x00, 0x05, 0xA9, 0xFF],
+            &[0x08, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x46],
+            &[0x06, 0x00, 0x40, 0x00, 0x80, 0x80],
+            &[0x04, 0x00, 0x30, 0x00],
+        ];
+        assert_eq!(stack.sent.len(), expected.len());
+        for ((remote, data), expected) in stack.sent.iter().zip(expected) {
+            assert_eq!(*remote, client(100));
+            assert_eq!(&data[..], expected);
+        }
+    }
+
+    #[test]
+    fn receive_concatenated() {
+        let (mut z21, mut stack) = setup();
+        let mut datagram = vec![0x07, 0x00, 0x40, 0x00, 0x61, 0x00, 0x61];
+        datagram.extend_from_slice(&[0x08, 0x00, 0x10, 0x00, 0x01, 0xE5, 0x00, 0x00]);
+        stack.incoming.push_back((client(100), datagram));
+        let msg = z21.receive(&mut stack);
+        assert!(matches!(
+            msg,
+            Ok(CentralMessage::XpressNet(
+                xnet::CentralMessage::TrackPowerOff
+            ))
+        ));
+        let msg = z21.receive(&mut stack);
+        assert!(matches!(msg, Ok(CentralMessage::SerialNumber(58625))));
+        assert!(matches!(
+            z21.receive(&mut stack),
+            Err(nb::Error::WouldBlock)
+        ));
+    }
+
+    #[test]
+    fn receive_malformed() {
+        let (mut z21, mut stack) = setup();
+        // the length of the second message exceeds the datagram
+        let datagram = vec![0x07, 0x00, 0x40, 0x00, 0x61, 0x01, 0x60, 0x08, 0x00, 0x10];
+        stack.incoming.push_back((client(100), datagram));
+        stack
+            .incoming
+            .push_back((client(100), vec![0x07, 0x00, 0x40, 0x00, 0x61, 0x00, 0x61]));
+        assert!(z21.receive(&mut stack).is_ok());
+        let msg = z21.receive(&mut stack);
+        assert!(matches!(msg, Err(nb::Error::Other(Error::ParseCommand))));
+        let msg = z21.receive(&mut stack);
+        assert!(matches!(
+            msg,
+            Ok(CentralMessage::XpressNet(
+                xnet::CentralMessage::TrackPowerOff
+            ))
+        ));
+    }
+}
@@ -0,0 +1,193 @@
+//! Occupancy feedback from R-BUS, LocoNet and CAN detectors
+
+use loco_core::mov;
+
+/// Number of R-BUS feedback modules with eight inputs each
+pub const RBUS_MODULES: usize = 20;
+/// Number of R-BUS modules reported in one group
+pub const RBUS_GROUP_SIZE: usize = 10;
+
+/// LocoNet detector request for a stationary interrogate (Uhlenbrock)
+pub const LOCONET_REQUEST_SIC: u8 = 0x80;
+/// LocoNet detector request for a report (Digitrax, Uhlenbrock)
+pub const LOCONET_REQUEST_REPORT: u8 = 0x81;
+/// LocoNet detector request for a LISSY report
+pub const LOCONET_REQUEST_LISSY: u8 = 0x82;
+
+/// CAN detector occupancy report
+pub const CAN_OCCUPANCY: u8 = 0x01;
+
+/// State of a LocoNet detector, the first value is the feedback address
+#[derive(Debug, Clone, PartialEq)]
+pub enum LocoNetDetector {
+    /// Occupancy report (Uhlenbrock)
+    Occupancy(u16, bool),
+    /// Transponder with the given address entered the block
+    TransponderEnter(u16, u16),
+    /// Transponder with the given address left the block
+    TransponderExit(u16, u16),
+    /// LISSY loco address with class and direction info
+    LissyLocoAddress(u16, u16, u8),
+    /// LISSY block status
+    LissyBlockStatus(u16, u8),
+    /// LISSY speed
+    LissySpeed(u16, u16),
+}
+
+impl LocoNetDetector {
+    pub fn address(&self) -> u16 {
+        use LocoNetDetector::*;
+        match self {
+            Occupancy(a, _)
+            | TransponderEnter(a, _)
+            | TransponderExit(a, _)
+            | LissyLocoAddress(a, _, _)
+            | LissyBlockStatus(a, _)
+            | LissySpeed(a, _) => *a,
+        }
+    }
+
+    /// Parse type, feedback address and info bytes
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        use LocoNetDetector::*;
+        let addr = |a: &u8, b: &u8| u16::from_le_bytes([*a, *b]);
+        match bytes {
+            [0x01, a, b, info, ..] => Some(Occupancy(addr(a, b), *info != 0)),
+            [0x02, a, b, t0, t1, ..] => Some(TransponderEnter(addr(a, b), addr(t0, t1))),
+            [0x03, a, b, t0, t1, ..] => Some(TransponderExit(addr(a, b), addr(t0, t1))),
+            [0x10, a, b, l0, l1, info, ..] => {
+                Some(LissyLocoAddress(addr(a, b), addr(l0, l1), *info))
+            }
+            [0x11, a, b, status, ..] => Some(LissyBlockStatus(addr(a, b), *status)),
+            [0x12, a, b, s0, s1, ..] => Some(LissySpeed(addr(a, b), addr(s0, s1))),
+            _ => None,
+        }
+    }
+
+    /// Write type, feedback address and info bytes, returns the length
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use LocoNetDetector::*;
+        let (kind, info_len) = match self {
+            Occupancy(_, occupied) => {
+                buf[3] = *occupied as u8;
+                (0x01, 1)
+            }
+            TransponderEnter(_, t) => (0x02, mov!(buf[3..=4] <- &t.to_le_bytes())),
+            TransponderExit(_, t) => (0x03, mov!(buf[3..=4] <- &t.to_le_bytes())),
+            LissyLocoAddress(_, loco, info) => {
+                mov!(buf[3..=4] <- &loco.to_le_bytes());
+                buf[5] = *info;
+                (0x10, 3)
+            }
+            LissyBlockStatus(_, status) => {
+                buf[3] = *status;
+                (0x11, 1)
+            }
+            LissySpeed(_, speed) => (0x12, mov!(buf[3..=4] <- &speed.to_le_bytes())),
+        };
+        buf[0] = kind;
+        mov!(buf[1..=2] <- &self.address().to_le_bytes());
+        3 + info_len
+    }
+}
+
+/// Report of a CAN detector port
+///
+/// For occupancy reports (`CAN_OCCUPANCY`) the first value holds the
+/// state, for RailCom reports both values hold loco addresses.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CanDetector {
+    pub network_id: u16,
+    pub address: u16,
+    pub port: u8,
+    pub kind: u8,
+    pub value1: u16,
+    pub value2: u16,
+}
+
+impl CanDetector {
+    /// Check if an occupancy report reports an occupied block
+    pub fn is_occupied(&self) -> bool {
+        self.kind == CAN_OCCUPANCY && self.value1 & 0x1000 == 0x1000
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let le16 = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        if bytes.len() < 10 {
+            return None;
+        }
+        Some(Self {
+            network_id: le16(0),
+            address: le16(2),
+            port: bytes[4],
+            kind: bytes[5],
+            value1: le16(6),
+            value2: le16(8),
+        })
+    }
+
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        mov!(buf[0..=1] <- &self.network_id.to_le_bytes());
+        mov!(buf[2..=3] <- &self.address.to_le_bytes());
+        mov!(buf[4..=5] <- &[self.port, self.kind]);
+        mov!(buf[6..=7] <- &self.value1.to_le_bytes());
+        mov!(buf[8..=9] <- &self.value2.to_le_bytes());
+        10
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loconet_detector() {
+        use LocoNetDetector::*;
+        let reports: [(LocoNetDetector, &[u8]); 6] = [
+            (Occupancy(0x0102, true), &[0x01, 0x02, 0x01, 0x01]),
+            (TransponderEnter(3, 1234), &[0x02, 0x03, 0x00, 0xD2, 0x04]),
+            (TransponderExit(3, 1234), &[0x03, 0x03, 0x00, 0xD2, 0x04]),
+            (
+                LissyLocoAddress(5, 1234, 0x81),
+                &[0x10, 0x05, 0x00, 0xD2, 0x04, 0x81],
+            ),
+            (LissyBlockStatus(5, 0x01), &[0x11, 0x05, 0x00, 0x01]),
+            (LissySpeed(5, 300), &[0x12, 0x05, 0x00, 0x2C, 0x01]),
+        ];
+        for (detector, bytes) in reports {
+            let mut buf = [0; 8];
+            let len = detector.to_buf(&mut buf);
+            assert_eq!(&buf[0..len], bytes);
+            assert_eq!(LocoNetDetector::from_bytes(bytes), Some(detector));
+        }
+        assert_eq!(LocoNetDetector::from_bytes(&[0x04, 0x01, 0x00, 0x00]), None);
+        assert_eq!(LocoNetDetector::from_bytes(&[0x02, 0x01, 0x00, 0x00]), None);
+    }
+
+    #[test]
+    fn can_detector() {
+        let bytes = [0x01, 0xD0, 0x02, 0x00, 0x03, 0x01, 0x00, 0x11, 0x00, 0x00];
+        let detector = CanDetector::from_bytes(&bytes).unwrap();
+        assert_eq!(
+            detector,
+            CanDetector {
+                network_id: 0xD001,
+                address: 2,
+                port: 3,
+                kind: CAN_OCCUPANCY,
+                value1: 0x1100,
+                value2: 0,
+            }
+        );
+        assert!(detector.is_occupied());
+        let mut buf = [0; 10];
+        assert_eq!(detector.to_buf(&mut buf), 10);
+        assert_eq!(buf, bytes);
+        let free = CanDetector {
+            value1: 0x0100,
+            ..detector
+        };
+        assert!(!free.is_occupied());
+        assert_eq!(CanDetector::from_bytes(&bytes[0..9]), None);
+    }
+}
@@ -0,0 +1,35 @@
+#![cfg_attr(not(test), no_std)]
+
+use loco_xpressnet as xnet;
+
+pub mod client;
+pub mod feedback;
+pub mod message;
+pub mod server;
+
+#[cfg(test)]
+mod tests_mock;
+
+pub use client::Client;
+pub use server::Server;
+
+/// Size of the send and receive buffers
+const BUF_SIZE: usize = 64;
+
+#[derive(Debug)]
+pub enum Error {
+    Receive,
+    Send,
+    Bind,
+    Connect,
+    ParseCommand,
+    /// R-BUS module number out of range
+    InvalidModule,
+    XpressNet(xnet::Error),
+}
+
+impl From<xnet::Error> for Error {
+    fn from(e: xnet::Error) -> Error {
+        Error::XpressNet(e)
+    }
+}
@@ -0,0 +1,739 @@
+//! Messages of the Z21 LAN protocol
+
+use bitflags::bitflags;
+use loco_core::{mov, Bits};
+use loco_xpressnet as xnet;
+use log::debug;
+
+use crate::feedback::{self, RBUS_GROUP_SIZE};
+use crate::Error;
+
+bitflags! {
+    pub struct CentralStateEx: u8 {
+        const HIGH_TEMPERATURE = 0b0000_0001;
+        const POWER_LOST = 0b0000_0010;
+        const SHORT_CIRCUIT_EXTERNAL = 0b0000_0100;
+        const SHORT_CIRCUIT_INTERNAL = 0b0000_1000;
+    }
+}
+
+bitflags! {
+    pub struct CentralState: u8 {
+        const EMERGENCY_OFF = 0b0000_0001;
+        const EMERGENCY_STOP = 0b0000_0010;
+        const SHORT_CIRCUIT = 0b0000_0100;
+        const PROGRAMMING_MODE = 0b0010_0000;
+    }
+}
+
+impl Bits<u8> for CentralState {
+    #[inline]
+    fn bits(&self) -> u8 {
+        self.bits
+    }
+}
+
+impl From<u8> for CentralState {
+    #[inline]
+    fn from(bits: u8) -> Self {
+        Self::from_bits_truncate(bits)
+    }
+}
+
+bitflags! {
+    pub struct BroadcastFlags: u32 {
+        const DRIVING_SWITCHING = 0x00000001;
+        const RBUS = 0x00000002;
+        const RAILCOM = 0x00000004;
+        const SYSTEM_STATUS = 0x00000100;
+        const DRIVING_SWITCHING_ALL = 0x00010000;
+        const LOCONET = 0x01000000;
+        const LOCONET_LOCO = 0x02000000;
+        const LOCONET_SWITCH = 0x04000000;
+        const LOCONET_OCCUPY = 0x08000000;
+        const RAILCOM_ALL = 0x00040000;
+        const CAN_OCCUPY = 0x00080000;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum HardwareType {
+    Z21Old,
+    Z21New,
+    SmartRail,
+    Z21Small,
+    Z21Start,
+    Custom(u32),
+}
+
+impl HardwareType {
+    pub fn to_u32(&self) -> u32 {
+        use HardwareType::*;
+        match self {
+            Z21Old => 0x200,
+            Z21New => 0x201,
+            SmartRail => 0x202,
+            Z21Small => 0x203,
+            Z21Start => 0x204,
+            Custom(n) => *n,
+        }
+    }
+
+    pub fn from_u32(n: u32) -> Self {
+        use HardwareType::*;
+        match n {
+            0x200 => Z21Old,
+            0x201 => Z21New,
+            0x202 => SmartRail,
+            0x203 => Z21Small,
+            0x204 => Z21Start,
+            n => Custom(n),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+}
+
+#[derive(Debug, Clone)]
+pub enum CentralMessage {
+    HardwareInfo(HardwareType, FirmwareVersion),
+    SerialNumber(u32),
+    SystemState {
+        main_current: i16,
+        prog_current: i16,
+        filtered_main_current: i16,
+        temperature: i16,
+        supply_voltage: u16,
+        vcc_voltage: u16,
+        central_state: CentralState,
+        central_state_ex: CentralStateEx,
+    },
+    BroadcastFlags(BroadcastFlags),
+    /// R-BUS feedback of a group of ten modules
+    RBusDataChanged(u8, [u8; RBUS_GROUP_SIZE]),
+    LocoNetDetector(feedback::LocoNetDetector),
+    CanDetector(feedback::CanDetector),
+    XpressNet(xnet::CentralMessage<CentralState>),
+}
+
+impl CentralMessage {
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use CentralMessage::*;
+        let bcd = |n: u8| (n / 10) << 4 | (n % 10);
+        match self {
+            HardwareInfo(hardware, FirmwareVersion { major, minor }) => {
+                mov!(buf[0..=3] <- &[0x0C, 0x00, 0x1A, 0x00]);
+                mov!(buf[4..=7] <- &hardware.to_u32().to_le_bytes());
+                mov!(buf[8..=11] <- &[bcd(*minor), bcd(*major), 0x00, 0x00]);
+                12
+            }
+            SystemState {
+                main_current,
+                prog_current,
+                filtered_main_current,
+                temperature,
+                supply_voltage,
+                vcc_voltage,
+                central_state,
+                central_state_ex,
+            } => {
+                mov!(buf[0..=3] <- &[0x14, 0x00, 0x84, 0x00]);
+                mov!(buf[4..=5] <- &main_current.to_le_bytes());
+                mov!(buf[6..=7] <- &prog_current.to_le_bytes());
+                mov!(buf[8..=9] <- &filtered_main_current.to_le_bytes());
+                mov!(buf[10..=11] <- &temperature.to_le_bytes());
+                mov!(buf[12..=13] <- &supply_voltage.to_le_bytes());
+                mov!(buf[14..=15] <- &vcc_voltage.to_le_bytes());
+                mov!(buf[16..=19] <- &[central_state.bits, central_state_ex.bits, 0x00, 0x00]);
+                20
+            }
+            SerialNumber(num) => {
+                mov!(buf[0..=3] <- &[0x08, 0x00, 0x10, 0x00]);
+                mov!(buf[4..=7] <- &num.to_le_bytes());
+                8
+            }
+            BroadcastFlags(flags) => {
+                mov!(buf[0..=3] <- &[0x08, 0x00, 0x51, 0x00]);
+                mov!(buf[4..=7] <- &flags.bits.to_le_bytes());
+                8
+            }
+            RBusDataChanged(group, data) => {
+                buf[4] = *group;
+                mov!(buf[5..5 + RBUS_GROUP_SIZE] <- data);
+                frame(buf, [0x80, 0x00], 1 + RBUS_GROUP_SIZE)
+            }
+            LocoNetDetector(detector) => {
+                let len = detector.to_buf(&mut buf[4..]);
+                frame(buf, [0xA4, 0x00], len)
+            }
+            CanDetector(detector) => {
+                let len = detector.to_buf(&mut buf[4..]);
+                frame(buf, [0xC4, 0x00], len)
+            }
+            XpressNet(xmsg) => {
+                let xnum = xmsg.to_z21_buf(&mut buf[4..]);
+                frame(buf, [0x40, 0x00], xnum)
+            }
+        }
+    }
+}
+
+impl CentralMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use CentralMessage::*;
+        let le16 = |b: &[u8], i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        let le32 = |b: &[u8]| u32::from_le_bytes([b[0], b[1], b[2], b[3]]);
+        let bcd = |n: u8| (n >> 4) * 10 + (n & 0x0F);
+        match split(bytes)? {
+            ([0x40, 0x00], data) => Ok(XpressNet(xnet::CentralMessage::from_bytes(data)?)),
+            ([0x1A, 0x00], data) if data.len() >= 8 => Ok(HardwareInfo(
+                HardwareType::from_u32(le32(data)),
+                FirmwareVersion {
+                    major: bcd(data[5]),
+                    minor: bcd(data[4]),
+                },
+            )),
+            ([0x10, 0x00], data) if data.len() >= 4 => Ok(SerialNumber(le32(data))),
+            ([0x84, 0x00], data) if data.len() >= 14 => Ok(SystemState {
+                main_current: le16(data, 0) as i16,
+                prog_current: le16(data, 2) as i16,
+                filtered_main_current: le16(data, 4) as i16,
+                temperature: le16(data, 6) as i16,
+                supply_voltage: le16(data, 8),
+                vcc_voltage: le16(data, 10),
+                central_state: CentralState::from_bits_truncate(data[12]),
+                central_state_ex: CentralStateEx::from_bits_truncate(data[13]),
+            }),
+            ([0x51, 0x00], data) if data.len() >= 4 => Ok(BroadcastFlags(
+                self::BroadcastFlags::from_bits_truncate(le32(data)),
+            )),
+            ([0x80, 0x00], [group, data @ ..]) if data.len() >= RBUS_GROUP_SIZE => {
+                let mut modules = [0; RBUS_GROUP_SIZE];
+                modules.copy_from_slice(&data[0..RBUS_GROUP_SIZE]);
+                Ok(RBusDataChanged(*group, modules))
+            }
+            ([0xA4, 0x00], data) => feedback::LocoNetDetector::from_bytes(data)
+                .map(LocoNetDetector)
+                .ok_or(Error::ParseCommand),
+            ([0xC4, 0x00], data) => feedback::CanDetector::from_bytes(data)
+                .map(CanDetector)
+                .ok_or(Error::ParseCommand),
+            _ => {
+                debug!("Unknown: {:#04X?}", bytes);
+                Err(Error::ParseCommand)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum ClientMessage {
+    GetHardwareInfo,
+    GetSerialNumber,
+    GetSystemState,
+    GetBroadcastFlags,
+    SetBroadcastFlags(BroadcastFlags),
+    Logoff,
+    /// Request the R-BUS feedback of a group of ten modules
+    GetRBusData(u8),
+    /// Set the address of the only R-BUS module connected, 0 ends programming
+    ProgramRBusModule(u8),
+    /// Request the state of a LocoNet detector (type, address)
+    GetLocoNetDetector(u8, u16),
+    /// Request the state of all CAN detectors of a network ID
+    GetCanDetector(u16),
+    XpressNet(xnet::DeviceMessage),
+}
+
+/// Split a message into header and data
+fn split(bytes: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    if bytes.len() < 4 {
+        return Err(Error::ParseCommand);
+    }
+    let len = u16::from_le_bytes([bytes[0], bytes[1]]) as usize;
+    if len < 4 || len > bytes.len() {
+        return Err(Error::ParseCommand);
+    }
+    Ok((&bytes[2..4], &bytes[4..len]))
+}
+
+/// Write the length and header of a message, returns the message length
+fn frame(buf: &mut [u8], header: [u8; 2], data_len: usize) -> usize {
+    let len = 4 + data_len;
+    mov!(buf[0..=1] <- &(len as u16).to_le_bytes());
+    mov!(buf[2..=3] <- &header);
+    len
+}
+
+impl ClientMessage {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+        use ClientMessage::*;
+        match split(bytes)? {
+            ([0x40, 0x00], data) => Ok(XpressNet(xnet::DeviceMessage::from_bytes(data)?)),
+            ([0x85, 0x00], _) => Ok(GetSystemState),
+            ([0x10, 0x00], _) => Ok(GetSerialNumber),
+            ([0x1A, 0x00], _) => Ok(GetHardwareInfo),
+            ([0x50, 0x00], [a, b, c, d, ..]) => Ok(SetBroadcastFlags(
+                BroadcastFlags::from_bits_truncate(u32::from_le_bytes([*a, *b, *c, *d])),
+            )),
+            ([0x51, 0x00], _) => Ok(GetBroadcastFlags),
+            ([0x30, 0x00], _) => Ok(Logoff),
+            ([0x81, 0x00], [group, ..]) => Ok(GetRBusData(*group)),
+            ([0x82, 0x00], [addr, ..]) => Ok(ProgramRBusModule(*addr)),
+            ([0xA4, 0x00], [kind, a, b, ..]) => {
+                Ok(GetLocoNetDetector(*kind, u16::from_le_bytes([*a, *b])))
+            }
+            ([0xC4, 0x00], [0x00, a, b, ..]) => Ok(GetCanDetector(u16::from_le_bytes([*a, *b]))),
+            _ => {
+                debug!("Unknown: {:#04X?}", bytes);
+                Err(Error::ParseCommand)
+            }
+        }
+    }
+
+    pub fn to_buf(&self, buf: &mut [u8]) -> usize {
+        use ClientMessage::*;
+        match self {
+            GetHardwareInfo => frame(buf, [0x1A, 0x00], 0),
+            GetSerialNumber => frame(buf, [0x10, 0x00], 0),
+            GetSystemState => frame(buf, [0x85, 0x00], 0),
+            GetBroadcastFlags => frame(buf, [0x51, 0x00], 0),
+            SetBroadcastFlags(flags) => {
+                mov!(buf[4..=7] <- &flags.bits.to_le_bytes());
+                frame(buf, [0x50, 0x00], 4)
+            }
+            Logoff => frame(buf, [0x30, 0x00], 0),
+            GetRBusData(group) => {
+                buf[4] = *group;
+                frame(buf, [0x81, 0x00], 1)
+            }
+            ProgramRBusModule(addr) => {
+                buf[4] = *addr;
+                frame(buf, [0x82, 0x00], 1)
+            }
+            GetLocoNetDetector(kind, addr) => {
+                buf[4] = *kind;
+                mov!(buf[5..=6] <- &addr.to_le_bytes());
+                frame(buf, [0xA4, 0x00], 3)
+            }
+            GetCanDetector(network_id) => {
+                buf[4] = 0x00;
+                mov!(buf[5..=6] <- &network_id.to_le_bytes());
+                frame(buf, [0xC4, 0x00], 3)
+            }
+            XpressNet(xmsg) => {
+                let xnum = xmsg.to_z21_buf(&mut buf[4..]);
+                frame(buf, [0x40, 0x00], xnum)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use loco_core::address::Address;
+    use loco_core::drive::{Direction, Speed};
+    use loco_core::functions::Function;
+
+    fn encode(msg: CentralMessage) -> ([u8; 32], usize) {
+        let mut buf = [0; 32];
+        let len = msg.to_buf(&mut buf);
+        (buf, len)
+    }
+
+    #[test]
+    fn encode_hardware_info() {
+        let msg = CentralMessage::HardwareInfo(
+            HardwareType::Z21New,
+            FirmwareVersion {
+                major: 1,
+                minor: 33,
+            },
+        );
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[0..len],
+            &[0x0C, 0x00, 0x1A, 0x00, 0x01, 0x02, 0x00, 0x00, 0x33, 0x01, 0x00, 0x00]
+        );
+        let msg = CentralMessage::HardwareInfo(
+            HardwareType::Custom(0x12345678),
+            FirmwareVersion { major: 2, minor: 5 },
+        );
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[4..len],
+            &[0x78, 0x56, 0x34, 0x12, 0x05, 0x02, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encode_hardware_types() {
+        use HardwareType::*;
+        let types = [Z21Old, Z21New, SmartRail, Z21Small, Z21Start];
+        let codes: [u32; 5] = [0x200, 0x201, 0x202, 0x203, 0x204];
+        for (t, code) in types.iter().zip(codes) {
+            assert_eq!(t.to_u32(), code);
+        }
+    }
+
+    #[test]
+    fn encode_serial_number() {
+        let (buf, len) = encode(CentralMessage::SerialNumber(58625));
+        assert_eq!(
+            &buf[0..len],
+            &[0x08, 0x00, 0x10, 0x00, 0x01, 0xE5, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encode_system_state() {
+        let msg = CentralMessage::SystemState {
+            main_current: 1000,
+            prog_current: -1,
+            filtered_main_current: 990,
+            temperature: 35,
+            supply_voltage: 18000,
+            vcc_voltage: 17500,
+            central_state: CentralState::EMERGENCY_OFF,
+            central_state_ex: CentralStateEx::POWER_LOST,
+        };
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[0..len],
+            &[
+                0x14, 0x00, 0x84, 0x00, 0xE8, 0x03, 0xFF, 0xFF, 0xDE, 0x03, 0x23, 0x00, 0x50, 0x46,
+                0x5C, 0x44, 0x01, 0x02, 0x00, 0x00
+            ]
+        );
+    }
+
+    #[test]
+    fn encode_broadcast_flags() {
+        let flags = BroadcastFlags::DRIVING_SWITCHING | BroadcastFlags::SYSTEM_STATUS;
+        let (buf, len) = encode(CentralMessage::BroadcastFlags(flags));
+        assert_eq!(
+            &buf[0..len],
+            &[0x08, 0x00, 0x51, 0x00, 0x01, 0x01, 0x00, 0x00]
+        );
+    }
+
+    #[test]
+    fn encode_xpressnet() {
+        let msg = CentralMessage::XpressNet(xnet::CentralMessage::TrackPowerOn);
+        let (buf, len) = encode(msg);
+        assert_eq!(&buf[0..len], &[0x07, 0x00, 0x40, 0x00, 0x61, 0x01, 0x60]);
+        let msg = CentralMessage::XpressNet(xnet::CentralMessage::TrackPowerOff);
+        let (buf, len) = encode(msg);
+        assert_eq!(&buf[0..len], &[0x07, 0x00, 0x40, 0x00, 0x61, 0x00, 0x61]);
+        let msg =
+            CentralMessage::XpressNet(xnet::CentralMessage::State(CentralState::EMERGENCY_STOP));
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[0..len],
+            &[0x08, 0x00, 0x40, 0x00, 0x62, 0x22, 0x02, 0x42]
+        );
+        let msg = CentralMessage::XpressNet(xnet::CentralMessage::Version(0x30, 0x12));
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[0..len],
+            &[0x09, 0x00, 0x40, 0x00, 0x63, 0x21, 0x30, 0x12, 0x60]
+        );
+    }
+
+    #[test]
+    fn encode_loco_information() {
+        let msg = CentralMessage::XpressNet(xnet::CentralMessage::Z21LocoInformation {
+            loco_address: Address::new(1234),
+            is_free: true,
+            direction: Direction::Forward,
+            speed: Speed::Steps128(20),
+            f0: 0x11.into(),
+            f1: 0x80.into(),
+            f2: 0x00.into(),
+            f3: 0x01.into(),
+            double_heading: false,
+            smart_search: false,
+        });
+        let (buf, len) = encode(msg);
+        assert_eq!(
+            &buf[0..len],
+            &[0x0E, 0x00, 0x40, 0x00, 0xEF, 0xC4, 0xD2, 0x04, 0x8A, 0x11, 0x80, 0x00, 0x01, 0xE7]
+        );
+    }
+
+    #[test]
+    fn decode_lan_messages() {
+        use ClientMessage::*;
+        let msg = ClientMessage::from_bytes(&[0x04, 0x00, 0x10, 0x00]);
+        assert!(matches!(msg, Ok(GetSerialNumber)));
+        let msg = ClientMessage::from_bytes(&[0x04, 0x00, 0x1A, 0x00]);
+        assert!(matches!(msg, Ok(GetHardwareInfo)));
+        let msg = ClientMessage::from_bytes(&[0x04, 0x00, 0x85, 0x00]);
+        assert!(matches!(msg, Ok(GetSystemState)));
+        let msg = ClientMessage::from_bytes(&[0x04, 0x00, 0x51, 0x00]);
+        assert!(matches!(msg, Ok(GetBroadcastFlags)));
+        let msg = ClientMessage::from_bytes(&[0x04, 0x00, 0x30, 0x00]);
+        assert!(matches!(msg, Ok(Logoff)));
+        let msg = ClientMessage::from_bytes(&[0x08, 0x00, 0x50, 0x00, 0x01, 0x01, 0x01, 0x00]);
+        let flags = BroadcastFlags::DRIVING_SWITCHING
+            | BroadcastFlags::SYSTEM_STATUS
+            | BroadcastFlags::DRIVING_SWITCHING_ALL;
+        assert!(matches!(msg, Ok(SetBroadcastFlags(f)) if f == flags));
+    }
+
+    #[test]
+    fn decode_xpressnet_messages() {
+        use xnet::DeviceMessage::*;
+        use ClientMessage::XpressNet;
+        let decode = |bytes: &[u8]| ClientMessage::from_bytes(bytes);
+        let msg = decode(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x21, 0x00]);
+        assert!(matches!(msg, Ok(XpressNet(GetVersion))));
+        let msg = decode(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x24, 0x05]);
+        assert!(matches!(msg, Ok(XpressNet(GetState))));
+        let msg = decode(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x81, 0xA0]);
+        assert!(matches!(msg, Ok(XpressNet(TrackPowerOn))));
+        let msg = decode(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x80, 0xA1]);
+        assert!(matches!(msg, Ok(XpressNet(TrackPowerOff))));
+        let msg = decode(&[0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0xC4, 0xD2, 0x05]);
+        assert!(matches!(
+            msg,
+            Ok(XpressNet(Z21GetLocoInformation(addr))) if addr == Address::new(1234)
+        ));
+        let msg = decode(&[0x0A, 0x00, 0x40, 0x00, 0xE4, 0x13, 0x00, 0x03, 0x8A, 0x7E]);
+        assert!(matches!(
+            msg,
+            Ok(XpressNet(LocoDrive(addr, Direction::Forward, Speed::Steps128(20))))
+                if addr == Address::new(3)
+        ));
+        let msg = decode(&[0x0A, 0x00, 0x40, 0x00, 0xE4, 0xF8, 0x00, 0x03, 0x85, 0x9A]);
+        assert!(matches!(
+            msg,
+            Ok(XpressNet(Z21SetFunction(addr, xnet::FunctionSwitch::Toggle, Function::F5)))
+                if addr == Address::new(3)
+        ));
+        let msg = decode(&[0x09, 0x00, 0x40, 0x00, 0x53, 0x01, 0x02, 0x88, 0xD8]);
+        let closed = xnet::Accessory {
+            address: 258,
+            thrown: false,
+            activate: true,
+            queue: false,
+        };
+        assert!(matches!(msg, Ok(XpressNet(Z21SetTurnout(a))) if a == closed));
+        let msg = decode(&[0x08, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x46]);
+        assert!(matches!(msg, Ok(XpressNet(Z21GetTurnoutInformation(5)))));
+    }
+
+    #[test]
+    fn decode_errors() {
+        // too short for a header
+        assert!(ClientMessage::from_bytes(&[0x04, 0x00, 0x10]).is_err());
+        // length exceeds the datagram
+        assert!(ClientMessage::from_bytes(&[0x08, 0x00, 0x10, 0x00]).is_err());
+        // length below the header size
+        assert!(ClientMessage::from_bytes(&[0x02, 0x00, 0x10, 0x00]).is_err());
+        // unknown header
+        assert!(ClientMessage::from_bytes(&[0x04, 0x00, 0xFF, 0x00]).is_err());
+        // wrong XpressNet checksum
+        let msg =
+            ClientMessage::from_bytes(&[0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0x00, 0x03, 0x00]);
+        assert!(matches!(msg, Err(Error::XpressNet(_))));
+    }
+
+    /// Decode a message and check that encoding it yields the same bytes
+    fn central_round_trip(bytes: &[u8]) -> CentralMessage {
+        let msg = CentralMessage::from_bytes(bytes).unwrap();
+        let (buf, len) = encode(msg.clone());
+        assert_eq!(&buf[0..len], bytes);
+        msg
+    }
+
+    fn client_round_trip(bytes: &[u8]) -> ClientMessage {
+        let msg = ClientMessage::from_bytes(bytes).unwrap();
+        let mut buf = [0; 32];
+        let len = msg.to_buf(&mut buf);
+        assert_eq!(&buf[0..len], bytes);
+        msg
+    }
+
+    #[test]
+    fn decode_central_messages() {
+        use CentralMessage::*;
+        let msg = central_round_trip(&[
+            0x0C, 0x00, 0x1A, 0x00, 0x01, 0x02, 0x00, 0x00, 0x33, 0x01, 0x00, 0x00,
+        ]);
+        assert!(matches!(
+            msg,
+            HardwareInfo(
+                HardwareType::Z21New,
+                FirmwareVersion {
+                    major: 1,
+                    minor: 33
+                }
+            )
+        ));
+        let msg = central_round_trip(&[0x08, 0x00, 0x10, 0x00, 0x01, 0xE5, 0x00, 0x00]);
+        assert!(matches!(msg, SerialNumber(58625)));
+        let msg = central_round_trip(&[0x08, 0x00, 0x51, 0x00, 0x01, 0x00, 0x01, 0x00]);
+        let flags =
+            super::BroadcastFlags::DRIVING_SWITCHING | super::BroadcastFlags::DRIVING_SWITCHING_ALL;
+        assert!(matches!(msg, BroadcastFlags(f) if f == flags));
+        let msg = central_round_trip(&[
+            0x14, 0x00, 0x84, 0x00, 0xE8, 0x03, 0xFF, 0xFF, 0xDE, 0x03, 0x23, 0x00, 0x50, 0x46,
+            0x5C, 0x44, 0x01, 0x02, 0x00, 0x00,
+        ]);
+        assert!(matches!(
+            msg,
+            SystemState {
+                main_current: 1000,
+                prog_current: -1,
+                temperature: 35,
+                supply_voltage: 18000,
+                central_state: CentralState::EMERGENCY_OFF,
+                central_state_ex: CentralStateEx::POWER_LOST,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn decode_central_xpressnet_messages() {
+        use xnet::CentralMessage::*;
+        use CentralMessage::XpressNet;
+        let msg = central_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x61, 0x01, 0x60]);
+        assert!(matches!(msg, XpressNet(TrackPowerOn)));
+        let msg = central_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x61, 0x00, 0x61]);
+        assert!(matches!(msg, XpressNet(TrackPowerOff)));
+        let msg = central_round_trip(&[0x08, 0x00, 0x40, 0x00, 0x62, 0x22, 0x02, 0x42]);
+        assert!(matches!(msg, XpressNet(State(s)) if s == CentralState::EMERGENCY_STOP));
+        let msg = central_round_trip(&[0x09, 0x00, 0x40, 0x00, 0x63, 0x21, 0x30, 0x12, 0x60]);
+        assert!(matches!(msg, XpressNet(Version(0x30, 0x12))));
+        let msg = central_round_trip(&[0x09, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x02, 0x44]);
+        assert!(matches!(
+            msg,
+            XpressNet(Z21TurnoutInformation(5, xnet::TurnoutState::Thrown))
+        ));
+        let msg = central_round_trip(&[
+            0x0E, 0x00, 0x40, 0x00, 0xEF, 0xC4, 0xD2, 0x04, 0x8A, 0x11, 0x80, 0x00, 0x01, 0xE7,
+        ]);
+        match msg {
+            XpressNet(Z21LocoInformation {
+                loco_address,
+                is_free,
+                direction,
+                speed,
+                f0,
+                f1,
+                ..
+            }) => {
+                assert_eq!(loco_address, Address::new(1234));
+                assert!(is_free);
+                assert_eq!(direction, Direction::Forward);
+                assert_eq!(speed, Speed::Steps128(20));
+                assert!(f0.get(Function::F0) && f0.get(Function::F1));
+                assert!(f1.get(Function::F12));
+            }
+            msg => panic!("unexpected message {:?}", msg),
+        }
+        // newer firmware sends additional function bytes
+        let msg = CentralMessage::from_bytes(&[
+            0x0F, 0x00, 0x40, 0x00, 0xEF, 0x00, 0x03, 0x04, 0x8A, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x62,
+        ]);
+        assert!(matches!(msg, Ok(XpressNet(Z21LocoInformation { .. }))));
+        let msg = CentralMessage::from_bytes(&[0x07, 0x00, 0x40, 0x00, 0x61, 0x13, 0x72]);
+        assert!(matches!(msg, Ok(XpressNet(ProgrammingNoData))));
+        let msg = CentralMessage::from_bytes(&[
+            0x0A, 0x00, 0x40, 0x00, 0x64, 0x14, 0x00, 0x1C, 0x05, 0x69,
+        ]);
+        assert!(matches!(
+            msg,
+            Ok(XpressNet(Z21ProgrammingDataDirect(29, 5)))
+        ));
+    }
+
+    #[test]
+    fn encode_client_messages() {
+        client_round_trip(&[0x04, 0x00, 0x10, 0x00]);
+        client_round_trip(&[0x04, 0x00, 0x1A, 0x00]);
+        client_round_trip(&[0x04, 0x00, 0x85, 0x00]);
+        client_round_trip(&[0x04, 0x00, 0x51, 0x00]);
+        client_round_trip(&[0x04, 0x00, 0x30, 0x00]);
+        client_round_trip(&[0x08, 0x00, 0x50, 0x00, 0x01, 0x01, 0x01, 0x00]);
+        client_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x21, 0x00]);
+        client_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x24, 0x05]);
+        client_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x81, 0xA0]);
+        client_round_trip(&[0x07, 0x00, 0x40, 0x00, 0x21, 0x80, 0xA1]);
+        client_round_trip(&[0x06, 0x00, 0x40, 0x00, 0x80, 0x80]);
+        client_round_trip(&[0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0xC4, 0xD2, 0x05]);
+        client_round_trip(&[0x0A, 0x00, 0x40, 0x00, 0xE4, 0x13, 0x00, 0x03, 0x8A, 0x7E]);
+        client_round_trip(&[0x0A, 0x00, 0x40, 0x00, 0xE4, 0xF8, 0x00, 0x03, 0x85, 0x9A]);
+        client_round_trip(&[0x09, 0x00, 0x40, 0x00, 0x53, 0x00, 0x05, 0xA9, 0xFF]);
+        client_round_trip(&[0x08, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x46]);
+        let msg = client_round_trip(&[0x09, 0x00, 0x40, 0x00, 0x23, 0x11, 0x00, 0x1C, 0x2E]);
+        assert!(matches!(
+            msg,
+            ClientMessage::XpressNet(xnet::DeviceMessage::Z21ProgrammingReadDirect(29))
+        ));
+        let msg = client_round_trip(&[0x0A, 0x00, 0x40, 0x00, 0x24, 0x12, 0x00, 0x1C, 0x05, 0x2F]);
+        assert!(matches!(
+            msg,
+            ClientMessage::XpressNet(xnet::DeviceMessage::Z21ProgrammingWriteDirect(29, 5))
+        ));
+    }
+
+    #[test]
+    fn decode_central_errors() {
+        // unknown header
+        assert!(CentralMessage::from_bytes(&[0x04, 0x00, 0xFF, 0x00]).is_err());
+        // data too short
+        assert!(CentralMessage::from_bytes(&[0x06, 0x00, 0x10, 0x00, 0x01, 0x02]).is_err());
+        // wrong XpressNet checksum
+        let msg = CentralMessage::from_bytes(&[0x08, 0x00, 0x40, 0x00, 0x62, 0x22, 0x02, 0x00]);
+        assert!(matches!(msg, Err(Error::XpressNet(_))));
+    }
+
+    #[test]
+    fn feedback_messages() {
+        use crate::feedback::{CanDetector, LocoNetDetector as Detector};
+        let msg = central_round_trip(&[
+            0x0F, 0x00, 0x80, 0x00, 0x01, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x80,
+        ]);
+        assert!(matches!(
+            msg,
+            CentralMessage::RBusDataChanged(1, [0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0x80])
+        ));
+        let msg = central_round_trip(&[0x08, 0x00, 0xA4, 0x00, 0x01, 0x02, 0x01, 0x01]);
+        assert!(matches!(
+            msg,
+            CentralMessage::LocoNetDetector(Detector::Occupancy(0x0102, true))
+        ));
+        let msg = central_round_trip(&[
+            0x0E, 0x00, 0xC4, 0x00, 0x01, 0xD0, 0x02, 0x00, 0x03, 0x01, 0x00, 0x11, 0x00, 0x00,
+        ]);
+        assert!(matches!(
+            msg,
+            CentralMessage::CanDetector(CanDetector {
+                address: 2,
+                port: 3,
+                ..
+            })
+        ));
+        let msg = CentralMessage::from_bytes(&[0x08, 0x00, 0xA4, 0x00, 0x7F, 0x02, 0x01, 0x01]);
+        assert!(msg.is_err());
+
+        let msg = client_round_trip(&[0x05, 0x00, 0x81, 0x00, 0x01]);
+        assert!(matches!(msg, ClientMessage::GetRBusData(1)));
+        let msg = client_round_trip(&[0x05, 0x00, 0x82, 0x00, 0x05]);
+        assert!(matches!(msg, ClientMessage::ProgramRBusModule(5)));
+        let msg = client_round_trip(&[0x07, 0x00, 0xA4, 0x00, 0x82, 0x03, 0x00]);
+        assert!(matches!(msg, ClientMessage::GetLocoNetDetector(0x82, 3)));
+        let msg = client_round_trip(&[0x07, 0x00, 0xC4, 0x00, 0x00, 0x01, 0xD0]);
+        assert!(matches!(msg, ClientMessage::GetCanDetector(0xD001)));
+    }
+}
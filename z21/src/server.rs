@@ -0,0 +1,1041 @@
+//! Z21 central serving clients from the state of a command station
+
+use embedded_nal::UdpFullStack;
+use heapless::Vec;
+use loco_command_station::{power::PowerState, Loco, Station};
+use loco_core::{address::Address, functions::FunctionGroupNumber};
+use loco_dcc::writer::Encoder;
+use loco_xpressnet::{self as xnet, TurnoutState};
+use log::{debug, trace};
+
+use crate::feedback::{
+    CanDetector, LocoNetDetector, LOCONET_REQUEST_LISSY, LOCONET_REQUEST_REPORT,
+    LOCONET_REQUEST_SIC, RBUS_GROUP_SIZE, RBUS_MODULES,
+};
+use crate::message::*;
+use crate::{Error, BUF_SIZE};
+
+/// Maximum number of registered clients
+const MAX_CLIENTS: usize = 16;
+/// Maximum number of locos a client is subscribed to
+const MAX_CLIENT_LOCOS: usize = 16;
+/// Seconds after which clients without any message are removed
+pub const CLIENT_TIMEOUT: u8 = 60;
+/// Number of turnouts whose position is tracked, starting at 0
+pub const MAX_TURNOUTS: usize = 2048;
+/// Number of LocoNet and CAN detector states kept for requests of clients
+pub const MAX_DETECTORS: usize = 32;
+
+pub const SERIAL_NUMBER: u32 = 58625;
+pub const HARDWARE_TYPE: HardwareType = HardwareType::Z21New;
+pub const FIRMWARE_VERSION: FirmwareVersion = FirmwareVersion {
+    major: 1,
+    minor: 33,
+};
+
+pub type ClientAddress = embedded_nal::SocketAddr;
+
+/// A client registered at the server
+#[derive(Debug, Clone)]
+pub struct ClientInfo {
+    address: ClientAddress,
+    flags: BroadcastFlags,
+    locos: Vec<Address, MAX_CLIENT_LOCOS>,
+    idle: u8,
+}
+
+impl ClientInfo {
+    fn new(address: ClientAddress) -> Self {
+        Self {
+            address,
+            flags: BroadcastFlags::empty(),
+            locos: Vec::new(),
+            idle: 0,
+        }
+    }
+
+    pub fn address(&self) -> ClientAddress {
+        self.address
+    }
+
+    pub fn flags(&self) -> BroadcastFlags {
+        self.flags
+    }
+
+    /// Locos the client gets information broadcasts for
+    pub fn locos(&self) -> &[Address] {
+        &self.locos
+    }
+
+    /// Subscribe to a loco, dropping the oldest subscription if full
+    fn subscribe(&mut self, addr: Address) {
+        if self.locos.contains(&addr) {
+            return;
+        }
+        if self.locos.is_full() {
+            self.locos.remove(0);
+        }
+        let _ = self.locos.push(addr);
+    }
+
+    fn wants_loco_info(&self, addr: Address) -> bool {
+        self.flags.contains(BroadcastFlags::DRIVING_SWITCHING_ALL)
+            || (self.flags.contains(BroadcastFlags::DRIVING_SWITCHING)
+                && self.locos.contains(&addr))
+    }
+}
+
+/// A Z21 central serving clients from the state of a command station
+///
+/// Every message registers its sender as a client. Clients that did not
+/// send a message within `CLIENT_TIMEOUT` seconds or sent `LAN_LOGOFF`
+/// are removed. Changes are broadcast to all clients subscribed to them.
+pub struct Server<S, E: Encoder, const N: usize> {
+    socket: S,
+    station: Station<E, N>,
+    clients: Vec<ClientInfo, MAX_CLIENTS>,
+    rbus: [u8; RBUS_MODULES],
+    /// Last reported state of each LocoNet detector
+    loconet_detectors: Vec<LocoNetDetector, MAX_DETECTORS>,
+    /// Last reported state of each CAN detector port
+    can_detectors: Vec<CanDetector, MAX_DETECTORS>,
+    /// Turnout positions, two bits each
+    turnouts: [u8; MAX_TURNOUTS / 4],
+    recv_buf: [u8; BUF_SIZE],
+    send_buf: [u8; BUF_SIZE],
+}
+
+impl<S, E, const N: usize> Server<S, E, N>
+where
+    S: Sized,
+    E: Encoder,
+{
+    pub fn new(socket: S, station: Station<E, N>) -> Self {
+        Self {
+            socket,
+            station,
+            clients: Vec::new(),
+            rbus: [0; RBUS_MODULES],
+            loconet_detectors: Vec::new(),
+            can_detectors: Vec::new(),
+            turnouts: [0; MAX_TURNOUTS / 4],
+            recv_buf: [0; BUF_SIZE],
+            send_buf: [0; BUF_SIZE],
+        }
+    }
+
+    pub fn station(&self) -> &Station<E, N> {
+        &self.station
+    }
+
+    pub fn station_mut(&mut self) -> &mut Station<E, N> {
+        &mut self.station
+    }
+
+    /// Central state from the track power state of the station
+    pub fn central_state(&self) -> CentralState {
+        match self.station.power() {
+            PowerState::Off => CentralState::EMERGENCY_OFF,
+            PowerState::On => CentralState::empty(),
+            PowerState::EmergencyOff => CentralState::EMERGENCY_OFF | CentralState::SHORT_CIRCUIT,
+            PowerState::EmergencyStop => CentralState::EMERGENCY_STOP,
+        }
+    }
+
+    pub fn clients(&self) -> &[ClientInfo] {
+        &self.clients
+    }
+
+    /// Advance the idle time of all clients by one second
+    ///
+    /// Has to be called once per second, removes clients that timed out.
+    pub fn tick(&mut self) {
+        for client in self.clients.iter_mut() {
+            client.idle = client.idle.saturating_add(1);
+        }
+        self.clients.retain(|client| {
+            let active = client.idle < CLIENT_TIMEOUT;
+            if !active {
+                debug!("client {:?} timed out", client.address);
+            }
+            active
+        });
+    }
+
+    pub fn send<U, EU>(
+        &mut self,
+        stack: &mut U,
+        client: ClientAddress,
+        message: &CentralMessage,
+    ) -> nb::Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let len = message.to_buf(&mut self.send_buf);
+        debug!("sending: ({:?},{:?})", client, message);
+        trace!("{:#04X?}", &self.send_buf[0..len]);
+        stack
+            .send_to(&mut self.socket, client, &self.send_buf[0..len])
+            .map_err(|e| e.map(|_| Error::Send))
+    }
+
+    /// Send a message to all clients subscribed to one of the given flags
+    pub fn broadcast<U, EU>(
+        &mut self,
+        stack: &mut U,
+        flags: BroadcastFlags,
+        message: &CentralMessage,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.send_to_clients(
+            stack,
+            None,
+            |client| client.flags.intersects(flags),
+            message,
+        )
+    }
+
+    /// Send the state of a loco to all clients subscribed to it
+    pub fn broadcast_loco_info<U, EU>(&mut self, stack: &mut U, addr: Address) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let message = self.loco_info(addr);
+        self.send_to_clients(stack, None, |client| client.wants_loco_info(addr), &message)
+    }
+
+    /// Get the inputs of an R-BUS feedback module, starting at 0
+    pub fn rbus_module(&self, module: usize) -> Option<u8> {
+        self.rbus.get(module).copied()
+    }
+
+    /// Set the inputs of an R-BUS feedback module, starting at 0
+    ///
+    /// Changes are broadcast to all clients subscribed to R-BUS feedback.
+    pub fn set_rbus_module<U, EU>(
+        &mut self,
+        stack: &mut U,
+        module: usize,
+        inputs: u8,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        match self.rbus.get(module) {
+            None => return Err(Error::InvalidModule),
+            Some(&old) if old == inputs => return Ok(()),
+            Some(_) => {}
+        }
+        self.rbus[module] = inputs;
+        let message = self.rbus_data((module / RBUS_GROUP_SIZE) as u8);
+        self.broadcast(stack, BroadcastFlags::RBUS, &message)
+    }
+
+    /// Get the position of a turnout, starting at 0
+    pub fn turnout(&self, addr: u16) -> TurnoutState {
+        let addr = addr as usize;
+        if addr >= MAX_TURNOUTS {
+            return TurnoutState::NotSwitched;
+        }
+        TurnoutState::from(self.turnouts[addr / 4] >> (addr % 4 * 2))
+    }
+
+    /// Switch a turnout on the command station, starting at 0
+    ///
+    /// The new position is broadcast to all clients subscribed to
+    /// driving and switching.
+    pub fn set_turnout<U, EU>(
+        &mut self,
+        stack: &mut U,
+        addr: u16,
+        thrown: bool,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.switch_turnout(stack, None, addr, thrown)
+    }
+
+    fn switch_turnout<U, EU>(
+        &mut self,
+        stack: &mut U,
+        sender: Option<ClientAddress>,
+        addr: u16,
+        thrown: bool,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let index = addr as usize;
+        if index >= MAX_TURNOUTS {
+            debug!("turnout {} out of range", addr);
+            return Ok(());
+        }
+        // DCC accessory output addresses start at 1
+        self.station.set_turnout(Address::new(addr + 1), thrown);
+        let state = if thrown {
+            TurnoutState::Thrown
+        } else {
+            TurnoutState::Closed
+        };
+        let shift = index % 4 * 2;
+        self.turnouts[index / 4] &= !(0x03 << shift);
+        self.turnouts[index / 4] |= u8::from(state) << shift;
+        let message = self.turnout_info(addr);
+        let flags = BroadcastFlags::DRIVING_SWITCHING;
+        self.send_to_clients(
+            stack,
+            sender,
+            |client| client.flags.intersects(flags),
+            &message,
+        )
+    }
+
+    /// Send the state of a LocoNet detector to all subscribed clients
+    ///
+    /// The state is kept to answer requests of clients, dropping the
+    /// oldest detector if there are more than `MAX_DETECTORS`.
+    pub fn report_loconet_detector<U, EU>(
+        &mut self,
+        stack: &mut U,
+        detector: LocoNetDetector,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let request = loconet_request(&detector);
+        self.loconet_detectors
+            .retain(|d| d.address() != detector.address() || loconet_request(d) != request);
+        if self.loconet_detectors.is_full() {
+            self.loconet_detectors.remove(0);
+        }
+        let _ = self.loconet_detectors.push(detector.clone());
+        let message = CentralMessage::LocoNetDetector(detector);
+        self.broadcast(stack, BroadcastFlags::LOCONET_OCCUPY, &message)
+    }
+
+    /// Send the state of a CAN detector to all subscribed clients
+    ///
+    /// The state is kept to answer requests of clients, dropping the
+    /// oldest detector port if there are more than `MAX_DETECTORS`.
+    pub fn report_can_detector<U, EU>(
+        &mut self,
+        stack: &mut U,
+        detector: CanDetector,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.can_detectors.retain(|d| {
+            d.network_id != detector.network_id
+                || d.address != detector.address
+                || d.port != detector.port
+                || d.kind != detector.kind
+        });
+        if self.can_detectors.is_full() {
+            self.can_detectors.remove(0);
+        }
+        let _ = self.can_detectors.push(detector.clone());
+        let message = CentralMessage::CanDetector(detector);
+        self.broadcast(stack, BroadcastFlags::CAN_OCCUPY, &message)
+    }
+
+    /// Send the track power state and system state to all subscribed clients
+    pub fn broadcast_power<U, EU>(&mut self, stack: &mut U) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        self.broadcast_power_to(stack, None)
+    }
+
+    fn broadcast_power_to<U, EU>(
+        &mut self,
+        stack: &mut U,
+        sender: Option<ClientAddress>,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let power = match self.station.power() {
+            PowerState::On => xnet::CentralMessage::TrackPowerOn,
+            PowerState::EmergencyStop => xnet::CentralMessage::EmergencyStop,
+            PowerState::Off | PowerState::EmergencyOff => xnet::CentralMessage::TrackPowerOff,
+        };
+        let message = CentralMessage::XpressNet(power);
+        let flags = BroadcastFlags::DRIVING_SWITCHING;
+        self.send_to_clients(
+            stack,
+            sender,
+            |client| client.flags.intersects(flags),
+            &message,
+        )?;
+        let message = self.system_state();
+        let flags = BroadcastFlags::SYSTEM_STATUS;
+        self.send_to_clients(
+            stack,
+            None,
+            |client| client.flags.intersects(flags),
+            &message,
+        )
+    }
+
+    /// Send a message to all clients matching the filter and to the sender
+    fn send_to_clients<U, EU, F>(
+        &mut self,
+        stack: &mut U,
+        sender: Option<ClientAddress>,
+        filter: F,
+        message: &CentralMessage,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+        F: Fn(&ClientInfo) -> bool,
+    {
+        let mut receivers: Vec<ClientAddress, MAX_CLIENTS> = self
+            .clients
+            .iter()
+            .filter(|client| filter(client))
+            .map(|client| client.address)
+            .collect();
+        if let Some(sender) = sender {
+            if !receivers.contains(&sender) {
+                // the sender is not registered if the client table is full
+                if receivers.push(sender).is_err() {
+                    nb::block!(self.send(stack, sender, message))?;
+                }
+            }
+        }
+        for addr in receivers {
+            nb::block!(self.send(stack, addr, message))?;
+        }
+        Ok(())
+    }
+
+    /// Receive a datagram, which may hold several messages
+    ///
+    /// Returns the client address and the number of received bytes.
+    fn receive<U, EU>(&mut self, stack: &mut U) -> nb::Result<(ClientAddress, usize), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let (num, addr) = stack
+            .receive(&mut self.socket, &mut self.recv_buf)
+            .map_err(|e| e.map(|_| Error::Receive))?;
+        trace!("{:#04X?}", &self.recv_buf[0..num]);
+        Ok((addr, num))
+    }
+
+    /// Receive a datagram and apply its messages to the command station
+    pub fn run<U, EU>(&mut self, stack: &mut U) -> nb::Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let (addr, num) = self.receive(stack)?;
+        let mut start = 0;
+        while num - start >= 4 {
+            let len = u16::from_le_bytes([self.recv_buf[start], self.recv_buf[start + 1]]) as usize;
+            if len < 4 || start + len > num {
+                return Err(nb::Error::Other(Error::ParseCommand));
+            }
+            let msg = ClientMessage::from_bytes(&self.recv_buf[start..start + len]);
+            start += len;
+            match msg {
+                Ok(msg) => {
+                    debug!("received: ({:?},{:?})", addr, msg);
+                    self.handle(stack, addr, msg)?;
+                }
+                // skip unknown messages, but handle the others
+                Err(e) => debug!("{:?}", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply a message from a client and send the replies
+    pub fn handle<U, EU>(
+        &mut self,
+        stack: &mut U,
+        client: ClientAddress,
+        msg: ClientMessage,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        use xnet::DeviceMessage as Device;
+        use ClientMessage::*;
+        self.register(client);
+        let reply = match msg {
+            GetSystemState => self.system_state(),
+            GetSerialNumber => CentralMessage::SerialNumber(SERIAL_NUMBER),
+            GetHardwareInfo => CentralMessage::HardwareInfo(HARDWARE_TYPE, FIRMWARE_VERSION),
+            GetBroadcastFlags => CentralMessage::BroadcastFlags(self.broadcast_flags(client)),
+            SetBroadcastFlags(flags) => {
+                if let Some(c) = self.client_mut(client) {
+                    c.flags = flags;
+                }
+                return Ok(());
+            }
+            Logoff => {
+                debug!("client {:?} logged off", client);
+                self.clients.retain(|c| c.address != client);
+                return Ok(());
+            }
+            GetRBusData(group) if (group as usize) < RBUS_MODULES / RBUS_GROUP_SIZE => {
+                self.rbus_data(group)
+            }
+            GetLocoNetDetector(request, addr) => {
+                // a stationary interrogate asks all detectors for their state
+                let wanted = |d: &LocoNetDetector| match request {
+                    LOCONET_REQUEST_SIC => loconet_request(d) == LOCONET_REQUEST_REPORT,
+                    _ => d.address() == addr && loconet_request(d) == request,
+                };
+                for i in 0..self.loconet_detectors.len() {
+                    if wanted(&self.loconet_detectors[i]) {
+                        let reply =
+                            CentralMessage::LocoNetDetector(self.loconet_detectors[i].clone());
+                        nb::block!(self.send(stack, client, &reply))?;
+                    }
+                }
+                return Ok(());
+            }
+            GetCanDetector(network_id) => {
+                for i in 0..self.can_detectors.len() {
+                    if self.can_detectors[i].network_id == network_id {
+                        let reply = CentralMessage::CanDetector(self.can_detectors[i].clone());
+                        nb::block!(self.send(stack, client, &reply))?;
+                    }
+                }
+                return Ok(());
+            }
+            // there is no R-BUS attached to the server
+            GetRBusData(_) | ProgramRBusModule(_) => {
+                debug!("unhandled message: {:?}", msg);
+                return Ok(());
+            }
+            XpressNet(Device::GetVersion) => {
+                CentralMessage::XpressNet(xnet::CentralMessage::Version(0x30, 0x12))
+            }
+            XpressNet(Device::GetState) => {
+                CentralMessage::XpressNet(xnet::CentralMessage::State(self.central_state()))
+            }
+            XpressNet(Device::TrackPowerOn) => {
+                self.station.set_power(PowerState::On);
+                return self.broadcast_power_to(stack, Some(client));
+            }
+            XpressNet(Device::TrackPowerOff) => {
+                self.station.set_power(PowerState::Off);
+                return self.broadcast_power_to(stack, Some(client));
+            }
+            XpressNet(Device::EmergencyStop) => {
+                self.station.set_power(PowerState::EmergencyStop);
+                return self.broadcast_power_to(stack, Some(client));
+            }
+            XpressNet(Device::LocoEmergencyStop(addr)) => {
+                self.add_loco(addr);
+                self.subscribe(client, addr);
+                self.station.loco_emergency_stop(addr);
+                return self.loco_changed(stack, client, addr);
+            }
+            XpressNet(Device::LocoDrive(addr, direction, speed)) => {
+                self.add_loco(addr);
+                self.subscribe(client, addr);
+                self.station.loco_set_drive(addr, speed, direction);
+                return self.loco_changed(stack, client, addr);
+            }
+            XpressNet(Device::Z21SetFunction(addr, switch, function)) => {
+                self.add_loco(addr);
+                self.subscribe(client, addr);
+                let value = match switch {
+                    xnet::FunctionSwitch::On => true,
+                    xnet::FunctionSwitch::Off => false,
+                    xnet::FunctionSwitch::Toggle => !matches!(
+                        self.station.loco(addr),
+                        Some(loco) if loco.is_function_set(function)
+                    ),
+                };
+                self.station.loco_set_function(addr, function, value);
+                return self.loco_changed(stack, client, addr);
+            }
+            // the station sends the deactivating packet itself after the
+            // activating ones, so deactivating commands of clients are
+            // ignored, and as the station schedules every command right
+            // away there is no queue to hold back commands with `queue` set
+            XpressNet(Device::Z21SetTurnout(accessory)) => {
+                if !accessory.activate {
+                    return Ok(());
+                }
+                return self.switch_turnout(
+                    stack,
+                    Some(client),
+                    accessory.address,
+                    accessory.thrown,
+                );
+            }
+            XpressNet(Device::Z21GetTurnoutInformation(addr)) => self.turnout_info(addr),
+            XpressNet(Device::Z21GetLocoInformation(addr)) => {
+                self.subscribe(client, addr);
+                self.loco_info(addr)
+            }
+            XpressNet(msg) => {
+                debug!("unhandled message: {:?}", msg);
+                CentralMessage::XpressNet(xnet::CentralMessage::UnknownCommand)
+            }
+        };
+        nb::block!(self.send(stack, client, &reply))
+    }
+
+    /// Send the changed state of a loco to the sender and all subscribed clients
+    fn loco_changed<U, EU>(
+        &mut self,
+        stack: &mut U,
+        sender: ClientAddress,
+        addr: Address,
+    ) -> Result<(), Error>
+    where
+        U: UdpFullStack<Error = EU, UdpSocket = S>,
+        EU: core::fmt::Debug,
+    {
+        let message = self.loco_info(addr);
+        self.send_to_clients(
+            stack,
+            Some(sender),
+            |client| client.wants_loco_info(addr),
+            &message,
+        )
+    }
+
+    fn add_loco(&mut self, addr: Address) {
+        if self.station.loco(addr).is_none() && self.station.add_loco(addr).is_err() {
+            debug!("no space left for loco {:?}", addr);
+        }
+    }
+
+    fn client_mut(&mut self, address: ClientAddress) -> Option<&mut ClientInfo> {
+        self.clients.iter_mut().find(|c| c.address == address)
+    }
+
+    /// Register a client or reset its idle time
+    fn register(&mut self, address: ClientAddress) {
+        if let Some(client) = self.client_mut(address) {
+            client.idle = 0;
+        } else if self.clients.push(ClientInfo::new(address)).is_err() {
+            debug!("too many clients, ignoring {:?}", address);
+        }
+    }
+
+    fn subscribe(&mut self, address: ClientAddress, addr: Address) {
+        if let Some(client) = self.client_mut(address) {
+            client.subscribe(addr);
+        }
+    }
+
+    fn broadcast_flags(&self, address: ClientAddress) -> BroadcastFlags {
+        self.clients
+            .iter()
+            .find(|c| c.address == address)
+            .map_or(BroadcastFlags::empty(), |c| c.flags)
+    }
+
+    fn rbus_data(&self, group: u8) -> CentralMessage {
+        let start = group as usize * RBUS_GROUP_SIZE;
+        let mut data = [0; RBUS_GROUP_SIZE];
+        data.copy_from_slice(&self.rbus[start..start + RBUS_GROUP_SIZE]);
+        CentralMessage::RBusDataChanged(group, data)
+    }
+
+    fn system_state(&self) -> CentralMessage {
+        let central_state_ex = if self.station.power() == PowerState::EmergencyOff {
+            CentralStateEx::SHORT_CIRCUIT_INTERNAL
+        } else {
+            CentralStateEx::empty()
+        };
+        CentralMessage::SystemState {
+            main_current: 0,
+            prog_current: 0,
+            filtered_main_current: 0,
+            temperature: 0,
+            supply_voltage: 0,
+            vcc_voltage: 0,
+            central_state: self.central_state(),
+            central_state_ex,
+        }
+    }
+
+    fn turnout_info(&self, addr: u16) -> CentralMessage {
+        CentralMessage::XpressNet(xnet::CentralMessage::Z21TurnoutInformation(
+            addr,
+            self.turnout(addr),
+        ))
+    }
+
+    fn loco_info(&self, addr: Address) -> CentralMessage {
+        use FunctionGroupNumber::*;
+        let unknown = Loco::new(addr);
+        let loco = self.station.loco(addr).unwrap_or(&unknown);
+        let group = |g| u8::from(loco.function_group(g));
+        CentralMessage::XpressNet(xnet::CentralMessage::Z21LocoInformation {
+            loco_address: addr,
+            is_free: true,
+            direction: loco.direction(),
+            speed: loco.speed(),
+            f0: group(G1).into(),
+            f1: (group(G2) | group(G3)).into(),
+            f2: group(G4).into(),
+            f3: group(G5).into(),
+            double_heading: false,
+            smart_search: false,
+        })
+    }
+}
+
+/// Type of the client request answered by a LocoNet detector report
+fn loconet_request(detector: &LocoNetDetector) -> u8 {
+    use LocoNetDetector::*;
+    match detector {
+        Occupancy(..) | TransponderEnter(..) | TransponderExit(..) => LOCONET_REQUEST_REPORT,
+        LissyLocoAddress(..) | LissyBlockStatus(..) | LissySpeed(..) => LOCONET_REQUEST_LISSY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests_mock::*;
+    use std::vec::Vec;
+
+    fn setup() -> (Server<(), NullEncoder, 8>, MockStack) {
+        let station = Station::new(NullEncoder);
+        (Server::new((), station), MockStack::default())
+    }
+
+    fn run(server: &mut Server<(), NullEncoder, 8>, stack: &mut MockStack) {
+        while !stack.incoming.is_empty() {
+            server.run(stack).unwrap();
+        }
+    }
+
+    const SET_FLAGS: [u8; 8] = [0x08, 0x00, 0x50, 0x00, 0x01, 0x01, 0x00, 0x00];
+    const GET_LOCO_3: [u8; 9] = [0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0x00, 0x03, 0x10];
+    const DRIVE_LOCO_3: [u8; 10] = [0x0A, 0x00, 0x40, 0x00, 0xE4, 0x13, 0x00, 0x03, 0x8A, 0x7E];
+    const POWER_OFF: [u8; 7] = [0x07, 0x00, 0x40, 0x00, 0x21, 0x80, 0xA1];
+    const LOGOFF: [u8; 4] = [0x04, 0x00, 0x30, 0x00];
+
+    #[test]
+    fn registry_and_logoff() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        stack.incoming.push_back((client(2), GET_LOCO_3.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(server.clients().len(), 2);
+        let c = &server.clients()[0];
+        assert_eq!(c.address(), client(1));
+        assert_eq!(
+            c.flags(),
+            BroadcastFlags::DRIVING_SWITCHING | BroadcastFlags::SYSTEM_STATUS
+        );
+        assert_eq!(server.clients()[1].locos(), &[Address::new(3)]);
+        stack.incoming.push_back((client(1), LOGOFF.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(server.clients().len(), 1);
+        assert_eq!(server.clients()[0].address(), client(2));
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        stack.incoming.push_back((client(2), SET_FLAGS.to_vec()));
+        run(&mut server, &mut stack);
+        for _ in 0..CLIENT_TIMEOUT - 1 {
+            server.tick();
+        }
+        // any message resets the idle time
+        stack.incoming.push_back((client(2), GET_LOCO_3.to_vec()));
+        run(&mut server, &mut stack);
+        server.tick();
+        assert_eq!(server.clients().len(), 1);
+        assert_eq!(server.clients()[0].address(), client(2));
+    }
+
+    #[test]
+    fn loco_subscriptions() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        for n in 0..=MAX_CLIENT_LOCOS as u8 {
+            let mut msg = [0x09, 0x00, 0x40, 0x00, 0xE3, 0xF0, 0x00, n + 1, 0x00];
+            msg[8] = msg[4..8].iter().fold(0, |acc, x| acc ^ x);
+            stack.incoming.push_back((client(1), msg.to_vec()));
+        }
+        run(&mut server, &mut stack);
+        // the oldest subscription was dropped
+        let locos = server.clients()[0].locos();
+        assert_eq!(locos.len(), MAX_CLIENT_LOCOS);
+        assert_eq!(locos[0], Address::new(2));
+        assert_eq!(locos[MAX_CLIENT_LOCOS - 1], Address::new(17));
+    }
+
+    #[test]
+    fn loco_info_broadcast() {
+        let (mut server, mut stack) = setup();
+        // client 1 and 2 subscribe to loco 3, client 3 has no flags
+        let mut subscribe = SET_FLAGS.to_vec();
+        subscribe.extend_from_slice(&GET_LOCO_3);
+        stack.incoming.push_back((client(1), subscribe.clone()));
+        stack.incoming.push_back((client(2), subscribe));
+        stack.incoming.push_back((client(3), GET_LOCO_3.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(stack.sent.len(), 3);
+        stack.sent.clear();
+
+        stack.incoming.push_back((client(3), DRIVE_LOCO_3.to_vec()));
+        run(&mut server, &mut stack);
+        let receivers: Vec<_> = stack.sent.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(receivers, [client(1), client(2), client(3)]);
+        let info = [
+            0x0E, 0x00, 0x40, 0x00, 0xEF, 0x00, 0x03, 0x04, 0x8A, 0x00, 0x00, 0x00, 0x00, 0x62,
+        ];
+        assert!(stack.sent.iter().all(|(_, data)| data[..] == info));
+        let loco = server.station().loco(Address::new(3)).unwrap();
+        assert_eq!(loco.speed(), loco_core::drive::Speed::Steps128(20));
+    }
+
+    #[test]
+    fn power_broadcast() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        stack.incoming.push_back((client(2), POWER_OFF.to_vec()));
+        run(&mut server, &mut stack);
+        assert!(server.central_state().contains(CentralState::EMERGENCY_OFF));
+        let sent: Vec<_> = stack
+            .sent
+            .iter()
+            .map(|(addr, data)| (*addr, &data[2..4]))
+            .collect();
+        // power off to client 1 and the sender, system state to client 1
+        assert_eq!(
+            sent,
+            [
+                (client(1), &[0x40, 0x00][..]),
+                (client(2), &[0x40, 0x00][..]),
+                (client(1), &[0x84, 0x00][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn emergency_stop() {
+        let (mut server, mut stack) = setup();
+        let stop = [0x06, 0x00, 0x40, 0x00, 0x80, 0x80];
+        let power_on = [0x07, 0x00, 0x40, 0x00, 0x21, 0x81, 0xA0];
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        stack.incoming.push_back((client(2), DRIVE_LOCO_3.to_vec()));
+        stack.incoming.push_back((client(2), stop.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(server.central_state(), CentralState::EMERGENCY_STOP);
+        assert!(stack
+            .sent
+            .iter()
+            .any(|(addr, data)| *addr == client(1) && data[4..7] == [0x81, 0x00, 0x81]));
+        let loco = server.station().loco(Address::new(3)).unwrap();
+        assert_eq!(loco.speed(), loco_core::drive::Speed::EmergencyStop);
+
+        // resume with the loco stopped
+        stack.incoming.push_back((client(2), power_on.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(server.central_state(), CentralState::empty());
+        let loco = server.station().loco(Address::new(3)).unwrap();
+        assert_eq!(loco.speed(), loco_core::drive::Speed::Stop);
+    }
+
+    #[test]
+    fn short_circuit_state() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        run(&mut server, &mut stack);
+        server.station_mut().set_power(PowerState::EmergencyOff);
+        server.broadcast_power(&mut stack).unwrap();
+        assert_eq!(
+            server.central_state(),
+            CentralState::EMERGENCY_OFF | CentralState::SHORT_CIRCUIT
+        );
+        assert_eq!(stack.sent[0].1[4..7], [0x61, 0x00, 0x61]);
+        let state = &stack.sent[1].1;
+        assert_eq!(state[2..4], [0x84, 0x00]);
+        assert_eq!(state[16..18], [0x05, 0x08]);
+    }
+
+    #[test]
+    fn rbus_feedback() {
+        let (mut server, mut stack) = setup();
+        let rbus = [0x08, 0x00, 0x50, 0x00, 0x02, 0x00, 0x00, 0x00];
+        stack.incoming.push_back((client(1), rbus.to_vec()));
+        stack.incoming.push_back((client(2), SET_FLAGS.to_vec()));
+        run(&mut server, &mut stack);
+        server.set_rbus_module(&mut stack, 11, 0x81).unwrap();
+        // unchanged inputs are not broadcast
+        server.set_rbus_module(&mut stack, 11, 0x81).unwrap();
+        let changed = [
+            0x0F, 0x00, 0x80, 0x00, 0x01, 0x00, 0x81, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x00,
+        ];
+        assert_eq!(stack.sent, [(client(1), changed.to_vec())]);
+        stack.sent.clear();
+
+        stack
+            .incoming
+            .push_back((client(2), vec![0x05, 0x00, 0x81, 0x00, 0x01]));
+        // invalid groups are ignored
+        stack
+            .incoming
+            .push_back((client(2), vec![0x05, 0x00, 0x81, 0x00, 0x02]));
+        run(&mut server, &mut stack);
+        assert_eq!(stack.sent, [(client(2), changed.to_vec())]);
+        assert_eq!(server.rbus_module(11), Some(0x81));
+        assert_eq!(server.rbus_module(RBUS_MODULES), None);
+        assert!(matches!(
+            server.set_rbus_module(&mut stack, RBUS_MODULES, 0x01),
+            Err(Error::InvalidModule)
+        ));
+    }
+
+    #[test]
+    fn turnouts() {
+        let (mut server, mut stack) = setup();
+        stack.incoming.push_back((client(1), SET_FLAGS.to_vec()));
+        run(&mut server, &mut stack);
+        // throw turnout 5, followed by the deactivating command
+        let throw = [0x09, 0x00, 0x40, 0x00, 0x53, 0x00, 0x05, 0x89, 0xDF];
+        let release = [0x09, 0x00, 0x40, 0x00, 0x53, 0x00, 0x05, 0x81, 0xD7];
+        stack.incoming.push_back((client(2), throw.to_vec()));
+        stack.incoming.push_back((client(2), release.to_vec()));
+        run(&mut server, &mut stack);
+        let info = [0x09, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x02, 0x44];
+        assert_eq!(
+            stack.sent,
+            [(client(1), info.to_vec()), (client(2), info.to_vec())]
+        );
+        assert_eq!(server.turnout(5), TurnoutState::Thrown);
+        assert_eq!(server.turnout(4), TurnoutState::NotSwitched);
+        stack.sent.clear();
+
+        server.set_turnout(&mut stack, 5, false).unwrap();
+        let info = [0x09, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x01, 0x47];
+        assert_eq!(stack.sent, [(client(1), info.to_vec())]);
+        stack.sent.clear();
+
+        let get = [0x08, 0x00, 0x40, 0x00, 0x43, 0x00, 0x05, 0x46];
+        stack.incoming.push_back((client(3), get.to_vec()));
+        run(&mut server, &mut stack);
+        assert_eq!(stack.sent, [(client(3), info.to_vec())]);
+        assert_eq!(server.turnout(5), TurnoutState::Closed);
+    }
+
+    #[test]
+    fn detector_broadcast() {
+        use crate::feedback::{CanDetector, LocoNetDetector, CAN_OCCUPANCY};
+        let (mut server, mut stack) = setup();
+        let loconet = [0x08, 0x00, 0x50, 0x00, 0x00, 0x00, 0x00, 0x08];
+        let can = [0x08, 0x00, 0x50, 0x00, 0x00, 0x00, 0x08, 0x00];
+        stack.incoming.push_back((client(1), loconet.to_vec()));
+        stack.incoming.push_back((client(2), can.to_vec()));
+        run(&mut server, &mut stack);
+        let detector = LocoNetDetector::Occupancy(5, true);
+        server
+            .report_loconet_detector(&mut stack, detector)
+            .unwrap();
+        let detector = CanDetector {
+            network_id: 0xD001,
+            address: 1,
+            port: 0,
+            kind: CAN_OCCUPANCY,
+            value1: 0x1100,
+            value2: 0,
+        };
+        server.report_can_detector(&mut stack, detector).unwrap();
+        let receivers: Vec<_> = stack
+            .sent
+            .iter()
+            .map(|(addr, data)| (*addr, data[2]))
+            .collect();
+        assert_eq!(receivers, [(client(1), 0xA4), (client(2), 0xC4)]);
+    }
+
+    #[test]
+    fn detector_requests() {
+        use crate::feedback::{CanDetector, LocoNetDetector, CAN_OCCUPANCY};
+        let (mut server, mut stack) = setup();
+        // the second report of detector 5 replaces the first one
+        for detector in [
+            LocoNetDetector::Occupancy(5, true),
+            LocoNetDetector::Occupancy(6, true),
+            LocoNetDetector::LissySpeed(5, 30),
+            LocoNetDetector::Occupancy(5, false),
+        ] {
+            server
+                .report_loconet_detector(&mut stack, detector)
+                .unwrap();
+        }
+        for (network_id, port) in [(0xD001, 0), (0xD002, 0), (0xD001, 1)] {
+            let detector = CanDetector {
+                network_id,
+                address: 1,
+                port,
+                kind: CAN_OCCUPANCY,
+                value1: 0x1100,
+                value2: 0,
+            };
+            server.report_can_detector(&mut stack, detector).unwrap();
+        }
+
+        // test that a report request is answered with the last occupancy
+        let get_report = [0x07, 0x00, 0xA4, 0x00, 0x81, 0x05, 0x00];
+        stack.incoming.push_back((client(3), get_report.to_vec()));
+        run(&mut server, &mut stack);
+        let occupancy = [0x08, 0x00, 0xA4, 0x00, 0x01, 0x05, 0x00, 0x00];
+        assert_eq!(stack.sent, [(client(3), occupancy.to_vec())]);
+        stack.sent.clear();
+
+        // test that a LISSY request only gets the LISSY report
+        let get_lissy = [0x07, 0x00, 0xA4, 0x00, 0x82, 0x05, 0x00];
+        stack.incoming.push_back((client(3), get_lissy.to_vec()));
+        run(&mut server, &mut stack);
+        let speed = [0x09, 0x00, 0xA4, 0x00, 0x12, 0x05, 0x00, 0x1E, 0x00];
+        assert_eq!(stack.sent, [(client(3), speed.to_vec())]);
+        stack.sent.clear();
+
+        // test that a stationary interrogate gets all occupancy reports
+        let get_sic = [0x07, 0x00, 0xA4, 0x00, 0x80, 0x00, 0x00];
+        stack.incoming.push_back((client(3), get_sic.to_vec()));
+        run(&mut server, &mut stack);
+        let addresses: Vec<_> = stack.sent.iter().map(|(_, data)| data[5]).collect();
+        assert_eq!(addresses, [6, 5]);
+        stack.sent.clear();
+
+        // test that a CAN request gets all ports of the network ID
+        let get_can = [0x07, 0x00, 0xC4, 0x00, 0x00, 0x01, 0xD0];
+        stack.incoming.push_back((client(3), get_can.to_vec()));
+        run(&mut server, &mut stack);
+        let ports: Vec<_> = stack
+            .sent
+            .iter()
+            .map(|(_, data)| (data[2], data[8]))
+            .collect();
+        assert_eq!(ports, [(0xC4, 0), (0xC4, 1)]);
+    }
+}
@@ -0,0 +1,76 @@
+pub use embedded_nal::{IpAddr, Ipv4Addr, SocketAddr, UdpClientStack, UdpFullStack};
+pub use loco_dcc::writer::{Bit, Encoder};
+use std::collections::VecDeque;
+use std::vec::Vec;
+
+/// Encoder that drops all bits
+pub struct NullEncoder;
+
+impl Encoder for NullEncoder {
+    fn write(&mut self, _bit: &Bit) -> nb::Result<(), loco_dcc::Error> {
+        Ok(())
+    }
+}
+
+/// UDP stack with a single socket, recording all sent datagrams
+#[derive(Default)]
+pub struct MockStack {
+    pub incoming: VecDeque<(SocketAddr, Vec<u8>)>,
+    pub sent: Vec<(SocketAddr, Vec<u8>)>,
+    pub remote: Option<SocketAddr>,
+}
+
+impl UdpClientStack for MockStack {
+    type UdpSocket = ();
+    type Error = ();
+
+    fn socket(&mut self) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn connect(&mut self, _socket: &mut (), remote: SocketAddr) -> Result<(), ()> {
+        self.remote = Some(remote);
+        Ok(())
+    }
+
+    fn send(&mut self, _socket: &mut (), buffer: &[u8]) -> nb::Result<(), ()> {
+        let remote = self.remote.ok_or(nb::Error::Other(()))?;
+        self.sent.push((remote, buffer.to_vec()));
+        Ok(())
+    }
+
+    fn receive(
+        &mut self,
+        _socket: &mut (),
+        buffer: &mut [u8],
+    ) -> nb::Result<(usize, SocketAddr), ()> {
+        let (addr, data) = self.incoming.pop_front().ok_or(nb::Error::WouldBlock)?;
+        buffer[0..data.len()].copy_from_slice(&data);
+        Ok((data.len(), addr))
+    }
+
+    fn close(&mut self, _socket: ()) -> Result<(), ()> {
+        Ok(())
+    }
+}
+
+impl UdpFullStack for MockStack {
+    fn bind(&mut self, _socket: &mut (), _local_port: u16) -> Result<(), ()> {
+        Ok(())
+    }
+
+    fn send_to(
+        &mut self,
+        _socket: &mut (),
+        remote: SocketAddr,
+        buffer: &[u8],
+    ) -> nb::Result<(), ()> {
+        self.sent.push((remote, buffer.to_vec()));
+        Ok(())
+    }
+}
+
+/// Address of the n-th client in the local network
+pub fn client(n: u8) -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 0, n)), 50000)
+}